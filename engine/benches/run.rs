@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use engine::{assemble_all, blocks::Backend, machine::Machine};
+
+/// Sums 1 to 100 fifty times over, spending nearly all its time in a three instruction loop.
+const NESTED_LOOPS: &str = "
+    mov r0, #0
+    mov r1, #50
+outer:
+    mov r2, #100
+inner:
+    add r0, r0, r2
+    subs r2, r2, #1
+    bne inner
+    subs r1, r1, #1
+    bne outer
+end:
+    b end
+";
+
+fn run(mut machine: Machine) -> Machine {
+    while !machine.halted() {
+        machine.step().unwrap();
+    }
+    machine
+}
+
+fn loops(c: &mut Criterion) {
+    let mut machine = Machine::default();
+    assemble_all(NESTED_LOOPS).serialise(&mut machine.ram);
+
+    let mut uncached = machine.clone();
+    uncached.decode_cache = None;
+
+    let mut blocks = Machine::with_backend(machine.ram.len(), Backend::Blocks);
+    blocks.ram.copy_from_slice(&machine.ram);
+
+    let mut group = c.benchmark_group("nested loops");
+    group.bench_function("decode cache", |b| b.iter_batched(|| machine.clone(), run, BatchSize::SmallInput));
+    group.bench_function("decode every step", |b| b.iter_batched(|| uncached.clone(), run, BatchSize::SmallInput));
+    group.bench_function("blocks", |b| b.iter_batched(|| blocks.clone(), |mut machine| machine.run(u64::MAX).unwrap(), BatchSize::SmallInput));
+    group.finish();
+}
+
+criterion_group!(benches, loops);
+criterion_main!(benches);
@@ -0,0 +1,158 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use engine::Assembly;
+
+use crate::ASSEMBLY_ERROR;
+
+/// Bytes in each Intel HEX data record.
+const HEX_RECORD_LEN: usize = 16;
+
+#[derive(Args)]
+pub struct AssembleArgs {
+    /// Source file to assemble
+    file: PathBuf,
+    /// Where to write the output, or stdout if not given
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Output format, guessed from the output file's extension if not given
+    #[arg(short, long, value_enum)]
+    format: Option<Format>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Raw image, the bytes loaded into RAM
+    Bin,
+    /// Intel HEX
+    Hex,
+    /// Address, encoded word and source line side by side
+    Listing,
+}
+
+impl Format {
+    fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "bin" | "img" => Some(Format::Bin),
+            "hex" | "ihx" => Some(Format::Hex),
+            "lst" => Some(Format::Listing),
+            _ => None,
+        }
+    }
+}
+
+pub fn assemble(args: AssembleArgs) -> Result<ExitCode> {
+    let (src, assembly) = match super::assemble_file(&args.file)? {
+        Some(assembled) => assembled,
+        None => return Ok(ExitCode::from(ASSEMBLY_ERROR)),
+    };
+
+    let format = args.format
+        .or_else(|| args.output.as_deref().and_then(Format::from_extension))
+        .unwrap_or(Format::Bin);
+
+    let bytes = match format {
+        Format::Bin => image(&assembly),
+        Format::Hex => intel_hex(&image(&assembly)).into_bytes(),
+        Format::Listing => listing(&src, &assembly).into_bytes(),
+    };
+
+    match &args.output {
+        Some(path) => std::fs::write(path, bytes).with_context(|| format!("Couldn't write {}", path.display()))?,
+        None => io::stdout().write_all(&bytes)?,
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The program as [`Assembly::serialise`] writes it into RAM.
+fn image(assembly: &Assembly) -> Vec<u8> {
+    let mut bytes = vec![0; assembly.instructions.len() * 4];
+    assembly.serialise(&mut bytes);
+    bytes
+}
+
+/// Encodes `bytes` as Intel HEX, starting at address 0.
+fn intel_hex(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut upper = 0;
+
+    for (i, chunk) in bytes.chunks(HEX_RECORD_LEN).enumerate() {
+        let addr = (i * HEX_RECORD_LEN) as u32;
+
+        // Data records only hold the bottom 16 bits of the address, so larger images need an extended linear address record
+        if addr >> 16 != upper {
+            upper = addr >> 16;
+            hex_record(&mut out, 0, 0x04, &(upper as u16).to_be_bytes());
+        }
+
+        hex_record(&mut out, addr as u16, 0x00, chunk);
+    }
+
+    hex_record(&mut out, 0, 0x01, &[]);
+    out
+}
+
+fn hex_record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let mut record = vec![data.len() as u8];
+    record.extend(addr.to_be_bytes());
+    record.push(kind);
+    record.extend(data);
+
+    let checksum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+    record.push(checksum);
+
+    out.push(':');
+    for byte in record {
+        write!(out, "{byte:02X}").unwrap();
+    }
+    out.push('\n');
+}
+
+/// Every source line, with the address and encoding of the instruction it assembled to.
+fn listing(src: &str, assembly: &Assembly) -> String {
+    let bytes = image(assembly);
+    let mut addresses = assembly.source_map().into_iter().map(|(addr, line)| (line, addr)).collect::<Vec<_>>();
+    addresses.sort();
+
+    let mut out = String::new();
+    for (i, line) in src.lines().enumerate() {
+        match addresses.binary_search_by_key(&(i as u32), |(line, _)| *line) {
+            Ok(found) => {
+                let addr = addresses[found].1;
+                let word = u32::from_be_bytes(bytes[addr as usize..addr as usize + 4].try_into().unwrap());
+                writeln!(out, "{addr:08X}  {word:08X}  {line}").unwrap();
+            }
+            Err(_) => writeln!(out, "{:20}{line}", "").unwrap(),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use engine::assemble_all;
+
+    use super::{image, intel_hex, listing};
+
+    #[test]
+    fn writes_formats() {
+        let src = "start: ; entry\nmov r1, #12\nb start";
+        let assembly = assemble_all(src);
+
+        assert_eq!(image(&assembly), [0xE3, 0xA0, 0x10, 0x0C, 0xEA, 0xFF, 0xFF, 0xFD]);
+        assert_eq!(intel_hex(&image(&assembly)), ":08000000E3A0100CEAFFFFFD74\n:00000001FF\n");
+        assert_eq!(listing(src, &assembly), concat!(
+            "                    start: ; entry\n",
+            "00000000  E3A0100C  mov r1, #12\n",
+            "00000004  EAFFFFFD  b start\n",
+        ));
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, ValueEnum};
+use engine::{
+    disassembler::{disassemble, SymbolTable},
+    machine::{register_index, register_name, Machine, RAM_SIZE},
+    Assembly, Instruction,
+};
+
+use crate::{
+    run::{execute, format_registers, format_value, load, Base, Outcome},
+    ASSEMBLY_ERROR,
+};
+
+/// Instructions shown either side of the PC by `disassemble`.
+const DISASSEMBLY_CONTEXT: u32 = 4;
+/// Source lines shown either side of the current line by `list`.
+const LIST_CONTEXT: usize = 3;
+
+const HELP: &str = "\
+step [n]            s    Run one instruction, or n
+next                n    Run one instruction, running a whole subroutine if it is a BL
+continue            c    Run until a breakpoint or the program stops
+finish                   Run until the current subroutine returns to LR
+break <loc>         b    Break at a label, source line or address such as 0x10
+delete [loc]        d    Remove a breakpoint, or all of them
+breakpoints              List breakpoints
+registers [base]    r    Print every register and the flags
+print <reg> [base]  p    Print a register
+flags                    Print the flags
+x <loc> [n] [base]       Print n words of memory
+set <reg> <value>        Set a register
+set flags <nzcv>         Set the flags, with set flags in upper case, e.g. nZCv
+disassemble [n]          Disassemble n instructions either side of the PC
+list                l    Show the source around the current line
+explain                  Explain what the next instruction will do
+reset                    Reload the program and start again
+quit                q    Leave the debugger
+
+Bases are hex, dec, signed and bin. An empty line repeats the last command.";
+
+#[derive(Args)]
+pub struct DebugArgs {
+    /// Source file to assemble
+    file: PathBuf,
+    /// Give up on `continue`, `next` and `finish` after this many instructions
+    #[arg(long, default_value_t = 1_000_000)]
+    max_steps: u64,
+    /// RAM size in bytes
+    #[arg(long, default_value_t = RAM_SIZE)]
+    ram_size: usize,
+    /// Base to print values in unless a command says otherwise
+    #[arg(long, value_enum, default_value_t = Base::Hex)]
+    base: Base,
+}
+
+pub fn debug(args: DebugArgs) -> Result<ExitCode> {
+    let (src, assembly) = match super::assemble_file(&args.file)? {
+        Some(assembled) => assembled,
+        None => return Ok(ExitCode::from(ASSEMBLY_ERROR)),
+    };
+
+    let machine = load(&assembly, args.ram_size)?;
+    let mut debugger = Debugger::new(&src, &assembly, machine, args.base, args.max_steps);
+    debugger.repl(&mut io::stdin().lock(), &mut io::stdout())?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+enum Flow {
+    Continue,
+    Quit,
+}
+
+struct Debugger {
+    machine: Machine,
+    /// The machine as it was loaded, for `reset`
+    initial: Machine,
+    lines: Vec<String>,
+    /// Address to the source line it was assembled from
+    source_map: HashMap<u32, u32>,
+    labels: HashMap<String, u32>,
+    symbols: SymbolTable,
+    base: Base,
+    max_steps: u64,
+}
+
+impl Debugger {
+    fn new(src: &str, assembly: &Assembly, machine: Machine, base: Base, max_steps: u64) -> Self {
+        Self {
+            initial: machine.clone(),
+            machine,
+            lines: src.lines().map(str::to_string).collect(),
+            source_map: assembly.source_map(),
+            labels: assembly.labels.clone(),
+            symbols: assembly.labels.iter().map(|(label, addr)| (*addr, label.clone())).collect(),
+            base,
+            max_steps,
+        }
+    }
+
+    /// Reads commands from `input` until it ends or the user quits.
+    /// Input the program reads from the console is taken from `input` too.
+    fn repl(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> Result<()> {
+        let mut last = String::new();
+        self.show_location(output)?;
+
+        loop {
+            write!(output, "(aqa) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+
+            if !line.trim().is_empty() {
+                last = line.trim().to_string();
+            }
+
+            match self.command(&last, input, output) {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Quit) => return Ok(()),
+                Err(e) => writeln!(output, "error: {e:#}")?,
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str, input: &mut impl BufRead, output: &mut impl Write) -> Result<Flow> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args = words.collect::<Vec<_>>();
+
+        match (command, args.as_slice()) {
+            ("", _) => {}
+            ("s" | "step", count) => {
+                let count = match count.first() {
+                    Some(count) => count.parse().context("Expected a number of instructions")?,
+                    None => 1,
+                };
+                self.resume(count, input, output, |_| false)?;
+            }
+            ("n" | "next", []) => {
+                let pc = self.machine.pc();
+                match self.current()?.is_call() {
+                    true => self.resume(self.max_steps, input, output, |machine| machine.pc() == pc.wrapping_add(4))?,
+                    false => self.resume(1, input, output, |_| false)?,
+                }
+            }
+            ("c" | "continue", []) => self.resume(self.max_steps, input, output, |_| false)?,
+            ("finish", []) => {
+                let lr = self.machine.registers[14];
+                self.resume(self.max_steps, input, output, |machine| machine.pc() == lr)?;
+            }
+            ("b" | "break", [location]) => {
+                let addr = self.location(location)?;
+                self.machine.breakpoints.insert(addr);
+                writeln!(output, "Breakpoint at {}", self.describe_addr(addr))?;
+            }
+            ("d" | "delete", []) => self.machine.breakpoints.clear(),
+            ("d" | "delete", [location]) => {
+                let addr = self.location(location)?;
+                if !self.machine.breakpoints.remove(&addr) {
+                    bail!("No breakpoint at {addr:#010x}");
+                }
+            }
+            ("breakpoints", []) => {
+                for addr in &self.machine.breakpoints {
+                    writeln!(output, "{}", self.describe_addr(*addr))?;
+                }
+            }
+            ("r" | "registers", base) => {
+                let base = self.base(base.first())?;
+                write!(output, "{}", format_registers(&self.machine, base))?;
+            }
+            ("p" | "print", [register, base @ ..]) => {
+                let index = parse_register(register)?;
+                let base = self.base(base.first())?;
+                writeln!(output, "{} = {}", register_name(index), format_value(self.machine.registers[index], base))?;
+            }
+            ("flags", []) => writeln!(output, "{}", self.machine.flags)?,
+            ("x", [location, rest @ ..]) => {
+                let addr = self.location(location)?;
+                let count = match rest.first() {
+                    Some(count) => count.parse().context("Expected a number of words")?,
+                    None => 1,
+                };
+                let base = self.base(rest.get(1))?;
+
+                for addr in (addr..).step_by(4).take(count) {
+                    let word = self.machine.fetch(addr).with_context(|| format!("{addr:#010x} is outside of memory"))?;
+                    writeln!(output, "{addr:#010x}: {}", format_value(u32::from_be_bytes(word), base))?;
+                }
+            }
+            ("set", ["flags", flags]) => self.machine.flags = flags.parse()?,
+            ("set", [register, value]) => self.machine.registers[parse_register(register)?] = parse_value(value)?,
+            ("disassemble", context) => {
+                let context = match context.first() {
+                    Some(context) => context.parse().context("Expected a number of instructions")?,
+                    None => DISASSEMBLY_CONTEXT,
+                };
+                let pc = self.machine.pc();
+                let from = pc.saturating_sub(context * 4);
+
+                for word in disassemble(&self.machine.ram, from..pc.saturating_add((context + 1) * 4), &self.symbols) {
+                    let marker = if word.addr == pc { "=>" } else { "  " };
+                    let text = word.text.unwrap_or_else(|| "<data>".into());
+                    writeln!(output, "{marker} {:#010x}  {:08X}  {text}", word.addr, word.word)?;
+                }
+            }
+            ("l" | "list", []) => {
+                let line = self.source_map.get(&self.machine.pc()).copied().context("The PC isn't on a source line")? as usize;
+                for i in line.saturating_sub(LIST_CONTEXT)..(line + LIST_CONTEXT + 1).min(self.lines.len()) {
+                    let marker = if i == line { "=>" } else { "  " };
+                    writeln!(output, "{marker} {:>4} | {}", i + 1, self.lines[i])?;
+                }
+            }
+            ("explain", []) => writeln!(output, "{}", self.machine.explain(&self.symbols)?)?,
+            ("reset", []) => {
+                let breakpoints = std::mem::take(&mut self.machine.breakpoints);
+                self.machine = self.initial.clone();
+                self.machine.breakpoints = breakpoints;
+                self.show_location(output)?;
+            }
+            ("h" | "help", []) => writeln!(output, "{HELP}")?,
+            ("q" | "quit", []) => return Ok(Flow::Quit),
+            _ => bail!("Unknown command `{line}`, try `help`"),
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    /// Runs at most `max_steps` instructions, stopping early at breakpoints or wherever `stop` says, then shows where it stopped.
+    fn resume(&mut self, max_steps: u64, input: &mut impl BufRead, output: &mut impl Write, mut stop: impl FnMut(&Machine) -> bool) -> Result<()> {
+        if let Some(code) = self.machine.exit_code {
+            bail!("The program has exited with code {code}, use `reset` to start again");
+        }
+
+        let outcome = execute(&mut self.machine, max_steps, input, output, |machine| {
+            machine.breakpoints.contains(&machine.pc()) || stop(machine)
+        })?;
+
+        match outcome {
+            Outcome::Exited(code) => return Ok(writeln!(output, "Program exited with code {code}")?),
+            Outcome::Halted => writeln!(output, "Program halted")?,
+            Outcome::Stopped if self.machine.breakpoints.contains(&self.machine.pc()) => {
+                writeln!(output, "Breakpoint at {}", self.describe_addr(self.machine.pc()))?
+            }
+            Outcome::OutOfBudget if max_steps == self.max_steps => writeln!(output, "Still running after {max_steps} instructions")?,
+            Outcome::Fault(e) => writeln!(output, "Fault: {e}")?,
+            Outcome::Stopped | Outcome::OutOfBudget => {}
+        }
+
+        self.show_location(output)
+    }
+
+    /// The instruction at the PC and the line it came from.
+    fn show_location(&self, output: &mut impl Write) -> Result<()> {
+        let readout = match self.machine.current_instruction(&self.source_map, &self.symbols) {
+            Ok(readout) => readout,
+            Err(e) => return Ok(writeln!(output, "{:#010x}: {e}", self.machine.pc())?),
+        };
+
+        write!(output, "{:#010x}  {}", readout.addr, readout.disassembly)?;
+        if let Some(line) = readout.line {
+            write!(output, "\n{:>7} | {}", line + 1, self.lines[line as usize])?;
+        }
+        writeln!(output)?;
+
+        Ok(())
+    }
+
+    fn current(&self) -> Result<Instruction> {
+        let word = self.machine.fetch(self.machine.pc()).context("PC is outside of memory")?;
+        Instruction::deserialise(&word)
+    }
+
+    /// An address given as a label, a one based source line, or a number starting `0x`, `0b` or `*`.
+    fn location(&self, location: &str) -> Result<u32> {
+        if let Some(addr) = self.labels.get(location) {
+            return Ok(*addr);
+        }
+
+        if location.starts_with("0x") || location.starts_with("0b") {
+            return parse_value(location);
+        }
+
+        if let Some(addr) = location.strip_prefix('*') {
+            return parse_value(addr);
+        }
+
+        let line = location.parse::<u32>().map_err(|_| anyhow!("`{location}` isn't a label, line or address"))?;
+
+        // Like gdb, a line without an instruction breaks at the next one which has
+        self.source_map
+            .iter()
+            .filter(|(_, source_line)| **source_line + 1 >= line)
+            .min_by_key(|(_, source_line)| **source_line)
+            .map(|(addr, _)| *addr)
+            .with_context(|| format!("There are no instructions on or after line {line}"))
+    }
+
+    fn describe_addr(&self, addr: u32) -> String {
+        match (self.symbols.get(&addr), self.source_map.get(&addr)) {
+            (Some(label), Some(line)) => format!("{addr:#010x} ({label}, line {})", line + 1),
+            (None, Some(line)) => format!("{addr:#010x} (line {})", line + 1),
+            _ => format!("{addr:#010x}"),
+        }
+    }
+
+    fn base(&self, base: Option<&&str>) -> Result<Base> {
+        match base {
+            Some(base) => Base::from_str(base, true).map_err(|_| anyhow!("Unknown base `{base}`, expected hex, dec, signed or bin")),
+            None => Ok(self.base),
+        }
+    }
+}
+
+/// `R0` to `R15`, or `SP`, `LR` or `PC`.
+fn parse_register(name: &str) -> Result<usize> {
+    register_index(name).with_context(|| format!("Unknown register `{name}`"))
+}
+
+/// A decimal, `0x` hex or `0b` binary number, optionally negative or starting with `#`.
+fn parse_value(value: &str) -> Result<u32> {
+    let text = value.trim_start_matches('#');
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+
+    let parsed = if let Some(hex) = text.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(bin) = text.strip_prefix("0b") {
+        u32::from_str_radix(bin, 2)
+    } else {
+        text.parse()
+    };
+
+    let parsed = parsed.map_err(|_| anyhow!("`{value}` isn't a number"))?;
+    Ok(if negative { parsed.wrapping_neg() } else { parsed })
+}
+
+#[cfg(test)]
+mod tests {
+    use engine::assemble_all;
+
+    use super::Debugger;
+    use crate::run::{load, Base};
+
+    #[test]
+    fn debugs_session() {
+        let src = "mov r0, #1\nbl sub\nmov r2, #3\nend:\nb end\nsub:\nadd r0, r0, #1\nmov pc, lr";
+        let assembly = assemble_all(src);
+        let mut debugger = Debugger::new(src, &assembly, load(&assembly, 64).unwrap(), Base::Hex, 1000);
+
+        let commands = "break sub\ncontinue\np r0 dec\nfinish\nset r3 -2\np r3 signed\nset flags nZcV\nflags\nreset\nnext\nnext\n\nx 0x4 1\nc\nq\n";
+        let mut output = Vec::new();
+        debugger.repl(&mut commands.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        for expected in [
+            "Breakpoint at 0x00000010 (sub, line 7)",
+            "R0 = 1\n",
+            "0x00000008  MOV R2, #3\n      3 | mov r2, #3",
+            "R3 = -2\n",
+            "nZcV\n",
+            "0x00000004: 0xeb000001\n",
+            "Program halted",
+        ] {
+            assert!(output.contains(expected), "missing {expected:?} in\n{output}");
+        }
+
+        // Once when set, once on `continue`, and once when `next` runs into it while stepping over the `BL`
+        assert_eq!(output.matches("Breakpoint at 0x00000010").count(), 3);
+    }
+}
@@ -0,0 +1,484 @@
+//! A stub speaking the GDB remote serial protocol, so gdb and IDE debuggers can drive the emulator.
+//!
+//! Instructions are stored big-endian, so gdb needs `set endian big` before connecting.
+
+use std::{
+    fmt::Write as _,
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use anyhow::Result;
+use clap::Args;
+use engine::{machine::{Machine, RAM_SIZE}, Flags};
+
+use crate::{
+    run::{execute, exit_status, load, Outcome},
+    ASSEMBLY_ERROR,
+};
+
+/// Steps run between checks for gdb interrupting a `continue`.
+const INTERRUPT_POLL_INTERVAL: u64 = 4096;
+/// Byte gdb sends to interrupt a running program.
+const INTERRUPT: u8 = 0x03;
+/// Register number of the CPSR, after R0 to R15.
+const CPSR: usize = 16;
+/// User mode, the only mode the emulator has.
+const USER_MODE: u32 = 0x10;
+
+/// Signals reported when the program stops.
+const SIGTRAP: u8 = 5;
+const SIGILL: u8 = 4;
+const SIGINT: u8 = 2;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.core">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+#[derive(Args)]
+pub struct GdbArgs {
+    /// Source file to assemble
+    file: PathBuf,
+    /// Port to listen on for gdb's `target remote :port`
+    #[arg(long, default_value_t = 1234)]
+    port: u16,
+    /// Speak over stdin and stdout instead, for gdb's `target remote | aqa-asm gdb prog.as --stdio`
+    #[arg(long)]
+    stdio: bool,
+    /// RAM size in bytes
+    #[arg(long, default_value_t = RAM_SIZE)]
+    ram_size: usize,
+}
+
+pub fn gdb(args: GdbArgs) -> Result<ExitCode> {
+    let (_, assembly) = match super::assemble_file(&args.file)? {
+        Some(assembled) => assembled,
+        None => return Ok(ExitCode::from(ASSEMBLY_ERROR)),
+    };
+
+    let machine = load(&assembly, args.ram_size)?;
+
+    if args.stdio {
+        Stub::new(machine, Stdio).serve()?;
+    } else {
+        let listener = TcpListener::bind(("127.0.0.1", args.port))?;
+        eprintln!("Waiting for gdb on 127.0.0.1:{}", args.port);
+        let (stream, _) = listener.accept()?;
+        Stub::new(machine, stream).serve()?;
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// A connection to gdb.
+trait Transport: Read + Write {
+    /// Whether gdb has asked to interrupt the running program, without blocking.
+    fn interrupted(&mut self) -> Result<bool>;
+}
+
+impl Transport for TcpStream {
+    fn interrupted(&mut self) -> Result<bool> {
+        let mut byte = [0];
+        self.set_nonblocking(true)?;
+        let peeked = self.peek(&mut byte);
+        self.set_nonblocking(false)?;
+
+        match peeked {
+            Ok(1) if byte[0] == INTERRUPT => {
+                self.read_exact(&mut byte)?;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Transport for Stdio {
+    /// Stdin can't be polled portably, so a running program can't be interrupted
+    fn interrupted(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+enum Flow {
+    Continue,
+    Disconnect,
+}
+
+struct Stub<T: Transport> {
+    machine: Machine,
+    transport: T,
+    /// Cleared once gdb asks for `QStartNoAckMode`
+    ack: bool,
+}
+
+impl<T: Transport> Stub<T> {
+    fn new(mut machine: Machine, transport: T) -> Self {
+        // gdb has no way to send console input, so reads see the end of the input rather than waiting forever
+        machine.console.closed = true;
+
+        Self { machine, transport, ack: true }
+    }
+
+    fn serve(&mut self) -> Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            if let Flow::Disconnect = self.handle(&packet)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The next packet's contents, or `None` once gdb disconnects.
+    fn read_packet(&mut self) -> Result<Option<String>> {
+        loop {
+            let mut packet = Vec::new();
+
+            // Skip acks and stray interrupts until the start of a packet
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => packet.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            self.transport.read_exact(&mut checksum)?;
+            let valid = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) == Some(checksum_of(&packet));
+
+            if self.ack {
+                self.transport.write_all(if valid { b"+" } else { b"-" })?;
+                self.transport.flush()?;
+            }
+
+            if valid || !self.ack {
+                return Ok(Some(String::from_utf8_lossy(&packet).into_owned()));
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut byte = [0];
+        match self.transport.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        write!(self.transport, "${data}#{:02x}", checksum_of(data.as_bytes()))?;
+        self.transport.flush()?;
+
+        // gdb acknowledges every packet until no-ack mode, and a `-` asks for it again
+        while self.ack {
+            match self.read_byte()? {
+                Some(b'+') | None => break,
+                Some(b'-') => write!(self.transport, "${data}#{:02x}", checksum_of(data.as_bytes()))?,
+                Some(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> Result<Flow> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => stop_reply(SIGTRAP),
+            "g" => (0..=CPSR).map(|register| hex_word(self.register(register))).collect(),
+            "G" => match (0..=CPSR).map(|register| args.get(register * 8..register * 8 + 8).and_then(parse_word)).collect::<Option<Vec<_>>>() {
+                Some(values) => {
+                    for (register, value) in values.into_iter().enumerate() {
+                        self.set_register(register, value);
+                    }
+                    "OK".into()
+                }
+                None => "E01".into(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(register) if register <= CPSR => hex_word(self.register(register)),
+                _ => "E01".into(),
+            },
+            "P" => match args.split_once('=').and_then(|(register, value)| Some((usize::from_str_radix(register, 16).ok()?, parse_word(value)?))) {
+                Some((register, value)) if register <= CPSR => {
+                    self.set_register(register, value);
+                    "OK".into()
+                }
+                _ => "E01".into(),
+            },
+            "m" => match parse_range(args).and_then(|(addr, len)| self.machine.ram.get(addr as usize..addr as usize + len as usize)) {
+                Some(bytes) => bytes.iter().fold(String::new(), |mut out, byte| {
+                    write!(out, "{byte:02x}").unwrap();
+                    out
+                }),
+                None => "E01".into(),
+            },
+            "M" => match args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, parse_bytes(data)?))) {
+                Some(((addr, len), data)) if data.len() == len as usize => match self.machine.ram.get_mut(addr as usize..addr as usize + data.len()) {
+                    Some(dest) => {
+                        dest.copy_from_slice(&data);
+                        "OK".into()
+                    }
+                    None => "E01".into(),
+                },
+                _ => "E01".into(),
+            },
+            "s" | "c" => {
+                if let Some(addr) = (!args.is_empty()).then(|| parse_word(args)).flatten() {
+                    self.machine.registers[15] = addr;
+                }
+                self.resume(if command == "s" { 1 } else { u64::MAX })?
+            }
+            "Z" | "z" => self.toggle_point(command == "Z", args),
+            "H" => "OK".into(),
+            "k" => return Ok(Flow::Disconnect),
+            "D" => {
+                self.send("OK")?;
+                return Ok(Flow::Disconnect);
+            }
+            "q" if args.starts_with("Supported") => "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".into(),
+            "q" if args == "Attached" => "1".into(),
+            "q" if args.starts_with("Xfer:features:read:target.xml:") => {
+                let (offset, len) = parse_range(&args["Xfer:features:read:target.xml:".len()..]).unwrap_or((0, 0));
+                let start = (offset as usize).min(TARGET_XML.len());
+                let end = (start + len as usize).min(TARGET_XML.len());
+                format!("{}{}", if end == TARGET_XML.len() { "l" } else { "m" }, &TARGET_XML[start..end])
+            }
+            "Q" if args == "StartNoAckMode" => {
+                self.send("OK")?;
+                self.ack = false;
+                return Ok(Flow::Continue);
+            }
+            // Anything else is unsupported, which gdb expects to be answered with an empty packet
+            _ => String::new(),
+        };
+
+        self.send(&reply)?;
+        Ok(Flow::Continue)
+    }
+
+    /// Runs until a breakpoint, interrupt or the program stopping, then says why.
+    fn resume(&mut self, max_steps: u64) -> Result<String> {
+        let mut steps = 0u64;
+        let mut interrupted = false;
+        let mut output = Vec::new();
+        let transport = &mut self.transport;
+
+        let outcome = execute(&mut self.machine, max_steps, &mut io::empty(), &mut output, |machine| {
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_POLL_INTERVAL) && transport.interrupted().unwrap_or(false) {
+                interrupted = true;
+            }
+
+            interrupted || machine.breakpoints.contains(&machine.pc())
+        })?;
+
+        // Console output is shown in gdb's terminal
+        if !output.is_empty() {
+            let hex = output.iter().fold(String::from("O"), |mut out, byte| {
+                write!(out, "{byte:02x}").unwrap();
+                out
+            });
+            self.send(&hex)?;
+        }
+
+        Ok(match outcome {
+            Outcome::Exited(code) => format!("W{:02x}", exit_status(code)),
+            Outcome::Fault(_) => stop_reply(SIGILL),
+            Outcome::Stopped if interrupted => stop_reply(SIGINT),
+            Outcome::Stopped | Outcome::Halted | Outcome::OutOfBudget => stop_reply(SIGTRAP),
+        })
+    }
+
+    /// `Z`/`z` type,addr,kind: software and hardware breakpoints are the same here.
+    /// Watchpoints are unsupported, as no instruction loads or stores memory so they could never trigger.
+    fn toggle_point(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let (kind, addr) = match (parts.next(), parts.next().and_then(|addr| u32::from_str_radix(addr, 16).ok())) {
+            (Some(kind), Some(addr)) => (kind, addr),
+            _ => return "E01".into(),
+        };
+
+        match (kind, insert) {
+            ("0" | "1", true) => self.machine.breakpoints.insert(addr),
+            ("0" | "1", false) => self.machine.breakpoints.remove(&addr),
+            _ => return String::new(),
+        };
+
+        "OK".into()
+    }
+
+    fn register(&self, register: usize) -> u32 {
+        match register {
+            CPSR => (u8::from(self.machine.flags) as u32) << 28 | USER_MODE,
+            _ => self.machine.registers[register],
+        }
+    }
+
+    fn set_register(&mut self, register: usize, value: u32) {
+        match register {
+            CPSR => self.machine.flags = Flags::from((value >> 28) as u8),
+            _ => self.machine.registers[register] = value,
+        }
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{signal:02x}")
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Registers are sent in the machine's big-endian byte order.
+fn hex_word(value: u32) -> String {
+    format!("{value:08x}")
+}
+
+fn parse_word(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// `addr,length` in hex.
+fn parse_range(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((u32::from_str_radix(addr, 16).ok()?, u32::from_str_radix(len, 16).ok()?))
+}
+
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+
+    use anyhow::Result;
+    use engine::assemble_all;
+
+    use super::{checksum_of, Stub, Transport};
+    use crate::run::load;
+
+    /// Replays packets from gdb and records the replies.
+    struct Recording {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Recording {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Recording {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Recording {
+        fn interrupted(&mut self) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        format!("${data}#{:02x}", checksum_of(data.as_bytes()))
+    }
+
+    #[test]
+    fn serves_gdb() {
+        let src = "mov r0, #65\nsvc #1\nmov r1, #2\nadd r1, r1, #1\nmov r0, #7\nsvc #0";
+        let assembly = assemble_all(src);
+
+        let packets = ["QStartNoAckMode", "?", "Z0,c,4", "c", "p1", "P1=0000002a", "g", "m4,4", "M20,2:beef", "m20,2", "Z2,20,2", "z0,c,4", "c", "k"];
+        let input = packets.iter().map(|data| packet(data) + "+").collect::<String>();
+        let mut stub = Stub::new(load(&assembly, 64).unwrap(), Recording { input: Cursor::new(input.into_bytes()), output: Vec::new() });
+        stub.serve().unwrap();
+
+        let output = String::from_utf8(stub.transport.output).unwrap();
+        let replies = output.split('$').skip(1).map(|reply| reply.split('#').next().unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(replies, [
+            "OK",
+            "S05",
+            "OK",
+            "O41",
+            "S05",
+            "00000002",
+            "OK",
+            "000000410000002a000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000c00000010",
+            "ef000001",
+            "OK",
+            "beef",
+            "",
+            "OK",
+            "W07",
+        ]);
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use engine::{
+    grader::{CaseReport, TestSuite},
+    profile::Profile,
+    Assembly,
+};
+use serde::Serialize;
+
+#[derive(Args)]
+pub struct GradeArgs {
+    /// Test specification, in TOML or, with a `.json` extension, JSON
+    #[arg(short, long)]
+    spec: PathBuf,
+    /// Submissions to run against every case
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Print the results as JSON rather than text
+    #[arg(long)]
+    json: bool,
+    /// Write the coverage of each submission across every case to this file in lcov format
+    #[arg(long)]
+    lcov: Option<PathBuf>,
+}
+
+/// Results for one submission.
+#[derive(Serialize)]
+struct Submission {
+    file: PathBuf,
+    /// Whether it assembled, as no cases are run if it doesn't
+    assembled: bool,
+    cases: Vec<CaseReport>,
+    /// The submission's source map and conditional branches, for coverage
+    #[serde(skip)]
+    assembly: Option<Assembly>,
+}
+
+impl Submission {
+    fn passed(&self) -> usize {
+        self.cases.iter().filter(|case| case.passed()).count()
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cases.iter().map(|case| case.metrics.cycles).sum()
+    }
+}
+
+/// Runs every submission against every case, succeeding only if they all pass.
+pub fn grade(args: GradeArgs) -> Result<ExitCode> {
+    let suite = load_suite(&args.spec)?;
+
+    let mut submissions = Vec::new();
+    for file in args.files {
+        let submission = match super::assemble_file(&file)? {
+            Some((_, assembly)) => {
+                let cases = suite.run(&assembly).with_context(|| format!("Invalid test specification {}", args.spec.display()))?;
+                Submission { file, assembled: true, cases, assembly: Some(assembly) }
+            }
+            None => Submission { file, assembled: false, cases: Vec::new(), assembly: None },
+        };
+        submissions.push(submission);
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&submissions)?);
+    } else {
+        print!("{}", report(&submissions, suite.cases.len()));
+    }
+
+    if let Some(path) = &args.lcov {
+        std::fs::write(path, lcov(&submissions)).with_context(|| format!("Couldn't write {}", path.display()))?;
+    }
+
+    let all_passed = submissions.iter().all(|submission| submission.assembled && submission.passed() == suite.cases.len());
+    Ok(if all_passed { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+fn load_suite(path: &Path) -> Result<TestSuite> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+
+    let suite = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(&text)?,
+        _ => toml::from_str(&text)?,
+    };
+
+    Ok(suite)
+}
+
+fn lcov(submissions: &[Submission]) -> String {
+    let mut out = String::new();
+
+    for submission in submissions {
+        if let Some(assembly) = &submission.assembly {
+            let mut profile = Profile::new();
+            for case in &submission.cases {
+                profile.merge(&case.profile);
+            }
+
+            out += &profile.to_lcov(&submission.file.display().to_string(), &assembly.source_map(), &assembly.conditional_branches());
+        }
+    }
+
+    out
+}
+
+fn report(submissions: &[Submission], cases: usize) -> String {
+    let mut out = String::new();
+
+    for submission in submissions {
+        writeln!(out, "{}", submission.file.display()).unwrap();
+
+        if !submission.assembled {
+            writeln!(out, "  FAIL  doesn't assemble").unwrap();
+        }
+
+        for case in &submission.cases {
+            let verdict = if case.passed() { "PASS" } else { "FAIL" };
+            writeln!(out, "  {verdict}  {} ({} instructions, {} cycles)", case.name, case.metrics.instructions, case.metrics.cycles).unwrap();
+            for mismatch in &case.mismatches {
+                writeln!(out, "        {mismatch}").unwrap();
+            }
+        }
+
+        writeln!(out, "{}/{cases} cases passed\n", submission.passed()).unwrap();
+    }
+
+    // Only submissions which pass everything are worth comparing on speed
+    let mut ranked = submissions.iter().filter(|submission| submission.assembled && submission.passed() == cases).collect::<Vec<_>>();
+    ranked.sort_by_key(|submission| submission.cycles());
+
+    if submissions.len() > 1 && !ranked.is_empty() {
+        writeln!(out, "Ranked by total cycles").unwrap();
+        for (i, submission) in ranked.iter().enumerate() {
+            writeln!(out, "  {}. {} ({} cycles)", i + 1, submission.file.display(), submission.cycles()).unwrap();
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use engine::{assemble_all, grader::TestSuite};
+
+    use super::{lcov, report, Submission};
+
+    #[test]
+    fn reports_submissions() {
+        let suite: TestSuite = toml::from_str(r#"
+            max_steps = 10
+
+            [[case]]
+            name = "doubles"
+            registers = { r1 = 21 }
+            expect = { registers = { r0 = 42 } }
+
+            [[case]]
+            name = "prints"
+            registers = { r1 = 21 }
+            expect.output = "*"
+        "#).unwrap();
+
+        let submission = |file: &str, src: &str| {
+            let assembly = assemble_all(src);
+            Submission { file: file.into(), assembled: true, cases: suite.run(&assembly).unwrap(), assembly: Some(assembly) }
+        };
+        let submissions = [
+            submission("slow.s", "mov r0, r1\nadd r0, r0, r1\nsvc #1\nend:\nb end"),
+            submission("fast.s", "add r0, r1, r1\nsvc #1\nend:\nb end"),
+            submission("wrong.s", "add r0, r1, r1\nend:\nb end"),
+            Submission { file: "bad.s".into(), assembled: false, cases: Vec::new(), assembly: None },
+        ];
+
+        assert_eq!(report(&submissions, 2), concat!(
+            "slow.s\n",
+            "  PASS  doubles (3 instructions, 5 cycles)\n",
+            "  PASS  prints (3 instructions, 5 cycles)\n",
+            "2/2 cases passed\n\n",
+            "fast.s\n",
+            "  PASS  doubles (2 instructions, 4 cycles)\n",
+            "  PASS  prints (2 instructions, 4 cycles)\n",
+            "2/2 cases passed\n\n",
+            "wrong.s\n",
+            "  PASS  doubles (1 instructions, 1 cycles)\n",
+            "  FAIL  prints (1 instructions, 1 cycles)\n",
+            "        output line 1: expected \"*\", got end of output\n",
+            "1/2 cases passed\n\n",
+            "bad.s\n",
+            "  FAIL  doesn't assemble\n",
+            "0/2 cases passed\n\n",
+            "Ranked by total cycles\n",
+            "  1. fast.s (8 cycles)\n",
+            "  2. slow.s (10 cycles)\n",
+        ));
+        assert_eq!(lcov(&submissions[1..]), concat!(
+            "TN:\nSF:fast.s\nBRF:0\nBRH:0\nDA:1,2\nDA:2,2\nDA:4,0\nLF:3\nLH:2\nend_of_record\n",
+            "TN:\nSF:wrong.s\nBRF:0\nBRH:0\nDA:1,2\nDA:3,0\nLF:2\nLH:1\nend_of_record\n",
+        ));
+    }
+}
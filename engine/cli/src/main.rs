@@ -0,0 +1,70 @@
+//! Command line tools for AQA assembly, for use in scripts and marking pipelines.
+
+use std::{path::Path, process::ExitCode};
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use engine::Assembly;
+
+mod assemble;
+mod debug;
+mod gdb;
+mod grade;
+mod run;
+
+/// Exit code when the program fails to assemble. Programs can exit with this too, see [`run::run`].
+const ASSEMBLY_ERROR: u8 = 65;
+
+#[derive(Parser)]
+#[command(name = "aqa-asm", version, about = "Assemble and run AQA assembly programs")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Assemble and run a program, with console I/O on stdin and stdout
+    Run(run::RunArgs),
+    /// Assemble a program into a raw image, Intel HEX or a listing
+    Assemble(assemble::AssembleArgs),
+    /// Step through a program at an interactive prompt
+    Debug(debug::DebugArgs),
+    /// Serve a program to gdb over the remote serial protocol
+    Gdb(gdb::GdbArgs),
+    /// Mark programs against the cases in a test specification
+    Test(grade::GradeArgs),
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Run(args) => run::run(args),
+        Command::Assemble(args) => assemble::assemble(args),
+        Command::Debug(args) => debug::debug(args),
+        Command::Gdb(args) => gdb::gdb(args),
+        Command::Test(args) => grade::grade(args),
+    };
+
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {e:#}");
+        ExitCode::FAILURE
+    })
+}
+
+/// Reads and assembles `path`, returning the source and its assembly, or printing every diagnostic if it doesn't assemble.
+fn assemble_file(path: &Path) -> Result<Option<(String, Assembly)>> {
+    let src = std::fs::read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let assembly = engine::assemble_all(&src);
+
+    if assembly.diagnostics.is_empty() {
+        return Ok(Some((src, assembly)));
+    }
+
+    for diagnostic in &assembly.diagnostics {
+        eprintln!("{}\n", diagnostic.error.clone().with_path(&path.display().to_string()));
+    }
+
+    Ok(None)
+}
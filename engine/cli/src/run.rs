@@ -0,0 +1,238 @@
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{Args, ValueEnum};
+use engine::{
+    machine::{register_name, Machine, RAM_SIZE},
+    profile::Profile,
+    Assembly,
+};
+
+use crate::ASSEMBLY_ERROR;
+
+/// The emulator hit an instruction it couldn't execute.
+const FAULT: u8 = 70;
+/// The instruction budget ran out, as `timeout` does.
+const OUT_OF_BUDGET: u8 = 124;
+
+#[derive(Args)]
+pub struct RunArgs {
+    /// Source file to assemble
+    file: PathBuf,
+    /// Stop after this many instructions, in case the program never halts
+    #[arg(long, default_value_t = 1_000_000)]
+    max_steps: u64,
+    /// RAM size in bytes
+    #[arg(long, default_value_t = RAM_SIZE)]
+    ram_size: usize,
+    /// Base to print the final registers in
+    #[arg(long, value_enum, default_value_t = Base::Hex)]
+    base: Base,
+    /// Don't print the final registers and flags to stderr
+    #[arg(long)]
+    no_registers: bool,
+    /// Print instruction, branch and cycle counts to stderr
+    #[arg(long)]
+    stats: bool,
+    /// Write line and branch coverage to this file in lcov format
+    #[arg(long)]
+    lcov: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Base {
+    Hex,
+    Dec,
+    Signed,
+    Bin,
+}
+
+/// Why a run stopped.
+#[derive(Debug)]
+pub(crate) enum Outcome {
+    /// Exited with `SVC #0`
+    Exited(u32),
+    /// Reached a branch to itself
+    Halted,
+    /// Reached a point the caller asked to stop at
+    Stopped,
+    OutOfBudget,
+    Fault(anyhow::Error),
+}
+
+/// Runs the program, exiting with its own exit code, or a code saying why it couldn't finish.
+///
+/// Programs can exit with any code, including the ones this uses for assembly errors, faults and timeouts,
+/// so those codes only mean the run failed when an error is also printed to stderr.
+pub fn run(args: RunArgs) -> Result<ExitCode> {
+    let (_, assembly) = match super::assemble_file(&args.file)? {
+        Some(assembled) => assembled,
+        None => return Ok(ExitCode::from(ASSEMBLY_ERROR)),
+    };
+
+    let mut machine = load(&assembly, args.ram_size)?;
+    if args.lcov.is_some() {
+        machine.profiler = Some(Profile::new());
+    }
+
+    let outcome = execute(&mut machine, args.max_steps, &mut io::stdin().lock(), &mut io::stdout(), |_| false)?;
+
+    if !args.no_registers {
+        eprint!("{}", format_registers(&machine, args.base));
+    }
+
+    if args.stats {
+        eprint!("{}", machine.metrics);
+    }
+
+    if let (Some(path), Some(profile)) = (&args.lcov, &machine.profiler) {
+        let lcov = profile.to_lcov(&args.file.display().to_string(), &assembly.source_map(), &assembly.conditional_branches());
+        std::fs::write(path, lcov).with_context(|| format!("Couldn't write {}", path.display()))?;
+    }
+
+    Ok(match outcome {
+        Outcome::Exited(code) => ExitCode::from(exit_status(code)),
+        Outcome::Halted | Outcome::Stopped => ExitCode::SUCCESS,
+        Outcome::OutOfBudget => {
+            eprintln!("error: Stopped after {} instructions without halting", args.max_steps);
+            ExitCode::from(OUT_OF_BUDGET)
+        }
+        Outcome::Fault(e) => {
+            eprintln!("error: {e} at {:#010x}", machine.pc());
+            ExitCode::from(FAULT)
+        }
+    })
+}
+
+/// The process exit code for a program exiting with `code`.
+/// Codes which don't fit in a byte become 255, rather than being truncated and possibly reading as success.
+pub(crate) fn exit_status(code: u32) -> u8 {
+    u8::try_from(code).unwrap_or(u8::MAX)
+}
+
+/// A machine with `ram_size` bytes of RAM and the program loaded at address 0.
+pub(crate) fn load(assembly: &Assembly, ram_size: usize) -> Result<Machine> {
+    let size = assembly.instructions.len() * 4;
+    if size > ram_size {
+        bail!("The program takes {size} bytes, which doesn't fit in {ram_size} bytes of RAM");
+    }
+
+    let mut machine = Machine::new(ram_size);
+    assembly.serialise(&mut machine.ram);
+    Ok(machine)
+}
+
+/// Steps `machine` until it stops or `stop` says to, feeding it `input` a line at a time as it asks for it.
+pub(crate) fn execute(
+    machine: &mut Machine,
+    max_steps: u64,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    mut stop: impl FnMut(&Machine) -> bool,
+) -> Result<Outcome> {
+    let mut steps = 0;
+
+    while !machine.halted() {
+        if steps == max_steps {
+            return Ok(Outcome::OutOfBudget);
+        }
+
+        if machine.console.waiting {
+            let mut line = Vec::new();
+            match input.read_until(b'\n', &mut line)? {
+                0 => machine.console.closed = true,
+                _ => machine.console.push_input(&line),
+            }
+        }
+
+        if let Err(e) = machine.step() {
+            return Ok(Outcome::Fault(e));
+        }
+
+        // Reads which had to wait for input will be retried, so don't count towards the budget
+        if !machine.console.waiting {
+            steps += 1;
+        }
+
+        let written = machine.console.take_output();
+        if !written.is_empty() {
+            output.write_all(&written)?;
+            output.flush()?;
+        }
+
+        if !machine.console.waiting && stop(machine) {
+            return Ok(Outcome::Stopped);
+        }
+    }
+
+    Ok(match machine.exit_code {
+        Some(code) => Outcome::Exited(code),
+        None => Outcome::Halted,
+    })
+}
+
+pub(crate) fn format_registers(machine: &Machine, base: Base) -> String {
+    let mut out = String::new();
+
+    for (i, value) in machine.registers.iter().enumerate() {
+        out += &format!("{:<3} {}\n", register_name(i), format_value(*value, base));
+    }
+
+    out + &format!("Flags {}\n", machine.flags)
+}
+
+pub(crate) fn format_value(value: u32, base: Base) -> String {
+    match base {
+        Base::Hex => format!("{value:#010x}"),
+        Base::Dec => value.to_string(),
+        Base::Signed => (value as i32).to_string(),
+        Base::Bin => format!("{value:#034b}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use engine::{assemble_all, machine::Machine};
+
+    use super::{execute, exit_status, format_registers, Base, Outcome};
+
+    fn run(src: &str, max_steps: u64, input: &str) -> (Machine, Outcome, String) {
+        let mut machine = Machine::default();
+        assemble_all(src).serialise(&mut machine.ram);
+
+        let mut output = Vec::new();
+        let outcome = execute(&mut machine, max_steps, &mut input.as_bytes(), &mut output, |_| false).unwrap();
+        (machine, outcome, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn runs_programs() {
+        let (machine, outcome, output) = run("svc #4\nmov r1, r0\nsvc #4\nadd r0, r0, r1\nsvc #3\nmov r0, #3\nsvc #0", 100, "40\n2\n");
+        assert!(matches!(outcome, Outcome::Exited(3)));
+        assert_eq!(output, "42");
+        assert!(format_registers(&machine, Base::Signed).starts_with("R0  3\nR1  40\n"));
+
+        let (_, outcome, _) = run("mov r0, #1\nend:\nb end", 100, "");
+        assert!(matches!(outcome, Outcome::Halted));
+
+        let (_, outcome, _) = run("loop:\nadd r0, r0, #1\nb loop", 100, "");
+        assert!(matches!(outcome, Outcome::OutOfBudget));
+
+        // The PC is left at the faulting call, which is the address reported
+        let (machine, outcome, _) = run("mov r0, #1\nsvc #99", 100, "");
+        assert!(matches!(outcome, Outcome::Fault(_)));
+        assert_eq!(machine.pc(), 4);
+    }
+
+    #[test]
+    fn keeps_failures_failing() {
+        assert_eq!(exit_status(0), 0);
+        assert_eq!(exit_status(42), 42);
+        assert_eq!(exit_status(256), 255);
+        assert_eq!(exit_status(-1i32 as u32), 255);
+    }
+}
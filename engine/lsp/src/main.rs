@@ -0,0 +1,411 @@
+//! Language server for AQA assembly (`.as` and `.s` files), speaking LSP over stdio.
+//!
+//! Every feature is a thin wrapper around the same engine functions the web editor uses.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use engine::{completion::CompletionKind, format::FormatOptions, highlight::TokenKind, utf16, Severity, SourceSpan, SymbolIndex};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _, PublishDiagnostics},
+    request::{
+        CodeActionRequest, Completion, DocumentSymbolRequest, Formatting, GotoDefinition, HoverRequest, References, Rename, Request as _,
+        SemanticTokensFullRequest,
+    },
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams, CodeActionProviderCapability, CodeActionResponse,
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse, CompletionTextEdit, Diagnostic,
+    DiagnosticSeverity, DocumentFormattingParams, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse, GotoDefinitionParams, GotoDefinitionResponse,
+    Hover, HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ReferenceParams, RenameParams, SemanticToken, SemanticTokenModifier, SemanticTokenType,
+    SemanticTokens, SemanticTokensFullOptions, SemanticTokensLegend, SemanticTokensOptions, SemanticTokensParams,
+    SemanticTokensResult, SemanticTokensServerCapabilities, ServerCapabilities, SymbolKind, TextDocumentPositionParams,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit, Uri, WorkspaceEdit,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+fn main() -> Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let capabilities = serde_json::to_value(ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        document_formatting_provider: Some(OneOf::Left(true)),
+        semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
+            legend: SemanticTokensLegend {
+                token_types: TOKEN_TYPES.to_vec(),
+                token_modifiers: vec![SemanticTokenModifier::DECLARATION, SemanticTokenModifier::DEFAULT_LIBRARY],
+            },
+            full: Some(SemanticTokensFullOptions::Bool(true)),
+            ..Default::default()
+        })),
+        ..Default::default()
+    })?;
+    connection.initialize(capabilities)?;
+
+    Server::default().run(&connection)?;
+    io_threads.join()?;
+
+    Ok(())
+}
+
+const TOKEN_TYPES: [SemanticTokenType; 7] = [
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::MODIFIER,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::OPERATOR,
+    SemanticTokenType::COMMENT,
+];
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<Uri, String>,
+}
+
+impl Server {
+    fn run(&mut self, connection: &Connection) -> Result<()> {
+        for message in &connection.receiver {
+            match message {
+                Message::Request(request) => {
+                    if connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    connection.sender.send(Message::Response(self.handle_request(request)))?;
+                }
+                Message::Notification(notification) => {
+                    let method = notification.method.clone();
+                    match self.handle_notification(notification) {
+                        Ok(Some(diagnostics)) => {
+                            let notification = Notification::new(PublishDiagnostics::METHOD.into(), diagnostics);
+                            connection.sender.send(Message::Notification(notification))?;
+                        }
+                        Ok(None) => {}
+                        // Notifications can't be answered with an error, and one bad message shouldn't end the session
+                        Err(e) => eprintln!("Ignoring {method} notification: {e:#}"),
+                    }
+                }
+                Message::Response(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        match request.method.as_str() {
+            HoverRequest::METHOD => self.respond(request, Self::hover),
+            Completion::METHOD => self.respond(request, Self::completion),
+            GotoDefinition::METHOD => self.respond(request, Self::definition),
+            References::METHOD => self.respond(request, Self::references),
+            Rename::METHOD => self.respond(request, Self::rename),
+            DocumentSymbolRequest::METHOD => self.respond(request, Self::document_symbols),
+            SemanticTokensFullRequest::METHOD => self.respond(request, Self::semantic_tokens),
+            CodeActionRequest::METHOD => self.respond(request, Self::code_actions),
+            Formatting::METHOD => self.respond(request, Self::format),
+            _ => Response::new_err(request.id, ErrorCode::MethodNotFound as i32, format!("Unsupported request {}", request.method)),
+        }
+    }
+
+    fn respond<P: DeserializeOwned, R: Serialize>(&self, request: Request, handler: impl FnOnce(&Self, P) -> Result<R>) -> Response {
+        let params = match serde_json::from_value(request.params) {
+            Ok(params) => params,
+            Err(e) => return Response::new_err(request.id, ErrorCode::InvalidParams as i32, e.to_string()),
+        };
+
+        match handler(self, params) {
+            Ok(result) => Response::new_ok(request.id, result),
+            Err(e) => Response::new_err(request.id, ErrorCode::RequestFailed as i32, e.to_string()),
+        }
+    }
+
+    /// Updates the open documents, returning new diagnostics for the document that changed.
+    fn handle_notification(&mut self, notification: Notification) -> Result<Option<PublishDiagnosticsParams>> {
+        let uri = match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = serde_json::from_value::<lsp_types::DidOpenTextDocumentParams>(notification.params)?;
+                self.documents.insert(params.text_document.uri.clone(), params.text_document.text);
+                params.text_document.uri
+            }
+            DidChangeTextDocument::METHOD => {
+                let params = serde_json::from_value::<lsp_types::DidChangeTextDocumentParams>(notification.params)?;
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.documents.insert(params.text_document.uri.clone(), change.text);
+                }
+                params.text_document.uri
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = serde_json::from_value::<lsp_types::DidCloseTextDocumentParams>(notification.params)?;
+                self.documents.remove(&params.text_document.uri);
+                return Ok(Some(PublishDiagnosticsParams::new(params.text_document.uri, Vec::new(), None)));
+            }
+            _ => return Ok(None),
+        };
+
+        let src = self.document(&uri)?;
+        let diagnostics = engine::lint_source(src)
+            .lints
+            .into_iter()
+            .map(|lint| {
+                Ok(Diagnostic {
+                    range: range(src, lint.from, lint.to),
+                    severity: Some(match lint.severity {
+                        Severity::Error => DiagnosticSeverity::ERROR,
+                        Severity::Warning => DiagnosticSeverity::WARNING,
+                        Severity::Hint => DiagnosticSeverity::HINT,
+                    }),
+                    code: serde_json::from_value(serde_json::to_value(lint.code)?)?,
+                    source: Some("aqa-asm".into()),
+                    message: lint.message,
+                    ..Default::default()
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Some(PublishDiagnosticsParams::new(uri, diagnostics, None)))
+    }
+
+    fn document(&self, uri: &Uri) -> Result<&str> {
+        self.documents
+            .get(uri)
+            .map(String::as_str)
+            .ok_or_else(|| anyhow::anyhow!("{} is not open", uri.as_str()))
+    }
+
+    /// The document and byte offset a request refers to.
+    fn locate(&self, params: &TextDocumentPositionParams) -> Result<(&str, usize)> {
+        let src = self.document(&params.text_document.uri)?;
+        Ok((src, offset(src, params.position)))
+    }
+
+    fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let (src, offset) = self.locate(&params.text_document_position_params)?;
+
+        Ok(engine::hover::hover(src, offset).map(|value| Hover {
+            contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value }),
+            range: None,
+        }))
+    }
+
+    fn completion(&self, params: CompletionParams) -> Result<Option<CompletionResponse>> {
+        let (src, offset) = self.locate(&params.text_document_position)?;
+
+        let items = engine::completion::complete(src, offset)
+            .into_iter()
+            .map(|completion| CompletionItem {
+                kind: Some(match completion.kind {
+                    CompletionKind::Mnemonic => CompletionItemKind::KEYWORD,
+                    CompletionKind::Register => CompletionItemKind::VARIABLE,
+                    CompletionKind::Label => CompletionItemKind::REFERENCE,
+                    CompletionKind::Shift => CompletionItemKind::OPERATOR,
+                }),
+                detail: Some(completion.detail),
+                text_edit: Some(CompletionTextEdit::Edit(TextEdit::new(
+                    range(src, completion.from, completion.to),
+                    completion.label.clone(),
+                ))),
+                label: completion.label,
+                ..Default::default()
+            })
+            .collect();
+
+        Ok(Some(CompletionResponse::Array(items)))
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Result<Option<GotoDefinitionResponse>> {
+        let (src, offset) = self.locate(&params.text_document_position_params)?;
+        let uri = &params.text_document_position_params.text_document.uri;
+
+        Ok(engine::navigation::definition(src, offset)
+            .map(|span| GotoDefinitionResponse::Scalar(location(uri, src, span))))
+    }
+
+    fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let (src, offset) = self.locate(&params.text_document_position)?;
+        let uri = &params.text_document_position.text_document.uri;
+
+        let mut spans = engine::navigation::references(src, offset);
+        if !params.context.include_declaration {
+            if let Some(definition) = engine::navigation::definition(src, offset) {
+                spans.retain(|span| *span != definition);
+            }
+        }
+
+        Ok(Some(spans.into_iter().map(|span| location(uri, src, span)).collect()))
+    }
+
+    fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let (src, offset) = self.locate(&params.text_document_position)?;
+        let uri = params.text_document_position.text_document.uri.clone();
+
+        let edits = engine::navigation::rename(src, offset, &params.new_name)?
+            .into_iter()
+            .map(|edit| TextEdit::new(range(src, edit.from, edit.to), edit.text))
+            .collect();
+
+        Ok(Some(WorkspaceEdit::new(HashMap::from([(uri, edits)]))))
+    }
+
+    #[allow(deprecated)]
+    fn document_symbols(&self, params: DocumentSymbolParams) -> Result<Option<DocumentSymbolResponse>> {
+        let src = self.document(&params.text_document.uri)?;
+
+        let mut symbols = SymbolIndex::build(src)
+            .symbols
+            .into_iter()
+            .filter_map(|(name, symbol)| Some((name, symbol.definition?, symbol.addr?)))
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|(_, definition, _)| definition.from);
+
+        Ok(Some(DocumentSymbolResponse::Nested(
+            symbols
+                .into_iter()
+                .map(|(name, definition, addr)| DocumentSymbol {
+                    name,
+                    detail: Some(format!("{addr:#04x}")),
+                    kind: SymbolKind::FUNCTION,
+                    tags: None,
+                    deprecated: None,
+                    range: range(src, definition.from, definition.to),
+                    selection_range: range(src, definition.from, definition.to),
+                    children: None,
+                })
+                .collect(),
+        )))
+    }
+
+    fn code_actions(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let src = self.document(&params.text_document.uri)?;
+        let lines = params.range.start.line..=params.range.end.line;
+
+        let actions = engine::lint_source(src)
+            .lints
+            .into_iter()
+            .filter(|lint| lines.contains(&lint.line))
+            .flat_map(|lint| lint.fixes)
+            .map(|fix| {
+                let edit = TextEdit::new(range(src, fix.from, fix.to), fix.replacement);
+                CodeActionOrCommand::CodeAction(CodeAction {
+                    title: fix.title,
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    edit: Some(WorkspaceEdit::new(HashMap::from([(params.text_document.uri.clone(), vec![edit])]))),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        Ok(Some(actions))
+    }
+
+    fn format(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let src = self.document(&params.text_document.uri)?;
+        let options = FormatOptions { indent: params.options.tab_size as usize, ..Default::default() };
+
+        let formatted = engine::format::format(src, &options);
+        if formatted == src {
+            return Ok(Some(Vec::new()));
+        }
+
+        Ok(Some(vec![TextEdit::new(range(src, 0, src.len() as u32), formatted)]))
+    }
+
+    fn semantic_tokens(&self, params: SemanticTokensParams) -> Result<Option<SemanticTokensResult>> {
+        let src = self.document(&params.text_document.uri)?;
+        let mut previous = Position::new(0, 0);
+
+        let data = engine::highlight::semantic_tokens(src)
+            .into_iter()
+            .map(|token| {
+                // Indices into TOKEN_TYPES and bit flags for the modifiers in the legend
+                let (token_type, modifiers) = match token.kind {
+                    TokenKind::Mnemonic => (0, 0),
+                    TokenKind::Condition => (1, 0),
+                    TokenKind::Register => (2, 0),
+                    TokenKind::Alias => (2, 0b10),
+                    TokenKind::LabelDefinition => (3, 0b01),
+                    TokenKind::LabelReference => (3, 0),
+                    TokenKind::Immediate => (4, 0),
+                    TokenKind::Shift => (5, 0),
+                    TokenKind::Comment => (6, 0),
+                };
+
+                let start = position(src, token.from as usize);
+                let end = position(src, token.to as usize);
+                let delta_line = start.line - previous.line;
+                let delta_start = if delta_line == 0 { start.character - previous.character } else { start.character };
+                previous = start;
+
+                SemanticToken {
+                    delta_line,
+                    delta_start,
+                    length: end.character - start.character,
+                    token_type,
+                    token_modifiers_bitset: modifiers,
+                }
+            })
+            .collect();
+
+        Ok(Some(SemanticTokensResult::Tokens(SemanticTokens { result_id: None, data })))
+    }
+}
+
+fn location(uri: &Uri, src: &str, span: SourceSpan) -> Location {
+    Location::new(uri.clone(), range(src, span.from, span.to))
+}
+
+fn range(src: &str, from: u32, to: u32) -> Range {
+    Range::new(position(src, from as usize), position(src, to as usize))
+}
+
+/// Converts an LSP position, which counts UTF-16 code units, into a byte offset.
+fn offset(src: &str, position: Position) -> usize {
+    utf16::line_column_offset(src, position.line, position.character)
+}
+
+fn position(src: &str, offset: usize) -> Position {
+    let (line, character) = utf16::line_column(src, offset);
+    Position::new(line, character)
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_server::{Connection, Message, Notification};
+    use lsp_types::{notification::{DidOpenTextDocument, Notification as _, PublishDiagnostics}, Position};
+    use serde_json::json;
+
+    use super::{offset, position, Server};
+
+    #[test]
+    fn converts_positions() {
+        let src = "; π ≈ 3\nmov r1, #3\n";
+
+        assert_eq!(offset(src, Position::new(1, 4)), 15);
+        assert_eq!(position(src, 15), Position::new(1, 4));
+        assert_eq!(position(src, 5), Position::new(0, 4));
+        assert_eq!(offset(src, Position::new(0, 4)), 5);
+        assert_eq!(offset(src, Position::new(0, 99)), 10);
+    }
+
+    #[test]
+    fn survives_malformed_notifications() {
+        let (server, client) = Connection::memory();
+        let open = |params| Message::Notification(Notification::new(DidOpenTextDocument::METHOD.into(), params));
+
+        client.sender.send(open(json!({ "textDocument": 3 }))).unwrap();
+        client.sender.send(open(json!({ "textDocument": { "uri": "file:///a.s", "languageId": "asm", "version": 1, "text": "foo r1" } }))).unwrap();
+        drop(client.sender);
+
+        Server::default().run(&server).unwrap();
+        match client.receiver.try_recv().unwrap() {
+            Message::Notification(notification) => assert_eq!(notification.method, PublishDiagnostics::METHOD),
+            message => panic!("Expected diagnostics, got {message:?}"),
+        }
+    }
+}
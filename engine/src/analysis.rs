@@ -0,0 +1,247 @@
+use std::collections::HashSet;
+
+use pest::{error::ErrorVariant, Span};
+
+use crate::{
+    assembler::{AssembledInstruction, Assembly, SourceSpan, SymbolIndex},
+    console::Service,
+    parser::Rule,
+    Condition, DataProcessingOpcode, DataProcessingOperand, Instruction, InstructionBody, Lint, LintCode, Register,
+};
+
+const LINK_REGISTER: Register = Register(14);
+const PROGRAM_COUNTER: Register = Register(15);
+
+/// Where execution can go after an instruction.
+struct Successors {
+    /// Indices of the next instructions, where one past the last instruction is the end of the program
+    next: Vec<usize>,
+    /// Whether the PC is also written with a value only known at runtime
+    computed: bool,
+}
+
+/// Warnings and hints about code which assembles but probably doesn't do what was meant.
+pub(crate) fn analyse(src: &str, assembly: &Assembly) -> Vec<Lint> {
+    let program = &assembly.instructions;
+    let mut lints = Vec::new();
+
+    for (name, symbol) in SymbolIndex::build(src).symbols {
+        if let (Some(definition), true) = (symbol.definition, symbol.references.is_empty()) {
+            lints.push(lint(src, LintCode::UnusedLabel, definition, &format!("The label `{name}` is never used")));
+        }
+    }
+
+    let reachable = reachable(program);
+    let has_computed_jumps = (0..program.len()).any(|i| successors(program, i).computed);
+    let written = program
+        .iter()
+        .filter_map(|line| line.instruction)
+        .flat_map(|instruction| instruction.registers_written())
+        .collect::<HashSet<_>>();
+
+    for (i, line) in program.iter().enumerate() {
+        let mut warn = |code, message: &str| lints.push(lint(src, code, line.span, message));
+
+        // Only the first instruction of an unreachable block is reported, to avoid a wall of warnings
+        if !reachable[i] && !has_computed_jumps && (i == 0 || reachable[i - 1]) {
+            warn(LintCode::UnreachableCode, "This instruction can never be reached");
+        }
+
+        let instruction = match line.instruction {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+
+        if let InstructionBody::DataProcessing(dp) = instruction.body {
+            if dp.dest == PROGRAM_COUNTER && !dp.opcode.is_comparison() && !is_return(&instruction) {
+                warn(LintCode::WritesPc, "Writing to the PC with a data processing instruction makes control flow hard to follow, use `B` to branch instead");
+            }
+        }
+
+        for register in instruction.registers_read() {
+            if register != PROGRAM_COUNTER && !written.contains(&register) {
+                warn(LintCode::UninitialisedRegister, &format!("{register} is not set by the program before this read"));
+            }
+        }
+
+        if let InstructionBody::Branch(branch) = instruction.body {
+            let target = branch.target(i as u32 * 4);
+            if target / 4 >= program.len() as u32 {
+                warn(LintCode::BranchIntoData, &format!("This branches to {target:#04x}, which is past the end of the program"));
+            }
+        }
+
+        if reachable[i] && successors(program, i).next.contains(&program.len()) && !matches!(instruction.body, InstructionBody::Branch(_)) {
+            warn(LintCode::FallsOffEnd, "Execution continues past the end of the program, end with `SVC #0` or a branch to itself to stop");
+        }
+
+        if reachable[i] && matches!(instruction.body, InstructionBody::DataProcessing(dp) if dp.opcode.is_comparison()) && !flags_used(program, i) {
+            warn(LintCode::UnusedComparison, "The result of this comparison is never used by a conditional instruction");
+        }
+    }
+
+    lints
+}
+
+fn successors(program: &[AssembledInstruction], i: usize) -> Successors {
+    let fall_through = Successors { next: vec![i + 1], computed: false };
+    let instruction = match program[i].instruction {
+        Some(instruction) => instruction,
+        None => return fall_through,
+    };
+
+    let conditional = instruction.condition != Condition::AL;
+    match instruction.body {
+        InstructionBody::Branch(branch) => {
+            let target = branch.target(i as u32 * 4);
+            let mut next = Vec::new();
+            if target / 4 < program.len() as u32 {
+                next.push(target as usize / 4);
+            }
+            // A branch with link comes back to the next instruction when the subroutine returns
+            if conditional || branch.link {
+                next.push(i + 1);
+            }
+            Successors { next, computed: false }
+        }
+        InstructionBody::DataProcessing(dp) if dp.dest == PROGRAM_COUNTER && !dp.opcode.is_comparison() => Successors {
+            next: if conditional { vec![i + 1] } else { Vec::new() },
+            // Returns go back to after a `BL`, which is already accounted for
+            computed: !is_return(&instruction),
+        },
+        InstructionBody::DataProcessing(_) => fall_through,
+        InstructionBody::SupervisorCall(call) if call.service() == Some(Service::Exit) => Successors {
+            next: if conditional { vec![i + 1] } else { Vec::new() },
+            computed: false,
+        },
+        InstructionBody::SupervisorCall(_) => fall_through,
+    }
+}
+
+fn reachable(program: &[AssembledInstruction]) -> Vec<bool> {
+    let mut reachable = vec![false; program.len() + 1];
+    let mut stack = vec![0];
+
+    while let Some(i) = stack.pop() {
+        if reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+
+        if i < program.len() {
+            stack.extend(successors(program, i).next);
+        }
+    }
+
+    reachable
+}
+
+/// Whether any path from the comparison at `from` reaches an instruction reading the flags before they are overwritten.
+fn flags_used(program: &[AssembledInstruction], from: usize) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = vec![from + 1];
+
+    while let Some(i) = stack.pop() {
+        if i >= program.len() || !seen.insert(i) {
+            continue;
+        }
+
+        let instruction = match program[i].instruction {
+            Some(instruction) => instruction,
+            None => return true,
+        };
+
+        if instruction.reads_flags() {
+            return true;
+        }
+
+        if instruction.writes_flags() {
+            continue;
+        }
+
+        let next = successors(program, i);
+        if next.computed || is_return(&instruction) {
+            return true;
+        }
+        stack.extend(next.next);
+    }
+
+    false
+}
+
+/// `MOV PC, LR`, returning from a subroutine.
+fn is_return(instruction: &Instruction) -> bool {
+    matches!(
+        instruction.body,
+        InstructionBody::DataProcessing(dp) if dp.opcode == DataProcessingOpcode::MOV
+            && dp.dest == PROGRAM_COUNTER
+            && matches!(dp.operand, DataProcessingOperand::Register { register: LINK_REGISTER, shift } if shift == Default::default())
+    )
+}
+
+fn lint(src: &str, code: LintCode, span: SourceSpan, message: &str) -> Lint {
+    let error = pest::error::Error::<Rule>::new_from_span(
+        ErrorVariant::CustomError { message: message.into() },
+        Span::new(src, span.from as usize, span.to as usize).unwrap(),
+    );
+
+    Lint::new(code, span, &error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::analyse;
+    use crate::{assembler::assemble_all, LintCode};
+
+    fn codes(src: &str) -> Vec<(u32, LintCode)> {
+        let mut codes = analyse(src, &assemble_all(src)).into_iter().map(|lint| (lint.line, lint.code)).collect::<Vec<_>>();
+        codes.sort();
+        codes
+    }
+
+    #[test]
+    fn warns() {
+        let src = "start:\nmov r0, #1\ncmp r0, r1\nadd r2, r0, #1\nb done\nmov r3, #2\nb start\ndone:\nb done\nlater:";
+
+        assert_eq!(codes(src), [
+            (2, LintCode::UninitialisedRegister),
+            (2, LintCode::UnusedComparison),
+            (5, LintCode::UnreachableCode),
+            (9, LintCode::UnusedLabel),
+        ]);
+
+        let src = "mov r1, #3\nloop:\nsubs r1, r1, #1\ncmp r1, #0\nbne loop\nb after\nafter:";
+        assert_eq!(codes(src), [(5, LintCode::BranchIntoData)]);
+
+        let src = "bl sub\nend:\nb end\nsub:\nmov r1, #1\nmov pc, lr";
+        assert_eq!(codes(src), []);
+
+        assert_eq!(codes("mov r0, #0\nsvc #0\nmov r0, #1"), [(2, LintCode::UnreachableCode)]);
+
+        assert_eq!(codes("mov r1, #1"), [(0, LintCode::FallsOffEnd)]);
+        assert_eq!(codes("mov r1, #4\nmov pc, r1"), [(1, LintCode::WritesPc)]);
+    }
+
+    #[test]
+    fn only_s_instructions_overwrite_comparisons() {
+        let src = "mov r1, #1\nloop:\ncmp r1, #1\nmov r2, #3\nbeq loop\nb loop";
+        assert_eq!(codes(src), []);
+
+        let src = "mov r1, #1\nloop:\ncmp r1, #1\nmovs r2, #3\nbeq loop\nb loop";
+        assert_eq!(codes(src), [(2, LintCode::UnusedComparison)]);
+    }
+
+    #[test]
+    fn explains_uninitialised_registers() {
+        let src = "mov r1, r2\nend:\nb end";
+        let lints = analyse(src, &assemble_all(src));
+        assert_eq!(lints[0].message, "R2 is not set by the program before this read");
+    }
+
+    #[test]
+    fn suggests_how_to_stop() {
+        let src = "mov r1, #1";
+        let lints = analyse(src, &assemble_all(src));
+        assert_eq!(lints[0].message, "Execution continues past the end of the program, end with `SVC #0` or a branch to itself to stop");
+    }
+}
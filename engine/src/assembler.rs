@@ -1,108 +1,280 @@
-use std::{collections::HashMap, ops::Div};
+use std::{collections::{HashMap, HashSet}, ops::Div};
 
 use pest::{
-    error::ErrorVariant, iterators::{Pair, Pairs}, Parser, Span
+    error::{ErrorVariant, InputLocation}, iterators::{Pair, Pairs}, Parser, Span
 };
+use serde::Serialize;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
 use crate::{
-    parser::{self, AssemblyParser, Rule}, unwrap_or_continue, Condition, DataProcessing, DataProcessingOpcode, DataProcessingOperand, Instruction, InstructionBody, Program, Register, Shift
+    disassembler::SymbolTable,
+    quickfix::{self, Fix},
+    parser::{self, AssemblyParser, Rule}, unwrap_or_continue, Condition, DataProcessing, DataProcessingOpcode, DataProcessingOperand, Instruction, InstructionBody, Program, Register, Shift, ShiftAmount, ShiftType, SupervisorCall
 };
 
-const MAX_REG_NUM: u8 = 15;
+pub(crate) const MAX_REG_NUM: u8 = 15;
+const MAX_SHIFT: u32 = 31;
+const BRANCH_OFFSET_MASK: u32 = (1 << 24) - 1;
+const SVC_COMMENT_MASK: u32 = (1 << 24) - 1;
 
 pub type Res<T> = Result<T, pest::error::Error<parser::Rule>>;
 
+/// Assembles `src`, failing with the first error.
 pub fn assemble(src: &str) -> Res<Program> {
-    let parsed = AssemblyParser::parse(Rule::program, src)?.next().unwrap();
+    let assembly = assemble_all(src);
 
-    let labels = get_labels(&parsed);
+    match assembly.diagnostics.into_iter().next() {
+        Some(diagnostic) => Err(diagnostic.error),
+        None => Ok(Program {
+            instructions: assembly.instructions.into_iter().filter_map(|line| line.instruction).collect(),
+        }),
+    }
+}
+
+/// An error along with where it is in the source.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub span: SourceSpan,
+    pub error: pest::error::Error<Rule>,
+    pub fixes: Vec<Fix>,
+}
 
-    let mut instructions = Vec::new();
-    let mut current_addr = 0;
+#[derive(Debug, Clone)]
+pub struct AssembledInstruction {
+    /// `None` if the instruction failed to assemble
+    pub instruction: Option<Instruction>,
+    pub span: SourceSpan,
+}
 
-    for line in parsed.into_inner() {
-        let line = unwrap_or_continue!(line.into_inner().next());
-        match line.as_rule() {
-            Rule::instruction => {
-                instructions.push(assemble_instruction(line, &labels, current_addr)?);
-                current_addr += 4;
+/// The result of assembling a whole file, carrying on past errors.
+#[derive(Debug, Clone, Default)]
+pub struct Assembly {
+    /// Every instruction line in address order. Lines which parse but fail to assemble still take up an address.
+    pub instructions: Vec<AssembledInstruction>,
+    pub labels: HashMap<String, u32>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Assembly {
+    /// Map from address to the line of the instruction there.
+    pub fn source_map(&self) -> HashMap<u32, u32> {
+        self.instructions
+            .iter()
+            .enumerate()
+            .map(|(i, line)| (i as u32 * 4, line.span.line))
+            .collect()
+    }
+
+    /// Addresses of the branches which have a condition, so can go either way.
+    pub fn conditional_branches(&self) -> Vec<u32> {
+        self.instructions
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| line.instruction.is_some_and(|instruction| instruction.is_conditional_branch()))
+            .map(|(i, _)| i as u32 * 4)
+            .collect()
+    }
+
+    /// Writes every instruction which assembled into `ram`, leaving the words of ones which failed as zero.
+    pub fn serialise(&self, ram: &mut [u8]) {
+        for (dest, line) in ram.chunks_mut(4).zip(&self.instructions) {
+            match line.instruction {
+                Some(instruction) => instruction.serialise(dest),
+                None => dest.fill(0),
+            }
+        }
+    }
+}
+
+/// Assembles every line of `src`, collecting all the errors rather than stopping at the first.
+pub fn assemble_all(src: &str) -> Assembly {
+    let parsed = AssemblyParser::parse(Rule::program, src)
+        .expect("invalid lines are parsed as `invalid_line`")
+        .next()
+        .unwrap();
+    let lines = lines_with_offsets(src).collect::<Vec<_>>();
+
+    let mut assembly = Assembly {
+        labels: get_labels(&parsed),
+        ..Default::default()
+    };
+    let mut defined = HashSet::new();
+
+    for entry in parsed.into_inner() {
+        match entry.as_rule() {
+            Rule::line => {
+                let line = entry.into_inner().next().unwrap();
+                if line.as_rule() == Rule::label {
+                    let name = line.into_inner().next().unwrap();
+                    if !defined.insert(name.as_str()) {
+                        let error = span_err(name.as_span(), &format!("Label `{}` is already defined", name.as_str()));
+                        assembly.diagnostics.push(Diagnostic { span: source_span(&name), error, fixes: Vec::new() });
+                    }
+                    continue;
+                }
+                if line.as_rule() != Rule::instruction {
+                    continue;
+                }
+
+                let span = source_span(&line);
+                let addr = assembly.instructions.len() as u32 * 4;
+                let instruction = match assemble_instruction(line.clone(), &assembly.labels, addr) {
+                    Ok(instruction) => Some(instruction),
+                    Err(error) => {
+                        let fixes = quickfix::suggest(line, &assembly.labels);
+                        assembly.diagnostics.push(Diagnostic { span: error_span(&error, span.line, 0), error, fixes });
+                        None
+                    }
+                };
+
+                assembly.instructions.push(AssembledInstruction { instruction, span });
             }
-            Rule::label => {},
-            _ => unreachable!(),
+            Rule::invalid_line => {
+                // Parse the line on its own to find out exactly where it went wrong
+                let line = entry.line_col().0 - 1;
+                let (start, text) = lines[line];
+                let error = match AssemblyParser::parse(Rule::lint_line, text) {
+                    Err(error) => error,
+                    Ok(_) => span_err(Span::new(text, 0, text.len()).unwrap(), "Invalid line"),
+                };
+
+                assembly.diagnostics.push(Diagnostic { span: error_span(&error, line as u32, start), error, fixes: Vec::new() });
+            }
+            _ => {}
         }
     }
 
-    Ok(Program { instructions })
+    assembly
 }
 
-pub fn get_lint_labels(lines: &[Res<Pairs<'_, Rule>>]) -> HashMap<String, u32> {
-    let labels = lines.iter()
-        .filter_map(|line| line.as_ref().ok())
-        .cloned()
-        .filter_map(|mut line| {
-            line.next()
-                .unwrap()
-                .into_inner()
-                .next()
-                .and_then(|line| match line.as_rule() {
-                    parser::Rule::label => Some(line.into_inner().next().unwrap().as_str().to_string()),
-                    _ => None
-                })
-        });
+fn source_span(pair: &Pair<'_, Rule>) -> SourceSpan {
+    SourceSpan {
+        line: pair.line_col().0 as u32 - 1,
+        from: pair.as_span().start() as u32,
+        to: pair.as_span().end() as u32,
+    }
+}
 
-    labels
-        .map(|label| (label, 0))
+/// Where `error` is in the source, given the line it is on and the offset its input starts at.
+fn error_span(error: &pest::error::Error<Rule>, line: u32, offset: usize) -> SourceSpan {
+    let (from, to) = match error.location {
+        InputLocation::Pos(p) => (p, p),
+        InputLocation::Span(span) => span,
+    };
+
+    SourceSpan { line, from: (offset + from) as u32, to: (offset + to) as u32 }
+}
+
+/// Labels in `src` keyed by address, or an empty table if it does not parse.
+pub fn symbol_table(src: &str) -> SymbolTable {
+    let parsed = match AssemblyParser::parse(Rule::program, src) {
+        Ok(mut parsed) => parsed.next().unwrap(),
+        Err(_) => return SymbolTable::new(),
+    };
+
+    get_labels(&parsed)
+        .into_iter()
+        .map(|(label, addr)| (addr, label))
         .collect()
 }
 
-pub fn parse_per_line(src: &str) -> Vec<Res<Pairs<'_, Rule>>> {
-    src
-        .lines()
-        .map(|line| AssemblyParser::parse(Rule::lint_line, line))
-        .collect::<Vec<_>>()
+/// A range of the source in byte offsets, along with the zero based line it is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SourceSpan {
+    pub line: u32,
+    pub from: u32,
+    pub to: u32,
 }
 
-pub fn gen_source_map(lines: &[Res<Pairs<'_, Rule>>]) -> HashMap<u32, u32> {
-    let mut current_addr = 0;
-    let mut source_map = HashMap::new();
+impl SourceSpan {
+    /// Whether a cursor at `offset` is touching the span, including just after its end.
+    pub fn contains(&self, offset: usize) -> bool {
+        self.from as usize <= offset && offset <= self.to as usize
+    }
+}
 
-    for (i, line) in lines.iter().cloned().enumerate() {
-        if let Ok(mut line) = line {
-            if let Some(line) = line.next()
-                .unwrap()
-                .into_inner()
-                .next() {
-                    match line.as_rule() {
-                        parser::Rule::instruction => {
-                            source_map.insert(current_addr as u32, i as u32);
-                            current_addr += 4;
-                        },
-                        _ => {}
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Symbol {
+    pub definition: Option<SourceSpan>,
+    pub addr: Option<u32>,
+    pub references: Vec<SourceSpan>,
+}
+
+/// Every label in a program, with where it is defined and used.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolIndex {
+    pub symbols: HashMap<String, Symbol>,
+}
+
+impl SymbolIndex {
+    /// Indexes `src` line by line, so lines which fail to parse don't hide the rest of the file.
+    pub fn build(src: &str) -> Self {
+        let mut index = Self::default();
+        let mut addr = 0;
+
+        for (i, (start, line)) in lines_with_offsets(src).enumerate() {
+            let parsed = match AssemblyParser::parse(Rule::lint_line, line) {
+                Ok(mut parsed) => unwrap_or_continue!(parsed.next().unwrap().into_inner().next()),
+                Err(_) => continue,
+            };
+
+            let span = |pair: &Pair<'_, Rule>| SourceSpan {
+                line: i as u32,
+                from: (start + pair.as_span().start()) as u32,
+                to: (start + pair.as_span().end()) as u32,
+            };
+
+            match parsed.as_rule() {
+                Rule::label => {
+                    let name = parsed.into_inner().next().unwrap();
+                    let symbol = index.symbols.entry(name.as_str().to_string()).or_default();
+                    if symbol.definition.is_none() {
+                        symbol.definition = Some(span(&name));
+                        symbol.addr = Some(addr);
+                    }
+                }
+                Rule::instruction => {
+                    for arg in parsed.into_inner().filter(|arg| arg.as_rule() == Rule::text) {
+                        index.symbols.entry(arg.as_str().to_string()).or_default().references.push(span(&arg));
                     }
+                    addr += 4;
                 }
+                _ => {}
+            }
         }
-    }
 
-    source_map
-}
+        index
+    }
 
-pub fn lint_line(parsed: Pair<'_, Rule>, labels: &HashMap<String, u32>) -> Res<()> {
-    let parsed = match parsed.into_inner().next() {
-        Some(p) => p,
-        None => return Ok(()),
-    };
+    /// Label name to address, for every defined label.
+    pub fn addresses(&self) -> HashMap<String, u32> {
+        self.symbols
+            .iter()
+            .filter_map(|(name, symbol)| Some((name.clone(), symbol.addr?)))
+            .collect()
+    }
 
-    match parsed.as_rule() {
-        Rule::label => Ok(()),
-        Rule::instruction => assemble_instruction(parsed, labels, 0).map(|_| ()),
-        Rule::EOI => Ok(()),
-        _ => unreachable!("{parsed:?}"),
+    /// The symbol defined or referenced at `offset`.
+    pub fn at(&self, offset: usize) -> Option<(&str, &Symbol)> {
+        self.symbols
+            .iter()
+            .find(|(_, symbol)| symbol.definition.iter().chain(&symbol.references).any(|span| span.contains(offset)))
+            .map(|(name, symbol)| (name.as_str(), symbol))
     }
 }
 
-fn span_err(span: Span<'_>, msg: &str) -> pest::error::Error<parser::Rule> {
+/// Each line of `src` with the byte offset it starts at, split the same way as [`str::lines`].
+pub(crate) fn lines_with_offsets(src: &str) -> impl Iterator<Item = (usize, &str)> {
+    src.split_inclusive('\n').scan(0, |start, line| {
+        let line_start = *start;
+        *start += line.len();
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        Some((line_start, line.strip_suffix('\r').unwrap_or(line)))
+    })
+}
+
+pub(crate) fn span_err(span: Span<'_>, msg: &str) -> pest::error::Error<parser::Rule> {
     pest::error::Error::new_from_span(
         ErrorVariant::CustomError {
             message: msg.into(),
@@ -118,7 +290,8 @@ fn get_labels(src: &Pair<'_, Rule>) -> HashMap<String, u32> {
     for line in src.clone().into_inner() {
         let line = unwrap_or_continue!(line.into_inner().next());
         match line.as_rule() {
-            Rule::label => { labels.insert(line.into_inner().next().unwrap().as_str().to_string(), addr); },
+            // Later definitions of the same label are errors, so the first one is used, as in the symbol index
+            Rule::label => { labels.entry(line.into_inner().next().unwrap().as_str().to_string()).or_insert(addr); },
             Rule::instruction => addr += 4,
             _ => unreachable!("{line:?}")
         }
@@ -127,20 +300,21 @@ fn get_labels(src: &Pair<'_, Rule>) -> HashMap<String, u32> {
     labels
 }
 
-fn assemble_instruction(src: Pair<'_, parser::Rule>, labels: &HashMap<String, u32>, current_addr: u32) -> Res<Instruction> {
+pub(crate) fn assemble_instruction(src: Pair<'_, parser::Rule>, labels: &HashMap<String, u32>, current_addr: u32) -> Res<Instruction> {
     let src_span = src.as_span();
     let mut inner = src.into_inner();
     let opcode = inner.next().ok_or(span_err(src_span, "Missing opcode"))?;
     // let opcode_span = opcode.as_span();
     
-    if let Some((opcode, condition)) = parse_opcode(opcode.as_str()) {
-        let body = match opcode {
+    if let Some((opcode, condition, set_condition_codes)) = parse_opcode(opcode.as_str()) {
+        let mut body = match opcode {
             Opcode::And => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::AND),
             Opcode::Eor => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::EOR),
             Opcode::Sub => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::SUB),
             Opcode::Rsb => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::RSB),
             Opcode::Add => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::ADD),
             Opcode::Adc => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::ADC),
+            Opcode::Sbc => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::SBC),
             Opcode::Rsc => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::RSC),
             Opcode::Tst => assemble_two_arg_dp(&mut inner, src_span, DataProcessingOpcode::TST),
             Opcode::Teq => assemble_two_arg_dp(&mut inner, src_span, DataProcessingOpcode::TEQ),
@@ -149,10 +323,17 @@ fn assemble_instruction(src: Pair<'_, parser::Rule>, labels: &HashMap<String, u3
             Opcode::Or => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::ORR),
             Opcode::Mov => assemble_two_arg_dp_dest(&mut inner, src_span, DataProcessingOpcode::MOV),
             Opcode::Bic => assemble_three_arg_dp(&mut inner, src_span, DataProcessingOpcode::BIC),
-            Opcode::Mvn => assemble_two_arg_dp(&mut inner, src_span, DataProcessingOpcode::MVN),
+            Opcode::Mvn => assemble_two_arg_dp_dest(&mut inner, src_span, DataProcessingOpcode::MVN),
             Opcode::B => assemble_branch(&mut inner, src_span, false, labels, current_addr),
             Opcode::Bl => assemble_branch(&mut inner, src_span, true, labels, current_addr),
+            Opcode::Svc => assemble_supervisor_call(&mut inner, src_span),
         }?;
+
+        if let InstructionBody::DataProcessing(dp) = &mut body {
+            // Comparisons are encoded with S set as they only exist to update the flags
+            dp.set_condition_codes = set_condition_codes || dp.opcode.is_comparison();
+        }
+
         Ok(Instruction { condition, body })
     } else {
         Err(span_err(src_span, "Invalid opcode"))
@@ -160,14 +341,15 @@ fn assemble_instruction(src: Pair<'_, parser::Rule>, labels: &HashMap<String, u3
 
 }
 
-#[derive(EnumIter, Debug)]
-enum Opcode {
+#[derive(EnumIter, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Opcode {
     And,
     Eor,
     Sub,
     Rsb,
     Add,
     Adc,
+    Sbc,
     Rsc,
     Tst,
     Teq,
@@ -178,10 +360,16 @@ enum Opcode {
     Bic,
     Mvn,
     B,
-    Bl
+    Bl,
+    Svc
 }
 
 impl Opcode {
+    /// Whether the opcode takes an `S` suffix to update the flags.
+    pub(crate) fn accepts_s(&self) -> bool {
+        !matches!(self, Opcode::Tst | Opcode::Teq | Opcode::Cmp | Opcode::Cmn | Opcode::B | Opcode::Bl | Opcode::Svc)
+    }
+
     pub fn as_str(&self) -> &'static [&'static str] {
         match self {
             Opcode::And => &["and"],
@@ -190,6 +378,7 @@ impl Opcode {
             Opcode::Rsb => &["rsb"],
             Opcode::Add => &["add"],
             Opcode::Adc => &["adc"],
+            Opcode::Sbc => &["sbc"],
             Opcode::Rsc => &["rsc"],
             Opcode::Tst => &["tst"],
             Opcode::Teq => &["teq"],
@@ -201,11 +390,13 @@ impl Opcode {
             Opcode::Mvn => &["mvn"],
             Opcode::B =>   &["b"],
             Opcode::Bl =>  &["bl"],
+            Opcode::Svc => &["svc", "swi"],
         }
     }
 }
 
-fn parse_opcode(src: &str) -> Option<(Opcode, Condition)> {
+/// Splits a mnemonic such as `addseq` into its opcode, condition and whether the `S` suffix was present.
+pub(crate) fn parse_opcode(src: &str) -> Option<(Opcode, Condition, bool)> {
     let src = src.to_ascii_lowercase();
     for opcode in Opcode::iter() {
         for op_str in opcode.as_str() {
@@ -215,13 +406,13 @@ fn parse_opcode(src: &str) -> Option<(Opcode, Condition)> {
 
             let remaining = &src[op_str.len()..];
 
-            if remaining.is_empty() {
-                return Some((opcode, Condition::AL))
+            if let Some(condition) = parse_condition(remaining) {
+                return Some((opcode, condition, false));
             }
 
-            for condition in Condition::iter() {
-                if remaining == condition.as_str() {
-                    return Some((opcode, condition));
+            if let Some(remaining) = remaining.strip_prefix('s').filter(|_| opcode.accepts_s()) {
+                if let Some(condition) = parse_condition(remaining) {
+                    return Some((opcode, condition, true));
                 }
             }
         }
@@ -230,8 +421,16 @@ fn parse_opcode(src: &str) -> Option<(Opcode, Condition)> {
     None
 }
 
+fn parse_condition(src: &str) -> Option<Condition> {
+    if src.is_empty() {
+        return Some(Condition::AL);
+    }
+
+    Condition::iter().find(|condition| condition.as_str() == src)
+}
+
 impl Condition {
-    fn as_str(&self) -> &'static str {
+    pub(crate) fn as_str(&self) -> &'static str {
         match self {
             Condition::EQ => "eq",
             Condition::NE => "ne",
@@ -256,26 +455,37 @@ fn assemble_branch(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, link: bool, labe
     let offset = pairs.next().ok_or(span_err(span, "Missing offset"))?;
     let offset = match offset.as_rule() {
         Rule::literal => parse_literal(offset)?,
+        // The PC reads two instructions ahead when the branch executes
         Rule::text => labels.get(offset.as_str())
             .ok_or(span_err(span, "Unknown label"))?
             .div(4)
-            .wrapping_sub(current_addr / 4 + 1),
+            .wrapping_sub(current_addr / 4 + 2),
         _ => return Err(span_err(span, "Invalid offset"))
     };
 
-    Ok(InstructionBody::Branch(crate::Branch { link, offset }))
+    Ok(InstructionBody::Branch(crate::Branch { link, offset: offset & BRANCH_OFFSET_MASK }))
 }
 
-fn assemble_two_arg_dp_dest(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, opcode: DataProcessingOpcode) -> Res<InstructionBody> {
-    let dest_reg = pairs.next().ok_or(span_err(span, "Missing destination"))?;
-    let src = pairs.next().ok_or(span_err(span, "Missing source"))?;
+fn assemble_supervisor_call(pairs: &mut Pairs<'_, Rule>, span: Span<'_>) -> Res<InstructionBody> {
+    let number = pairs.next().ok_or(span_err(span, "Missing call number"))?;
+    let number_span = number.as_span();
+    let comment = match number.as_rule() {
+        Rule::literal => parse_literal(number)?,
+        _ => return Err(span_err(number_span, "Expected a call number")),
+    };
 
-    if pairs.next().is_some() {
-        return Err(span_err(span, "Expected end of instruction"))
+    if comment > SVC_COMMENT_MASK {
+        return Err(span_err(number_span, "Call number must fit in 24 bits"));
     }
 
+    Ok(InstructionBody::SupervisorCall(SupervisorCall { comment }))
+}
+
+fn assemble_two_arg_dp_dest(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, opcode: DataProcessingOpcode) -> Res<InstructionBody> {
+    let dest_reg = pairs.next().ok_or(span_err(span, "Missing destination"))?;
+
     let dest = parse_reg(dest_reg)?;
-    let operand = parse_dp_operand(src)?;
+    let operand = parse_operand2(pairs, span, "Missing source")?;
 
     Ok(InstructionBody::DataProcessing(DataProcessing {
             dest,
@@ -288,14 +498,9 @@ fn assemble_two_arg_dp_dest(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, opcode:
 
 fn assemble_two_arg_dp(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, opcode: DataProcessingOpcode) -> Res<InstructionBody> {
     let reg1 = pairs.next().ok_or(span_err(span, "Missing register operand"))?;
-    let src = pairs.next().ok_or(span_err(span, "Missing source"))?;
-
-    if pairs.next().is_some() {
-        return Err(span_err(span, "Expected end of instruction"))
-    }
 
     let reg1 = parse_reg(reg1)?;
-    let operand = parse_dp_operand(src)?;
+    let operand = parse_operand2(pairs, span, "Missing source")?;
 
     Ok(InstructionBody::DataProcessing(DataProcessing {
             dest: Register(0),
@@ -309,15 +514,10 @@ fn assemble_two_arg_dp(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, opcode: Data
 fn assemble_three_arg_dp(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, opcode: DataProcessingOpcode) -> Res<InstructionBody> {
     let dest_reg = pairs.next().ok_or(span_err(span, "Missing destination"))?;
     let lhs = pairs.next().ok_or(span_err(span, "Missing lhs"))?;
-    let rhs = pairs.next().ok_or(span_err(span, "Missing rhs"))?;
-
-    if pairs.next().is_some() {
-        return Err(span_err(span, "Expected end of instruction"))
-    }
 
     let dest = parse_reg(dest_reg)?;
     let lhs = parse_reg(lhs)?;
-    let operand = parse_dp_operand(rhs)?;
+    let operand = parse_operand2(pairs, span, "Missing rhs")?;
 
     Ok(InstructionBody::DataProcessing(DataProcessing {
             dest,
@@ -328,24 +528,79 @@ fn assemble_three_arg_dp(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, opcode: Da
     }))
 }
 
+/// Parses the flexible second operand, a register with an optional shift or an immediate, which ends the instruction.
+fn parse_operand2(pairs: &mut Pairs<'_, Rule>, span: Span<'_>, missing: &str) -> Res<DataProcessingOperand> {
+    let src = pairs.next().ok_or(span_err(span, missing))?;
+    let mut operand = parse_dp_operand(src)?;
+
+    if let Some(next) = pairs.next() {
+        match (&mut operand, next.as_rule()) {
+            (DataProcessingOperand::Register { shift, .. }, Rule::shift) => *shift = parse_shift(next)?,
+            (_, Rule::shift) => return Err(span_err(next.as_span(), "Only register operands can be shifted")),
+            _ => return Err(span_err(span, "Expected end of instruction")),
+        }
+    }
+
+    if pairs.next().is_some() {
+        return Err(span_err(span, "Expected end of instruction"))
+    }
+
+    Ok(operand)
+}
+
+fn parse_shift(src: Pair<'_, Rule>) -> Res<Shift> {
+    let mut inner = src.into_inner();
+    let ty = match inner.next().expect("Missing shift type").as_str().to_ascii_lowercase().as_str() {
+        "lsl" => ShiftType::LogicalLeft,
+        "lsr" => ShiftType::LogicalRight,
+        "asr" => ShiftType::ArithmeticRight,
+        "ror" => ShiftType::RotateRight,
+        _ => unreachable!(),
+    };
+
+    let amount = inner.next().expect("Missing shift amount");
+    let amount = match amount.as_rule() {
+        Rule::register => ShiftAmount::Register(parse_reg(amount)?),
+        Rule::literal => {
+            let span = amount.as_span();
+            let value = parse_literal(amount)?;
+            if value > MAX_SHIFT {
+                return Err(span_err(span, &format!("Shift amount cannot be greater than {MAX_SHIFT}")));
+            }
+            ShiftAmount::Immediate(value as u8)
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(Shift { ty, amount })
+}
+
+/// Finds the smallest rotation that expresses `value` as an 8 bit immediate rotated right by twice the rotate field.
+pub(crate) fn encode_immediate(value: u32) -> Option<(u8, u8)> {
+    (0..16u8).find_map(|rotate| {
+        let unrotated = value.rotate_left(rotate as u32 * 2);
+        (unrotated <= 0xFF).then_some((rotate, unrotated as u8))
+    })
+}
+
 fn parse_dp_operand(src: Pair<'_, Rule>) -> Res<DataProcessingOperand> {
     match src.as_rule() {
-        Rule::literal => Ok(DataProcessingOperand::Immediate {
-            rotate: 0,
-            value: parse_literal(src)? as u8,
-        }),
+        Rule::literal => {
+            let span = src.as_span();
+            let (rotate, value) = encode_immediate(parse_literal(src)?)
+                .ok_or(span_err(span, "Immediate cannot be encoded as a rotated 8 bit value"))?;
+
+            Ok(DataProcessingOperand::Immediate { rotate, value })
+        },
         Rule::register => Ok(DataProcessingOperand::Register {
-            shift: Shift {
-                amount: crate::ShiftAmount::Immediate(0),
-                ty: crate::ShiftType::LogicalLeft
-            },
+            shift: Shift::default(),
             register: parse_reg(src)?,
         }),
         _ => Err(span_err(src.as_span(), "Invalid source"))?,
     }
 }
 
-fn parse_reg(reg: Pair<'_, Rule>) -> Res<Register> {
+pub(crate) fn parse_reg(reg: Pair<'_, Rule>) -> Res<Register> {
     let span = reg.as_span();
 
     let index_pair = reg
@@ -374,7 +629,7 @@ fn parse_reg(reg: Pair<'_, Rule>) -> Res<Register> {
     Ok(Register(index))
 }
 
-fn parse_literal(literal: Pair<'_, Rule>) -> Res<u32> {
+pub(crate) fn parse_literal(literal: Pair<'_, Rule>) -> Res<u32> {
     let span = literal.as_span();
     let mut inner = literal.into_inner();
 
@@ -385,14 +640,21 @@ fn parse_literal(literal: Pair<'_, Rule>) -> Res<u32> {
         _ => (next, false)
     };
 
+    let mut negative = negative;
     let value = match contents.as_rule() {
-        Rule::decimal_literal => contents
-            .into_inner()
-            .next()
-            .expect("Invalid literal")
-            .as_str()
-            .parse()
-            .or(Err(span_err(span, "Invalid decimal literal"))),
+        Rule::decimal_literal => {
+            let mut inner = contents.into_inner();
+            let mut digits = inner.next().expect("Invalid literal");
+            if digits.as_rule() == Rule::negation {
+                negative = !negative;
+                digits = inner.next().expect("Missing literal body");
+            }
+
+            digits
+                .as_str()
+                .parse()
+                .or(Err(span_err(span, "Invalid decimal literal")))
+        },
         Rule::hex_literal => u32::from_str_radix(
             contents
                 .into_inner()
@@ -406,7 +668,7 @@ fn parse_literal(literal: Pair<'_, Rule>) -> Res<u32> {
     }?;
 
     if negative {
-        Ok(!value + 1)
+        Ok(value.wrapping_neg())
     } else {
         Ok(value)
     }
@@ -414,9 +676,54 @@ fn parse_literal(literal: Pair<'_, Rule>) -> Res<u32> {
 
 #[cfg(test)]
 mod tests {
+    use super::{assemble, assemble_all, SourceSpan, SymbolIndex};
+
+    /// The words `src` assembles to.
+    fn encode(src: &str) -> Vec<u32> {
+        let program = assemble(src).unwrap();
+        let mut ram = vec![0; program.instructions.len() * 4];
+        program.serialise(&mut ram);
+        ram.chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn assembles_mvn_with_destination() {
+        assert_eq!(encode("mvn r2, #0"), [0xE3E02000]);
+        assert_eq!(encode("mvn r2, r3"), [0xE1E02003]);
+    }
+
+    #[test]
+    fn assembles_sbc() {
+        assert_eq!(encode("sbc r0, r1, r2"), [0xE0C10002]);
+    }
+
+    #[test]
+    fn parses_literals() {
+        assert_eq!(encode("mov r0, 0xFF"), [0xE3A000FF]);
+        assert_eq!(encode("mov r1, #-0\nmov r2, -#0"), [0xE3A01000, 0xE3A02000]);
+    }
+
+    #[test]
+    fn collects_every_error() {
+        let src = "mov r1, #1\nfoo r1\nmov r2 r3\nloop:\nb loop\nmov r4, #257";
+        let assembly = assemble_all(src);
+
+        assert_eq!(assembly.diagnostics.iter().map(|d| d.span.line).collect::<Vec<_>>(), [1, 2, 5]);
+        assert_eq!(&src[assembly.diagnostics[1].span.from as usize..], "r3\nloop:\nb loop\nmov r4, #257");
+        assert_eq!(assembly.labels["loop"], 8);
+        assert_eq!(assembly.source_map()[&8], 4);
+        assert_eq!(assembly.instructions.iter().map(|line| line.instruction.is_some()).collect::<Vec<_>>(), [true, false, true, false]);
+    }
+
     #[test]
-    fn test() {
-        simple_logger::init().unwrap();
-        // assemble("label1:\n\tmov R1, #12").unwrap()
+    fn reports_duplicate_labels() {
+        let src = "loop:\nb loop\nloop:\nb loop";
+        let assembly = assemble_all(src);
+
+        assert_eq!(assembly.diagnostics.len(), 1);
+        assert_eq!(assembly.diagnostics[0].span, SourceSpan { line: 2, from: 13, to: 17 });
+        assert_eq!(assembly.labels["loop"], 0);
+        assert_eq!(SymbolIndex::build(src).addresses()["loop"], 0);
+        assert!(assemble(src).is_err());
     }
 }
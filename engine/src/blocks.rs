@@ -0,0 +1,228 @@
+use crate::{
+    emulator::{alu, expand_immediate, operand_registers},
+    machine::Machine,
+    Condition, DataProcessingOpcode, DataProcessingOperand, Instruction, InstructionBody, Shift,
+};
+
+/// Instructions translated into one block at most, so long runs of code don't stall the budget checks between blocks.
+pub(crate) const MAX_BLOCK_LEN: usize = 64;
+
+/// Which way a [`Machine`] runs programs in [`Machine::run`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Fetch, decode and execute one instruction at a time
+    #[default]
+    Interpreter,
+    /// Translate straight-line code into micro-ops once, then run whole blocks at a time
+    Blocks,
+}
+
+/// Translated blocks by start address.
+///
+/// Like [`DecodeCache`](crate::decode_cache::DecodeCache), each block keeps the words it was translated from,
+/// and is translated again if memory has changed since, so self-modifying code still works.
+#[derive(Debug, Clone, Default)]
+pub struct BlockCache {
+    /// Indexed by word address
+    blocks: Vec<Option<Block>>,
+}
+
+/// Straight-line code ending at a branch, a write to the PC or an instruction only the interpreter handles.
+#[derive(Debug, Clone)]
+pub(crate) struct Block {
+    pub(crate) words: Vec<u8>,
+    pub(crate) ops: Vec<MicroOp>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MicroOp {
+    pub(crate) addr: u32,
+    pub(crate) condition: Condition,
+    /// Kept for metrics and profiling
+    pub(crate) instruction: Instruction,
+    pub(crate) kind: OpKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum OpKind {
+    DataProcessing {
+        opcode: DataProcessingOpcode,
+        /// `None` for comparisons, which only set the flags
+        dest: Option<usize>,
+        set_flags: bool,
+        lhs: usize,
+        rhs: Operand,
+    },
+    Branch {
+        link: bool,
+        target: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Operand {
+    /// Already expanded from its rotated form
+    Immediate(u32),
+    Register(usize),
+    Shifted { register: usize, shift: Shift },
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the block starting at the PC if it fits in `budget` instructions, returning how many it ran.
+    /// Returns `None` without running anything if the instruction at the PC has to go through the interpreter,
+    /// which includes the branch to itself a program halts at.
+    pub(crate) fn run(&mut self, machine: &mut Machine, budget: u64) -> Option<u64> {
+        let pc = machine.pc();
+        if !pc.is_multiple_of(4) || pc as usize >= machine.ram.len() {
+            return None;
+        }
+
+        let index = pc as usize / 4;
+        if index >= self.blocks.len() {
+            self.blocks.resize(index + 1, None);
+        }
+
+        let stale = self.blocks[index].as_ref().is_none_or(|block| machine.ram.get(pc as usize..pc as usize + block.words.len()) != Some(&block.words));
+        if stale {
+            self.blocks[index] = Some(translate(&machine.ram, pc)?);
+        }
+
+        let block = self.blocks[index].as_ref()?;
+        if block.ops.len() as u64 > budget {
+            return None;
+        }
+
+        for op in &block.ops {
+            op.execute(machine);
+        }
+
+        Some(block.ops.len() as u64)
+    }
+}
+
+/// Translates from `start` until the end of the block, or `None` if the first instruction can't be translated.
+pub(crate) fn translate(ram: &[u8], start: u32) -> Option<Block> {
+    let mut ops = Vec::new();
+
+    while ops.len() < MAX_BLOCK_LEN {
+        let addr = start + ops.len() as u32 * 4;
+        let Some(instruction) = ram.get(addr as usize..addr as usize + 4).and_then(|word| Instruction::deserialise(word.try_into().ok()?).ok()) else {
+            break;
+        };
+
+        let kind = match instruction.body {
+            InstructionBody::DataProcessing(data_processing) => OpKind::DataProcessing {
+                opcode: data_processing.opcode,
+                dest: (!data_processing.opcode.is_comparison()).then_some(data_processing.dest.0 as usize),
+                set_flags: data_processing.set_condition_codes,
+                lhs: data_processing.register.0 as usize,
+                rhs: match data_processing.operand {
+                    DataProcessingOperand::Immediate { rotate, value } => Operand::Immediate(expand_immediate(rotate, value)),
+                    DataProcessingOperand::Register { shift, register } if shift == Shift::default() => Operand::Register(register.0 as usize),
+                    DataProcessingOperand::Register { shift, register } => Operand::Shifted { register: register.0 as usize, shift },
+                },
+            },
+            // A branch to itself is how programs halt, which `Machine::halted` has to see before it runs
+            InstructionBody::Branch(branch) if branch.target(addr) == addr => break,
+            InstructionBody::Branch(branch) => OpKind::Branch { link: branch.link, target: branch.target(addr) },
+            // Only the machine can service calls, which may also need to wait for input
+            InstructionBody::SupervisorCall(_) => break,
+        };
+
+        ops.push(MicroOp { addr, condition: instruction.condition, instruction, kind });
+
+        let jumps = match kind {
+            OpKind::Branch { .. } => true,
+            OpKind::DataProcessing { dest, .. } => dest == Some(15),
+        };
+        if jumps {
+            break;
+        }
+    }
+
+    if ops.is_empty() {
+        return None;
+    }
+
+    let words = ram[start as usize..start as usize + ops.len() * 4].to_vec();
+    Some(Block { words, ops })
+}
+
+impl MicroOp {
+    /// Does exactly what the interpreter would for this instruction, including reading the PC as its address plus 8.
+    fn execute(&self, machine: &mut Machine) {
+        let registers = &mut machine.registers;
+        registers[15] = self.addr + 4;
+
+        let condition_passed = self.condition.matches(machine.flags);
+        if condition_passed {
+            match self.kind {
+                OpKind::DataProcessing { opcode, dest, set_flags, lhs, rhs } => {
+                    let reads = operand_registers(registers, self.addr);
+                    let rhs = match rhs {
+                        Operand::Immediate(value) => value,
+                        Operand::Register(register) => reads[register],
+                        Operand::Shifted { register, shift } => shift.eval(reads[register], &reads),
+                    };
+
+                    let (result, flags) = alu(opcode, reads[lhs], rhs, machine.flags);
+                    if let Some(dest) = dest {
+                        registers[dest] = result;
+                    }
+                    if set_flags {
+                        machine.flags = flags;
+                    }
+                }
+                OpKind::Branch { link, target } => {
+                    if link {
+                        registers[14] = self.addr + 4;
+                    }
+                    registers[15] = target;
+                }
+            }
+        }
+
+        machine.metrics.record(&machine.cycle_model, &self.instruction, condition_passed);
+        if let Some(profiler) = &mut machine.profiler {
+            profiler.record(self.addr, &self.instruction, condition_passed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::any, proptest};
+
+    use crate::{
+        differential::{differential, program_ram, random_program, PROGRAM_INPUT},
+        machine::Machine,
+        Instruction,
+    };
+
+    use super::BlockCache;
+
+    /// Runs `machine` with the block cache, as the [`Backend::Blocks`](super::Backend::Blocks) backend does.
+    fn run_blocks(machine: &mut Machine, max_steps: u64) -> anyhow::Result<u64> {
+        machine.blocks = Some(BlockCache::new());
+        machine.run(max_steps)
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        for max_steps in [0, 5, 40, 1000] {
+            differential(&program_ram(), [0; 16], 0, PROGRAM_INPUT, max_steps, run_blocks);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn random_programs_match_interpreter(program in vec(any::<Instruction>(), 1..24), registers in any::<[u32; 16]>(), flags in 0..16u8, max_steps in 0..200u64) {
+            let (ram, registers) = random_program(&program, registers);
+            differential(&ram, registers, flags, b"7\n", max_steps, run_blocks);
+        }
+    }
+}
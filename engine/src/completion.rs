@@ -0,0 +1,203 @@
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use crate::{
+    assembler::{parse_opcode, Opcode, SymbolIndex},
+    hover::line_at,
+    utf16::floor_char_boundary,
+    Condition, Register, ShiftType,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    pub detail: String,
+    /// Byte range of `src` replaced by the completion
+    pub from: u32,
+    pub to: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompletionKind {
+    Mnemonic,
+    Register,
+    Label,
+    Shift,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OperandKind {
+    Register,
+    /// A register or immediate, optionally followed by a shift
+    Operand2,
+    Label,
+}
+
+/// Completions for the partially typed word ending at byte `offset` in `src`.
+/// An offset inside a character is moved back to its start.
+pub fn complete(src: &str, offset: usize) -> Vec<Completion> {
+    let offset = floor_char_boundary(src, offset);
+    let (line_start, line) = match line_at(src, offset) {
+        Some(line) => line,
+        None => return Vec::new(),
+    };
+    let prefix = &line[..offset - line_start];
+
+    if prefix.contains("//") || prefix.contains(';') || prefix.contains(':') {
+        return Vec::new();
+    }
+
+    let word_len = prefix.len() - prefix.trim_end_matches(|c: char| c.is_ascii_alphanumeric()).len();
+    let word = &prefix[prefix.len() - word_len..];
+    let range = ((offset - word_len) as u32, offset as u32);
+
+    let mut parts = prefix.trim_start().splitn(2, char::is_whitespace);
+    let mnemonic = parts.next().unwrap_or_default();
+    let args = match parts.next() {
+        Some(args) => args,
+        None => return complete_mnemonic(range),
+    };
+
+    let operands = match parse_opcode(mnemonic) {
+        Some((opcode, _, _)) => opcode.operands(),
+        None => return Vec::new(),
+    };
+
+    // A literal can't be completed
+    if args.trim_end_matches(word).ends_with('#') {
+        return Vec::new();
+    }
+
+    match operands.get(args.matches(',').count()) {
+        Some(OperandKind::Register | OperandKind::Operand2) => complete_register(range),
+        Some(OperandKind::Label) => complete_label(src, range),
+        None if operands.last() == Some(&OperandKind::Operand2) => complete_shift(range),
+        None => Vec::new(),
+    }
+}
+
+fn complete_mnemonic((from, to): (u32, u32)) -> Vec<Completion> {
+    let mut completions = Vec::new();
+
+    for opcode in Opcode::iter() {
+        let (title, syntax, _) = opcode.documentation();
+        let name = syntax.split('{').next().unwrap_or_default();
+        let suffixes: &[&str] = if opcode.accepts_s() { &["", "S"] } else { &[""] };
+
+        for suffix in suffixes {
+            for condition in Condition::iter() {
+                let detail = match (condition, *suffix) {
+                    (Condition::AL, "") => title.to_string(),
+                    (Condition::AL, _) => format!("{title}, updating the flags"),
+                    (_, "") => format!("{title} if {}", condition.describe()),
+                    (_, _) => format!("{title} if {}, updating the flags", condition.describe()),
+                };
+
+                completions.push(Completion {
+                    label: format!("{name}{suffix}{condition}"),
+                    kind: CompletionKind::Mnemonic,
+                    detail,
+                    from,
+                    to,
+                });
+            }
+        }
+    }
+
+    completions
+}
+
+fn complete_register((from, to): (u32, u32)) -> Vec<Completion> {
+    let registers = (0..16).map(|i| (format!("R{i}"), match i {
+        13 => "Stack pointer".to_string(),
+        14 => "Link register".to_string(),
+        15 => "Program counter".to_string(),
+        _ => "General purpose register".to_string(),
+    }));
+    let aliases = (13..16).map(|i| (Register(i).to_string(), format!("Alias for R{i}")));
+
+    registers
+        .chain(aliases)
+        .map(|(label, detail)| Completion { label, kind: CompletionKind::Register, detail, from, to })
+        .collect()
+}
+
+fn complete_label(src: &str, (from, to): (u32, u32)) -> Vec<Completion> {
+    let mut labels = SymbolIndex::build(src)
+        .symbols
+        .into_iter()
+        .filter_map(|(label, symbol)| Some((label, symbol.definition?, symbol.addr?)))
+        .collect::<Vec<_>>();
+    labels.sort_by_key(|(_, definition, _)| definition.from);
+
+    labels
+        .into_iter()
+        .map(|(label, _, addr)| Completion {
+            label,
+            kind: CompletionKind::Label,
+            detail: format!("Label at {addr:#04x}"),
+            from,
+            to,
+        })
+        .collect()
+}
+
+fn complete_shift((from, to): (u32, u32)) -> Vec<Completion> {
+    [ShiftType::LogicalLeft, ShiftType::LogicalRight, ShiftType::ArithmeticRight, ShiftType::RotateRight]
+        .into_iter()
+        .map(|ty| Completion {
+            label: ty.to_string(),
+            kind: CompletionKind::Shift,
+            detail: "Shift the register operand".into(),
+            from,
+            to,
+        })
+        .collect()
+}
+
+impl Opcode {
+    fn operands(&self) -> &'static [OperandKind] {
+        match self {
+            Opcode::B | Opcode::Bl => &[OperandKind::Label],
+            Opcode::Svc => &[],
+            Opcode::Mov | Opcode::Mvn | Opcode::Tst | Opcode::Teq | Opcode::Cmp | Opcode::Cmn => &[OperandKind::Register, OperandKind::Operand2],
+            _ => &[OperandKind::Register, OperandKind::Register, OperandKind::Operand2],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{complete, CompletionKind};
+    use crate::utf16::{ToUtf16, Utf16Map};
+
+    #[test]
+    fn completes_by_position() {
+        let src = "loop:\n  add\n  bne lo\n  mov r1, r2, \n";
+
+        let mnemonics = complete(src, 11);
+        assert!(mnemonics.iter().any(|c| c.label == "ADDSGT"));
+        assert!(mnemonics.iter().all(|c| c.kind == CompletionKind::Mnemonic && (c.from, c.to) == (8, 11)));
+
+        let labels = complete(src, 20);
+        assert_eq!(labels.iter().map(|c| c.label.as_str()).collect::<Vec<_>>(), ["loop"]);
+        assert_eq!((labels[0].from, labels[0].to), (18, 20));
+
+        assert!(complete(src, 35).iter().any(|c| c.label == "LSL"));
+        assert!(complete(src, 28).iter().any(|c| c.label == "SP"));
+    }
+
+    #[test]
+    fn completes_after_non_ascii() {
+        let src = "; ≈\nloop:\n  bne lo";
+
+        let map = Utf16Map::new(src);
+
+        // The editor asks at the end of `lo`, which is 18 UTF-16 units in but 20 bytes
+        let labels = complete(src, map.byte(18)).to_utf16(&map);
+        assert_eq!(labels.iter().map(|c| (c.label.as_str(), c.from, c.to)).collect::<Vec<_>>(), [("loop", 16, 18)]);
+        assert!(complete(src, 3).is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+use crate::{machine::Machine, SupervisorCall};
+
+/// Services a program can ask for with `SVC #n`, passing values in R0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+pub enum Service {
+    /// Stops the program, with R0 as the exit code
+    Exit = 0,
+    /// Writes the bottom byte of R0
+    WriteChar = 1,
+    /// Reads a byte into R0, or -1 at the end of the input
+    ReadChar = 2,
+    /// Writes R0 as a signed decimal number
+    WriteInt = 3,
+    /// Reads a signed decimal number into R0, or 0 if the next word isn't one
+    ReadInt = 4,
+}
+
+impl SupervisorCall {
+    pub fn number(&self) -> u32 {
+        self.comment
+    }
+
+    pub fn service(&self) -> Option<Service> {
+        Service::from_u32(self.comment)
+    }
+}
+
+/// Console I/O for a [`Machine`], buffered so the host decides where it comes from and goes to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Console {
+    /// Input the program hasn't read yet
+    pub input: VecDeque<u8>,
+    /// No more input will arrive, so reads past the end stop waiting
+    pub closed: bool,
+    /// Output the host hasn't taken yet
+    pub output: Vec<u8>,
+    /// The last step tried to read input which hasn't arrived, and will try again when stepped
+    pub waiting: bool,
+}
+
+impl Console {
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// `None` if the read has to wait for more input.
+    fn read_char(&mut self) -> Option<u32> {
+        match self.input.pop_front() {
+            Some(byte) => Some(byte as u32),
+            None if self.closed => Some(u32::MAX),
+            None => None,
+        }
+    }
+
+    /// Whether a call to `service` would have to wait for more input.
+    pub(crate) fn would_wait(&self, service: Service) -> bool {
+        match service {
+            Service::ReadChar => !self.closed && self.input.is_empty(),
+            Service::ReadInt => !self.closed && !self.input.iter().skip_while(|byte| byte.is_ascii_whitespace()).any(|&byte| byte == b'\n'),
+            Service::Exit | Service::WriteChar | Service::WriteInt => false,
+        }
+    }
+
+    /// Numbers are read a line at a time, so a number split across two writes to the input is still read whole.
+    fn read_int(&mut self) -> Option<u32> {
+        while self.input.front().is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.input.pop_front();
+        }
+
+        if !self.closed && !self.input.contains(&b'\n') {
+            return None;
+        }
+
+        let len = self.input.iter().position(|byte| byte.is_ascii_whitespace()).unwrap_or(self.input.len());
+        let word = self.input.drain(..len).collect::<Vec<_>>();
+
+        Some(std::str::from_utf8(&word).ok().and_then(|word| word.parse::<i32>().ok()).unwrap_or(0) as u32)
+    }
+}
+
+impl Machine {
+    /// Performs a supervisor call the emulator has just executed.
+    pub(crate) fn supervisor_call(&mut self, call: SupervisorCall) -> Result<()> {
+        let service = match call.service() {
+            Some(service) => service,
+            None => bail!("Unknown supervisor call {}", call.number()),
+        };
+
+        self.console.waiting = false;
+        let r0 = self.registers[0];
+
+        match service {
+            Service::Exit => self.exit_code = Some(r0),
+            Service::WriteChar => self.console.output.push(r0 as u8),
+            Service::WriteInt => self.console.output.extend((r0 as i32).to_string().bytes()),
+            Service::ReadChar => self.read_into_r0(Console::read_char),
+            Service::ReadInt => self.read_into_r0(Console::read_int),
+        }
+
+        Ok(())
+    }
+
+    fn read_into_r0(&mut self, read: fn(&mut Console) -> Option<u32>) {
+        match read(&mut self.console) {
+            Some(value) => self.registers[0] = value,
+            // Run the call again next step, once there may be more input
+            None => {
+                self.registers[15] = self.registers[15].wrapping_sub(4);
+                self.console.waiting = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, machine::Machine};
+
+    #[test]
+    fn echoes_input() {
+        let src = "svc #4\nadd r0, r0, #1\nsvc #3\nmov r0, #33\nsvc #1\nsvc #2\nsvc #0";
+        let mut machine = Machine::default();
+        assemble(src).unwrap().serialise(&mut machine.ram);
+
+        machine.console.push_input(b"4");
+        machine.step().unwrap();
+        assert!(machine.console.waiting);
+        assert_eq!(machine.pc(), 0);
+
+        machine.console.push_input(b"1\n");
+        while machine.exit_code.is_none() {
+            machine.step().unwrap();
+        }
+
+        assert_eq!(machine.console.take_output(), b"42!");
+        assert_eq!(machine.exit_code, Some(b'\n' as u32));
+        assert!(machine.step().is_err());
+    }
+}
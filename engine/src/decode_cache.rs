@@ -0,0 +1,82 @@
+use anyhow::Result;
+
+use crate::Instruction;
+
+/// Instructions already decoded, by address, so loops don't walk the bits of the same words every time round.
+///
+/// Memory can be written through [`Machine::ram`](crate::machine::Machine::ram) without the cache knowing,
+/// so each entry keeps the word it was decoded from and is decoded again once the word changes.
+/// That keeps self-modifying code, debugger writes and reloaded programs working.
+#[derive(Debug, Clone, Default)]
+pub struct DecodeCache {
+    entries: Vec<Option<Entry>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    word: [u8; 4],
+    instruction: Instruction,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes `word`, which was fetched from `addr`.
+    pub fn decode(&mut self, addr: u32, word: [u8; 4]) -> Result<Instruction> {
+        if let Some(instruction) = self.get(addr, word) {
+            return Ok(instruction);
+        }
+
+        // Unaligned addresses would share entries with their neighbours, and only come from broken programs anyway
+        if !addr.is_multiple_of(4) {
+            return Instruction::deserialise(&word);
+        }
+
+        let index = addr as usize / 4;
+        let instruction = Instruction::deserialise(&word)?;
+
+        if index >= self.entries.len() {
+            self.entries.resize(index + 1, None);
+        }
+        self.entries[index] = Some(Entry { word, instruction });
+
+        Ok(instruction)
+    }
+
+    /// The instruction decoded from `word` at `addr`, if it's already cached.
+    pub fn get(&self, addr: u32, word: [u8; 4]) -> Option<Instruction> {
+        if !addr.is_multiple_of(4) {
+            return None;
+        }
+
+        self.entries.get(addr as usize / 4)?.filter(|entry| entry.word == word).map(|entry| entry.instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble_all, machine::Machine, Instruction};
+
+    #[test]
+    fn rereads_modified_code() {
+        let mut machine = Machine::default();
+        assemble_all("loop:\nadd r0, r0, #1\nb loop").serialise(&mut machine.ram);
+
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.registers[0], 2);
+
+        // Overwrite the add, as self-modifying code or a debugger would
+        assemble_all("add r0, r0, #10").serialise(&mut machine.ram[0..4]);
+        machine.step().unwrap();
+        assert_eq!(machine.registers[0], 12);
+
+        let word = machine.fetch(0).unwrap();
+        let cache = machine.decode_cache.as_mut().unwrap();
+        assert_eq!(cache.decode(0, word).unwrap(), Instruction::deserialise(&word).unwrap());
+        assert!(cache.decode(0, [0xff; 4]).is_err());
+    }
+}
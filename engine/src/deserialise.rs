@@ -14,6 +14,7 @@ use crate::InstructionBody;
 use crate::Register;
 use crate::Shift;
 use crate::ShiftType;
+use crate::SupervisorCall;
 
 impl Instruction {
     pub fn deserialise(src: &[u8; 4]) -> Result<Self> {
@@ -26,6 +27,7 @@ impl Instruction {
         let body = match bits[4..=5].load_be::<u8>() {
             0b00 => deserialise_data_processing(&mut InstructionReader::new(&bits[6..])).map(InstructionBody::DataProcessing),
             0b10 => deserialise_branch(&mut InstructionReader::new(&bits[7..])).map(InstructionBody::Branch),
+            0b11 if bits[6] && bits[7] => Ok(InstructionBody::SupervisorCall(SupervisorCall { comment: bits[8..].load_be::<u32>() })),
             _ => Err(anyhow!("Invalid Opcode"))
         }?;
 
@@ -0,0 +1,68 @@
+//! Checks that the faster ways of running a program end up in exactly the same state as the interpreter.
+
+use anyhow::Result;
+
+use crate::{assemble_all, machine::Machine, Flags, Instruction};
+
+/// A loop calling a subroutine, with console input and output, shifted operands and reads of the PC.
+const PROGRAM: &str = "
+        svc #4
+        mov r1, #0
+        mov r2, r0
+    loop:
+        add r1, r1, r2, lsl #1
+        subs r2, r2, #1
+        rsc r3, r1, r2, ror r2
+        bl check
+        bgt loop
+        mov r0, r1
+        add r0, r0, pc
+        svc #3
+        svc #0
+    check:
+        cmp r2, #0
+        mov pc, lr
+";
+
+/// Input for [`PROGRAM`], which runs the loop this many times.
+pub(crate) const PROGRAM_INPUT: &[u8] = b"30\n";
+
+/// [`PROGRAM`] assembled into 128 bytes of RAM.
+pub(crate) fn program_ram() -> Vec<u8> {
+    let mut ram = vec![0; 128];
+    assemble_all(PROGRAM).serialise(&mut ram);
+    ram
+}
+
+/// `program` in 128 bytes of RAM, with the PC in `registers` moved inside it so a random PC doesn't just fault straight away.
+pub(crate) fn random_program(program: &[Instruction], mut registers: [u32; 16]) -> (Vec<u8>, [u32; 16]) {
+    let mut ram = vec![0; 128];
+    for (dest, instruction) in ram.chunks_mut(4).zip(program) {
+        instruction.serialise(dest);
+    }
+
+    registers[15] %= program.len() as u32 * 4;
+    registers[15] &= !3;
+
+    (ram, registers)
+}
+
+/// Runs `ram` on the interpreter and with `run`, checking they end up in the same state.
+/// `run` is given a machine with the interpreter backend and the step budget, and returns how many steps it ran.
+pub(crate) fn differential(ram: &[u8], registers: [u32; 16], flags: u8, input: &[u8], max_steps: u64, run: impl FnOnce(&mut Machine, u64) -> Result<u64>) {
+    let [mut interpreted, mut compiled] = [(); 2].map(|_| {
+        let mut machine = Machine::new(ram.len());
+        machine.ram.copy_from_slice(ram);
+        machine.registers = registers;
+        machine.flags = Flags::from(flags);
+        machine.console.push_input(input);
+        machine.console.closed = true;
+        machine
+    });
+
+    let interpreted_steps = interpreted.run(max_steps).map_err(|e| e.to_string());
+    let compiled_steps = run(&mut compiled, max_steps).map_err(|e| e.to_string());
+
+    assert_eq!(interpreted_steps, compiled_steps);
+    assert_eq!(interpreted, compiled);
+}
@@ -0,0 +1,182 @@
+use std::{collections::HashMap, fmt::{self, Display, Formatter}, ops::Range};
+
+use serde::Serialize;
+
+use crate::{
+    emulator::expand_immediate, Branch, Condition, DataProcessing, DataProcessingOpcode, DataProcessingOperand, Instruction, InstructionBody, Register, Shift, ShiftAmount, ShiftType
+};
+
+/// Address to label name, used to show branch targets symbolically.
+pub type SymbolTable = HashMap<u32, String>;
+
+#[derive(Debug, Serialize)]
+pub struct DisassembledWord {
+    pub addr: u32,
+    pub word: u32,
+    /// `None` if the word does not decode to an instruction
+    pub text: Option<String>,
+}
+
+/// Decodes every aligned word in `range`, resolving branch targets through `symbols`.
+pub fn disassemble(ram: &[u8], range: Range<u32>, symbols: &SymbolTable) -> Vec<DisassembledWord> {
+    let start = range.start.next_multiple_of(4);
+    let end = range.end.min(ram.len() as u32);
+
+    (start..end)
+        .step_by(4)
+        .filter_map(|addr| {
+            let bytes: [u8; 4] = ram.get(addr as usize..addr as usize + 4)?.try_into().ok()?;
+            let text = Instruction::deserialise(&bytes)
+                .ok()
+                .map(|instruction| instruction.to_asm(addr, symbols));
+
+            Some(DisassembledWord { addr, word: u32::from_be_bytes(bytes), text })
+        })
+        .collect()
+}
+
+impl Instruction {
+    /// Like the [`Display`] form, but branch targets are resolved relative to `addr` and shown as labels where known.
+    pub fn to_asm(&self, addr: u32, symbols: &SymbolTable) -> String {
+        match &self.body {
+            InstructionBody::Branch(branch) => match symbols.get(&branch.target(addr)) {
+                Some(label) => format!("{} {label}", branch.mnemonic(self.condition)),
+                None => self.to_string(),
+            },
+            InstructionBody::DataProcessing(_) | InstructionBody::SupervisorCall(_) => self.to_string(),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.body {
+            InstructionBody::DataProcessing(dp) => write_data_processing(f, dp, self.condition),
+            InstructionBody::Branch(branch) => write!(f, "{} #{}", branch.mnemonic(self.condition), branch.signed_offset()),
+            InstructionBody::SupervisorCall(call) => write!(f, "SVC{} #{}", self.condition, call.comment),
+        }
+    }
+}
+
+fn write_data_processing(f: &mut Formatter<'_>, dp: &DataProcessing, condition: Condition) -> fmt::Result {
+    write!(f, "{}", dp.opcode)?;
+    if dp.set_condition_codes && !dp.opcode.is_comparison() {
+        f.write_str("S")?;
+    }
+    write!(f, "{condition} ")?;
+
+    match dp.opcode {
+        DataProcessingOpcode::MOV | DataProcessingOpcode::MVN => write!(f, "{}, {}", dp.dest, dp.operand),
+        DataProcessingOpcode::TST
+        | DataProcessingOpcode::TEQ
+        | DataProcessingOpcode::CMP
+        | DataProcessingOpcode::CMN => write!(f, "{}, {}", dp.register, dp.operand),
+        _ => write!(f, "{}, {}, {}", dp.dest, dp.register, dp.operand),
+    }
+}
+
+impl Branch {
+    /// The offset field sign extended from 24 bits, in words.
+    pub fn signed_offset(&self) -> i32 {
+        ((self.offset << 8) as i32) >> 8
+    }
+
+    /// Address of the instruction this branch jumps to when executed from `addr`.
+    /// The offset is relative to the PC, which reads two instructions ahead.
+    pub fn target(&self, addr: u32) -> u32 {
+        addr.wrapping_add(8).wrapping_add_signed(self.signed_offset() * 4)
+    }
+
+    fn mnemonic(&self, condition: Condition) -> String {
+        format!("{}{condition}", if self.link { "BL" } else { "B" })
+    }
+}
+
+impl DataProcessingOpcode {
+    /// Comparisons always set the flags, so they never carry an `S` suffix.
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, Self::TST | Self::TEQ | Self::CMP | Self::CMN)
+    }
+}
+
+impl Display for DataProcessingOpcode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl Display for Condition {
+    /// `AL` is implied, so it is displayed as nothing.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::AL => Ok(()),
+            _ => f.write_str(&self.as_str().to_ascii_uppercase()),
+        }
+    }
+}
+
+impl Display for Register {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            13 => f.write_str("SP"),
+            14 => f.write_str("LR"),
+            15 => f.write_str("PC"),
+            n => write!(f, "R{n}"),
+        }
+    }
+}
+
+impl Display for DataProcessingOperand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            DataProcessingOperand::Immediate { rotate, value } => write!(f, "#{}", expand_immediate(rotate, value)),
+            DataProcessingOperand::Register { shift, register } => {
+                write!(f, "{register}")?;
+                if shift != Shift::default() {
+                    write!(f, ", {shift}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Display for Shift {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.ty)?;
+        match self.amount {
+            ShiftAmount::Immediate(amount) => write!(f, "#{amount}"),
+            ShiftAmount::Register(register) => write!(f, "{register}"),
+        }
+    }
+}
+
+impl Display for ShiftType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ShiftType::LogicalLeft => "LSL",
+            ShiftType::LogicalRight => "LSR",
+            ShiftType::ArithmeticRight => "ASR",
+            ShiftType::RotateRight => "ROR",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::{assemble, symbol_table}, disassembler::disassemble};
+
+    #[test]
+    fn disassemble_program() {
+        let src = "start:\nmov R1, #12\naddeq r2, r1, lr\nbl start";
+        let mut ram = [0u8; 12];
+        assemble(src).unwrap().serialise(&mut ram);
+
+        let text = disassemble(&ram, 0..12, &symbol_table(src))
+            .into_iter()
+            .map(|word| word.text.unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(text, ["MOV R1, #12", "ADDEQ R2, R1, LR", "BL start"]);
+    }
+}
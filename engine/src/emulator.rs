@@ -1,17 +1,30 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 
-use crate::{Branch, Condition, DataProcessing, DataProcessingOpcode, Flags, Instruction, ProcessorState, Register, Shift};
+use crate::{Branch, Condition, DataProcessing, DataProcessingOpcode, DataProcessingOperand, Flags, Instruction, ProcessorState, Register, Shift, SupervisorCall};
 
 impl<'a> ProcessorState<'a> {
-    pub fn step(&mut self) -> Result<()> {
+    /// Executes the instruction at the PC.
+    /// Supervisor calls are returned rather than performed, as only the host knows how to service them.
+    pub fn step(&mut self) -> Result<Option<SupervisorCall>> {
+        let instruction = Instruction::deserialise(&self.fetch()?)?;
+        self.execute(instruction)
+    }
+
+    /// The word at the PC.
+    pub fn fetch(&self) -> Result<[u8; 4]> {
         let start_instruction = self.get_pc() as usize;
         let end_instruction = start_instruction + 4;
-        let instruction = &self.ram[start_instruction..end_instruction].try_into()?;
-        let instruction = Instruction::deserialise(instruction)?;
 
+        Ok(self.ram.get(start_instruction..end_instruction)
+            .context("PC is outside of memory")?
+            .try_into()?)
+    }
+
+    /// Executes `instruction` as though it had been fetched from the PC, for callers which have already decoded it.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<Option<SupervisorCall>> {
         if !instruction.condition.matches(self.flags) {
             self.inc_pc();
-            return Ok(());
+            return Ok(None);
         }
 
         self.inc_pc();
@@ -19,9 +32,10 @@ impl<'a> ProcessorState<'a> {
         match instruction.body {
             crate::InstructionBody::DataProcessing(data_processing) => self.execute_data_processing(data_processing),
             crate::InstructionBody::Branch(branch) => self.execute_branch(branch),
+            crate::InstructionBody::SupervisorCall(call) => return Ok(Some(call)),
         }?;
 
-        Ok(())
+        Ok(None)
     }
 
     fn inc_pc(&mut self) {
@@ -29,118 +43,39 @@ impl<'a> ProcessorState<'a> {
     }
 
     fn execute_branch(&mut self, instruction: Branch) -> Result<()> {
-        let offset = (((instruction.offset << 2) as i32) << 6) >> 6;
+        let addr = self.get_pc().wrapping_sub(4);
 
         if instruction.link {
             self.registers[14] = self.get_pc();
         }
 
-        self.registers[15] = self.registers[15].wrapping_add_signed(offset);
+        self.registers[15] = instruction.target(addr);
 
         Ok(())
     }
 
     fn execute_data_processing(&mut self, instruction: DataProcessing) -> Result<()> {
-        let rhs = match instruction.operand {
-            crate::DataProcessingOperand::Immediate { rotate, value } => (value as u32).rotate_left(rotate as u32),
-            crate::DataProcessingOperand::Register { shift, register } => shift.eval(self.registers[register.0 as usize]),
-        };
+        let registers = operand_registers(self.registers, self.get_pc().wrapping_sub(4));
+        let rhs = instruction.operand.eval(&registers);
 
-        let lhs = self.get_register(instruction.register)?;
-        let mut flags = Flags::default();
-        let old_flags = self.flags;
-        let dest = self.get_register_mut(instruction.dest)?;
-
-        // TODO: Implement overflow flag
-        match instruction.opcode {
-            DataProcessingOpcode::AND => *dest = lhs & rhs,
-            DataProcessingOpcode::EOR => *dest = lhs ^ rhs,
-            DataProcessingOpcode::SUB => {
-                *dest = lhs.wrapping_sub(rhs);
-                flags.c = *dest < lhs;
-            },
-            DataProcessingOpcode::RSB => {
-                *dest = rhs.wrapping_sub(lhs);
-                flags.c = *dest < rhs
-            },
-            DataProcessingOpcode::ADD => {
-                *dest = lhs.wrapping_add(rhs);
-                flags.c = *dest < lhs;
-            },
-            DataProcessingOpcode::ADC => {
-                *dest = lhs.wrapping_add(rhs).wrapping_add(old_flags.c as u32);
-                flags.c = *dest < lhs;
-            },
-            DataProcessingOpcode::SBC => {
-                *dest = lhs.wrapping_sub(rhs).wrapping_add(old_flags.c as u32).wrapping_sub(1);
-                flags.c = *dest < lhs;
-            },
-            DataProcessingOpcode::RSC => {
-                *dest = rhs.wrapping_sub(lhs).wrapping_add(old_flags.c as u32).wrapping_sub(1);
-                flags.c = *dest < lhs;
-            },
-            DataProcessingOpcode::TST => {
-                let dummy = lhs & rhs;
-                flags.z = dummy == 0;
-                flags.n = (dummy >> 31) & 1 == 1; 
-
-                self.flags = flags;
-                
-                return Ok(());
-            },
-            DataProcessingOpcode::TEQ => {
-                let dummy = lhs ^ rhs;
-                flags.z = dummy == 0;
-                flags.n = (dummy >> 31) & 1 == 1; 
-                
-                self.flags = flags;
-
-                return Ok(());
-            },
-            DataProcessingOpcode::CMP => {
-                let dummy = lhs.wrapping_sub(rhs);
-                flags.c = dummy < lhs;
-                flags.z = dummy == 0;
-                flags.n = (dummy >> 31) & 1 == 1; 
-                flags.v = true;
-
-                self.flags = flags;
-
-                return Ok(());
-            },
-            DataProcessingOpcode::CMN => {
-                let dummy = lhs.wrapping_add(rhs);
-                flags.c = dummy > lhs;
-                flags.z = dummy == 0;
-                flags.n = (dummy >> 31) & 1 == 1; 
-
-                self.flags = flags;
-
-                return Ok(());
-            },
-            DataProcessingOpcode::ORR => *dest = lhs | rhs,
-            DataProcessingOpcode::MOV => *dest = rhs,
-            DataProcessingOpcode::BIC => *dest = lhs & !rhs,
-            DataProcessingOpcode::MVN => *dest = !rhs,
-        };
+        let lhs = registers[instruction.register.0 as usize];
+        let (result, flags) = alu(instruction.opcode, lhs, rhs, self.flags);
+
+        if !instruction.opcode.is_comparison() {
+            *self.get_register_mut(instruction.dest)? = result;
+        }
 
-        flags.z = *dest == 0;
-        flags.n = (*dest >> 31) & 1 == 1; 
-        
-        self.flags = flags;
+        // Only instructions with the S suffix update the flags, which comparisons are always assembled with
+        if instruction.set_condition_codes {
+            self.flags = flags;
+        }
 
         Ok(())
     }
 
     fn get_register_mut(&mut self, reg: Register) -> Result<&mut u32> {
         self.registers.get_mut(reg.0 as usize)
-            .ok_or(anyhow!("Invalid Register index"))
-    }
-
-    fn get_register(&self, reg: Register) -> Result<u32> {
-        self.registers.get(reg.0 as usize)
-            .copied()
-            .ok_or(anyhow!("Invalid Register index"))
+            .ok_or_else(|| anyhow!("Invalid Register index"))
     }
 
     fn get_pc(&self) -> u32 {
@@ -148,24 +83,87 @@ impl<'a> ProcessorState<'a> {
     }
 }
 
+/// The registers as the instruction at `addr` reads them, with the PC two instructions ahead as on ARM.
+pub(crate) fn operand_registers(registers: &[u32; 16], addr: u32) -> [u32; 16] {
+    let mut registers = *registers;
+    registers[15] = addr.wrapping_add(8);
+    registers
+}
+
+/// The result of a data processing operation, and the flags it leaves.
+pub(crate) fn alu(opcode: DataProcessingOpcode, lhs: u32, rhs: u32, flags: Flags) -> (u32, Flags) {
+    let carry = flags.c;
+
+    // Logical operations leave the carry and overflow flags alone
+    let (result, c, v) = match opcode {
+        DataProcessingOpcode::AND | DataProcessingOpcode::TST => (lhs & rhs, flags.c, flags.v),
+        DataProcessingOpcode::EOR | DataProcessingOpcode::TEQ => (lhs ^ rhs, flags.c, flags.v),
+        DataProcessingOpcode::ORR => (lhs | rhs, flags.c, flags.v),
+        DataProcessingOpcode::MOV => (rhs, flags.c, flags.v),
+        DataProcessingOpcode::BIC => (lhs & !rhs, flags.c, flags.v),
+        DataProcessingOpcode::MVN => (!rhs, flags.c, flags.v),
+        DataProcessingOpcode::ADD | DataProcessingOpcode::CMN => add_with_carry(lhs, rhs, false),
+        DataProcessingOpcode::ADC => add_with_carry(lhs, rhs, carry),
+        DataProcessingOpcode::SUB | DataProcessingOpcode::CMP => add_with_carry(lhs, !rhs, true),
+        DataProcessingOpcode::SBC => add_with_carry(lhs, !rhs, carry),
+        DataProcessingOpcode::RSB => add_with_carry(rhs, !lhs, true),
+        DataProcessingOpcode::RSC => add_with_carry(rhs, !lhs, carry),
+    };
+
+    let flags = Flags {
+        n: (result >> 31) & 1 == 1,
+        z: result == 0,
+        c,
+        v,
+    };
+
+    (result, flags)
+}
+
+/// Adds with a carry in, returning the result along with the carry and signed overflow out.
+/// Subtraction is `a + !b + 1`, so the carry is set when no borrow occurs.
+fn add_with_carry(a: u32, b: u32, carry_in: bool) -> (u32, bool, bool) {
+    let unsigned = a as u64 + b as u64 + carry_in as u64;
+    let signed = a as i32 as i64 + b as i32 as i64 + carry_in as i64;
+    let result = unsigned as u32;
+
+    (result, unsigned > u32::MAX as u64, signed != result as i32 as i64)
+}
+
+/// An 8 bit immediate rotated right by twice the 4 bit rotate field.
+pub(crate) fn expand_immediate(rotate: u8, value: u8) -> u32 {
+    (value as u32).rotate_right(rotate as u32 * 2)
+}
+
+impl DataProcessingOperand {
+    /// The value of the operand, given the registers as they read while the instruction executes.
+    pub(crate) fn eval(&self, registers: &[u32; 16]) -> u32 {
+        match *self {
+            DataProcessingOperand::Immediate { rotate, value } => expand_immediate(rotate, value),
+            DataProcessingOperand::Register { shift, register } => shift.eval(registers[register.0 as usize], registers),
+        }
+    }
+}
+
 impl Shift {
-    fn eval(&self, input: u32) -> u32 {
+    pub(crate) fn eval(&self, input: u32, registers: &[u32; 16]) -> u32 {
         let shift = match self.amount {
             crate::ShiftAmount::Immediate(val) => val as u32,
-            crate::ShiftAmount::Register(register) => register.0 as u32,
+            // Only the bottom byte of the register is used
+            crate::ShiftAmount::Register(register) => registers[register.0 as usize] & 0xFF,
         };
 
         match self.ty {
-            crate::ShiftType::LogicalLeft => input >> shift,
-            crate::ShiftType::LogicalRight => input << shift,
-            crate::ShiftType::ArithmeticRight => ((input as i32) << shift) as u32, // TODO: Confirm if this is correct
+            crate::ShiftType::LogicalLeft => input.checked_shl(shift).unwrap_or(0),
+            crate::ShiftType::LogicalRight => input.checked_shr(shift).unwrap_or(0),
+            crate::ShiftType::ArithmeticRight => ((input as i32) >> shift.min(31)) as u32,
             crate::ShiftType::RotateRight => input.rotate_right(shift),
         }
     }
 }
 
 impl Condition {
-    fn matches(&self, flags: Flags) -> bool {
+    pub(crate) fn matches(&self, flags: Flags) -> bool {
         match self {
             Condition::EQ => flags.z,
             Condition::NE => !flags.z,
@@ -176,7 +174,7 @@ impl Condition {
             Condition::VS => flags.v,
             Condition::VC => !flags.v,
             Condition::HI => flags.c && !flags.z,
-            Condition::LS => !flags.c && flags.z,
+            Condition::LS => !flags.c || flags.z,
             Condition::GE => flags.n == flags.v,
             Condition::LT => flags.n != flags.v,
             Condition::GT => !flags.z && (flags.n == flags.v),
@@ -184,4 +182,133 @@ impl Condition {
             Condition::AL => true,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, machine::Machine};
+
+    fn load(src: &str) -> Machine {
+        let mut machine = Machine::default();
+        assemble(src).unwrap().serialise(&mut machine.ram);
+        machine
+    }
+
+    /// A machine with already encoded instructions loaded from address 0.
+    fn load_words(words: &[u32]) -> Machine {
+        let mut machine = Machine::default();
+        for (dest, word) in machine.ram.chunks_mut(4).zip(words) {
+            dest.copy_from_slice(&word.to_be_bytes());
+        }
+        machine
+    }
+
+    #[test]
+    fn branches_relative_to_pc_plus_8() {
+        let mut machine = load("loop:\nb loop\nback:\nbl back");
+        assert_eq!(machine.ram[..8], [0xEA, 0xFF, 0xFF, 0xFE, 0xEB, 0xFF, 0xFF, 0xFE]);
+
+        machine.step().unwrap();
+        assert_eq!(machine.registers[15], 0);
+
+        machine.registers[15] = 4;
+        machine.step().unwrap();
+        assert_eq!((machine.registers[14], machine.registers[15]), (8, 4));
+
+        // A literal offset counts in instructions from PC+8 as well
+        let mut machine = load("b #1");
+        machine.step().unwrap();
+        assert_eq!(machine.registers[15], 12);
+    }
+
+    #[test]
+    fn pc_reads_as_address_plus_8() {
+        let mut machine = load("mov r0, pc\nadd pc, pc, #0\nmov r1, #1\nadd r2, r0, pc, lsl #1");
+        machine.step().unwrap();
+        assert_eq!(machine.registers[0], 8);
+
+        // Skips the next instruction
+        machine.step().unwrap();
+        assert_eq!(machine.pc(), 12);
+
+        machine.step().unwrap();
+        assert_eq!((machine.registers[1], machine.registers[2], machine.pc()), (0, 8 + 40, 16));
+    }
+
+    #[test]
+    fn shifts_register_operands() {
+        // MOV R3, R1, LSL #2; MOV R4, R5, LSR R2; MOV R6, R7, ASR #1; MOV R8, R9, LSL R10
+        let mut machine = load_words(&[0xE1A03101, 0xE1A04235, 0xE1A060C7, 0xE1A08A19]);
+        machine.registers[1] = 3;
+        machine.registers[5] = 0x80;
+        machine.registers[2] = 4;
+        machine.registers[7] = 0x8000_0000;
+        machine.registers[9] = 1;
+        // Only the bottom byte counts, so this shifts by 32
+        machine.registers[10] = 0x120;
+
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+
+        assert_eq!([machine.registers[3], machine.registers[4], machine.registers[6], machine.registers[8]], [12, 8, 0xC000_0000, 0]);
+    }
+
+    /// Runs every line of `src` with R0 and R1 set, returning R2 and the flags.
+    fn run_alu(src: &str, r0: u32, r1: u32) -> (u32, String) {
+        let mut machine = load(src);
+        machine.registers[0] = r0;
+        machine.registers[1] = r1;
+
+        for _ in src.lines() {
+            machine.step().unwrap();
+        }
+
+        (machine.registers[2], machine.flags.to_string())
+    }
+
+    #[test]
+    fn computes_carry_and_overflow() {
+        assert_eq!(run_alu("cmp r0, r1", 5, 1), (0, "nzCv".into()));
+        assert_eq!(run_alu("cmp r0, r1", 1, 2), (0, "Nzcv".into()));
+        assert_eq!(run_alu("cmp r0, r1", 0x8000_0000, 1), (0, "nzCV".into()));
+        assert_eq!(run_alu("cmn r0, r1", u32::MAX, 1), (0, "nZCv".into()));
+        assert_eq!(run_alu("adds r2, r0, r1", u32::MAX, 1), (0, "nZCv".into()));
+        assert_eq!(run_alu("adds r2, r0, r1", i32::MAX as u32, 1), (0x8000_0000, "NzcV".into()));
+        assert_eq!(run_alu("rsbs r2, r0, r1", 1, 2), (1, "nzCv".into()));
+
+        // The carry in comes from the previous instruction
+        assert_eq!(run_alu("adds r2, r0, r0\nadcs r2, r1, r1", u32::MAX, 1), (3, "nzcv".into()));
+        assert_eq!(run_alu("cmp r0, r1\nsbcs r2, r0, r1", 1, 2), (0xFFFF_FFFE, "Nzcv".into()));
+
+        // Logical operations keep C and V
+        assert_eq!(run_alu("cmp r0, r1\nands r2, r0, r1", 0x8000_0000, 1), (0, "nZCV".into()));
+    }
+
+    #[test]
+    fn only_s_instructions_set_flags() {
+        let src = "
+            cmp r0, #0
+            mov r1, #1
+            beq zero
+            mov r2, #1
+        zero:
+            add r3, r1, #1
+            adds r4, r1, #1
+        ";
+        let mut machine = load(src);
+
+        // The MOV in between mustn't clear Z, so the branch is still taken
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.pc(), 16);
+        assert_eq!(machine.flags.to_string(), "nZCv");
+
+        machine.step().unwrap();
+        assert_eq!(machine.flags.to_string(), "nZCv");
+        machine.step().unwrap();
+        assert_eq!(machine.flags.to_string(), "nzcv");
+        assert_eq!(machine.registers[2..5], [0, 2, 2]);
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::{Context, Result};
+
+use crate::{
+    console::Service, disassembler::SymbolTable, emulator::{alu, expand_immediate, operand_registers}, machine::{CpuState, Machine}, Branch, Condition, DataProcessing, DataProcessingOpcode, DataProcessingOperand, Flags, Instruction, InstructionBody, ShiftAmount, ShiftType, SupervisorCall
+};
+
+impl Machine {
+    /// Explains in plain English what the instruction at the PC is about to do.
+    pub fn explain(&self, symbols: &SymbolTable) -> Result<String> {
+        let addr = self.pc();
+        let word = self.fetch(addr).context("PC is outside of memory")?;
+        let instruction = Instruction::deserialise(&word)?;
+        let before = self.cpu_state();
+
+        Ok(explain(&instruction, &self.ram, &before, &state_after(&instruction, &before), symbols))
+    }
+}
+
+/// The registers and flags once `instruction` runs from the PC of `before`, as far as an explanation needs them.
+/// Only data processing results are described, so branches and supervisor calls leave the state as it was.
+fn state_after(instruction: &Instruction, before: &CpuState) -> CpuState {
+    let mut after = *before;
+    let InstructionBody::DataProcessing(dp) = instruction.body else {
+        return after;
+    };
+    if !instruction.condition.matches(before.flags) {
+        return after;
+    }
+
+    let reads = operand_registers(&before.registers, before.registers[15]);
+    let (result, flags) = alu(dp.opcode, reads[dp.register.0 as usize], dp.operand.eval(&reads), before.flags);
+
+    if !dp.opcode.is_comparison() {
+        after.registers[dp.dest.0 as usize] = result;
+    }
+    if dp.set_condition_codes {
+        after.flags = flags;
+    }
+
+    after
+}
+
+/// Describes `instruction` executing at the PC of `before`, using `after` for the results.
+pub fn explain(instruction: &Instruction, ram: &[u8], before: &CpuState, after: &CpuState, symbols: &SymbolTable) -> String {
+    let addr = before.registers[15];
+    let passes = instruction.condition.matches(before.flags);
+
+    let action = match &instruction.body {
+        InstructionBody::DataProcessing(dp) => explain_data_processing(dp, ram, before, after, passes),
+        InstructionBody::Branch(branch) => explain_branch(branch, addr, symbols),
+        InstructionBody::SupervisorCall(call) => explain_supervisor_call(call, before),
+    };
+
+    if instruction.condition == Condition::AL {
+        return format!("{action}.");
+    }
+
+    let outcome = match (&instruction.body, passes) {
+        (InstructionBody::Branch(_), true) => "the branch will be taken",
+        (InstructionBody::Branch(_), false) => "the branch will not be taken",
+        (_, true) => "this will run",
+        (_, false) => "this will be skipped",
+    };
+
+    format!(
+        "If {}, {}. {}, so {outcome}.",
+        instruction.condition.describe(),
+        lowercase_first(&action),
+        relevant_flags(instruction.condition, before.flags),
+    )
+}
+
+fn explain_branch(branch: &Branch, addr: u32, symbols: &SymbolTable) -> String {
+    let target = branch.target(addr);
+    let target = symbols.get(&target).cloned().unwrap_or_else(|| format!("address {target:#x}"));
+
+    if branch.link {
+        format!("Branch to {target}, saving the return address {:#x} in LR", addr.wrapping_add(4))
+    } else {
+        format!("Branch to {target}")
+    }
+}
+
+fn explain_supervisor_call(call: &SupervisorCall, before: &CpuState) -> String {
+    let r0 = before.registers[0];
+
+    match call.service() {
+        Some(Service::Exit) => format!("Exit the program with code {r0} from R0"),
+        Some(Service::WriteChar) => format!("Write the character {:?} in R0 to the console", r0 as u8 as char),
+        Some(Service::ReadChar) => "Read a character from the console into R0".into(),
+        Some(Service::WriteInt) => format!("Write R0 ({}) to the console", r0 as i32),
+        Some(Service::ReadInt) => "Read a number from the console into R0".into(),
+        None => format!("Make supervisor call {}, which isn't a known service", call.number()),
+    }
+}
+
+fn explain_data_processing(dp: &DataProcessing, ram: &[u8], before: &CpuState, after: &CpuState, passes: bool) -> String {
+    let reads = CpuState { registers: operand_registers(&before.registers, before.registers[15]), flags: before.flags };
+    let lhs = reads.registers[dp.register.0 as usize];
+    let rhs = dp.operand.eval(&reads.registers);
+    let op = describe_operand(&dp.operand, &reads);
+    let rn = format!("{} ({})", dp.register, lhs as i32);
+    let rd = dp.dest;
+    let carry = before.flags.c as u32;
+
+    // The result is only known if the instruction actually runs
+    let result = after.registers[dp.dest.0 as usize];
+    let show = |calculation: String| if passes { format!(": {calculation}") } else { String::new() };
+
+    match dp.opcode {
+        DataProcessingOpcode::MOV => format!("Copy {op} into {rd}"),
+        DataProcessingOpcode::MVN => format!("Copy the bitwise NOT of {op} into {rd}{}", show(format!("{result:#x}"))),
+        DataProcessingOpcode::ADD => format!("Add {op} to {rn} and store the result in {rd}{}",
+            show(format!("{} + {} = {}", lhs as i32, rhs as i32, result as i32))),
+        DataProcessingOpcode::ADC => format!("Add {op} and the carry to {rn} and store the result in {rd}{}",
+            show(format!("{} + {} + {carry} = {}", lhs as i32, rhs as i32, result as i32))),
+        DataProcessingOpcode::SUB => format!("Subtract {op} from {rn} and store the result in {rd}{}",
+            show(format!("{} - {} = {}", lhs as i32, rhs as i32, result as i32))),
+        DataProcessingOpcode::SBC => format!("Subtract {op} and the borrow from {rn} and store the result in {rd}{}",
+            show(format!("{} - {} - {} = {}", lhs as i32, rhs as i32, 1 - carry, result as i32))),
+        DataProcessingOpcode::RSB => format!("Subtract {rn} from {op} and store the result in {rd}{}",
+            show(format!("{} - {} = {}", rhs as i32, lhs as i32, result as i32))),
+        DataProcessingOpcode::RSC => format!("Subtract {rn} and the borrow from {op} and store the result in {rd}{}",
+            show(format!("{} - {} - {} = {}", rhs as i32, lhs as i32, 1 - carry, result as i32))),
+        DataProcessingOpcode::AND => format!("Bitwise AND {rn} with {op} and store the result in {rd}{}", show(format!("{result:#x}"))),
+        DataProcessingOpcode::EOR => format!("Bitwise exclusive OR {rn} with {op} and store the result in {rd}{}", show(format!("{result:#x}"))),
+        DataProcessingOpcode::ORR => format!("Bitwise OR {rn} with {op} and store the result in {rd}{}", show(format!("{result:#x}"))),
+        DataProcessingOpcode::BIC => format!("Clear the bits of {rn} that are set in {op} and store the result in {rd}{}", show(format!("{result:#x}"))),
+        DataProcessingOpcode::CMP => format!("Compare {} with {op}{}", dp.register,
+            show(format!("{rn} - {} = {}, so {}", rhs as i32, lhs.wrapping_sub(rhs) as i32, flag_values(after.flags, true))))
+            + &next_instruction_outcome(ram, before, after, passes),
+        DataProcessingOpcode::CMN => format!("Compare {} with the negative of {op}{}", dp.register,
+            show(format!("{rn} + {} = {}, so {}", rhs as i32, lhs.wrapping_add(rhs) as i32, flag_values(after.flags, true))))
+            + &next_instruction_outcome(ram, before, after, passes),
+        DataProcessingOpcode::TST => format!("Test the bits of {} against {op}{}", dp.register,
+            show(format!("{lhs:#x} AND {rhs:#x} = {:#x}, so {}", lhs & rhs, flag_values(after.flags, false))))
+            + &next_instruction_outcome(ram, before, after, passes),
+        DataProcessingOpcode::TEQ => format!("Test whether {} equals {op}{}", dp.register,
+            show(format!("{lhs:#x} EOR {rhs:#x} = {:#x}, so {}", lhs ^ rhs, flag_values(after.flags, false))))
+            + &next_instruction_outcome(ram, before, after, passes),
+    }
+}
+
+/// Comparisons are almost always followed by a conditional instruction, so say what it will do.
+fn next_instruction_outcome(ram: &[u8], before: &CpuState, after: &CpuState, passes: bool) -> String {
+    if !passes {
+        return String::new();
+    }
+
+    let next = before.registers[15].wrapping_add(4) as usize;
+    let word = ram.get(next..next + 4).and_then(|word| word.try_into().ok());
+    let instruction = match word.map(|word: [u8; 4]| Instruction::deserialise(&word)) {
+        Some(Ok(instruction)) if instruction.condition != Condition::AL => instruction,
+        _ => return String::new(),
+    };
+
+    let mnemonic = instruction.to_string().split_whitespace().next().unwrap_or_default().to_string();
+    let outcome = match (instruction.body, instruction.condition.matches(after.flags)) {
+        (InstructionBody::Branch(_), true) => "will be taken",
+        (InstructionBody::Branch(_), false) => "will not be taken",
+        (_, true) => "will run",
+        (_, false) => "will be skipped",
+    };
+
+    format!("; the {mnemonic} {outcome}")
+}
+
+fn describe_operand(operand: &DataProcessingOperand, machine: &CpuState) -> String {
+    match *operand {
+        DataProcessingOperand::Immediate { rotate, value } => format!("{}", expand_immediate(rotate, value) as i32),
+        DataProcessingOperand::Register { shift, register } => {
+            let value = machine.registers[register.0 as usize];
+            if shift == Default::default() {
+                return format!("{register} ({})", value as i32);
+            }
+
+            let direction = match shift.ty {
+                ShiftType::LogicalLeft => "shifted left",
+                ShiftType::LogicalRight => "shifted right",
+                ShiftType::ArithmeticRight => "arithmetically shifted right",
+                ShiftType::RotateRight => "rotated right",
+            };
+            let amount = match shift.amount {
+                ShiftAmount::Immediate(amount) => amount.to_string(),
+                ShiftAmount::Register(amount) => format!("{amount} ({})", machine.registers[amount.0 as usize] & 0xFF),
+            };
+
+            format!("{register} ({}) {direction} by {amount} ({})", value as i32, operand.eval(&machine.registers) as i32)
+        }
+    }
+}
+
+/// Comparisons by subtraction or addition also set C and V, which the signed and unsigned conditions depend on.
+fn flag_values(flags: Flags, arithmetic: bool) -> String {
+    let mut values = format!("N={}, Z={}", flags.n as u8, flags.z as u8);
+    if arithmetic {
+        values += &format!(", C={}, V={}", flags.c as u8, flags.v as u8);
+    }
+    values
+}
+
+fn relevant_flags(condition: Condition, flags: Flags) -> String {
+    let names: &[(&str, bool)] = match condition {
+        Condition::EQ | Condition::NE => &[("Z", flags.z)],
+        Condition::CS | Condition::CC => &[("C", flags.c)],
+        Condition::MI | Condition::PL => &[("N", flags.n)],
+        Condition::VS | Condition::VC => &[("V", flags.v)],
+        Condition::HI | Condition::LS => &[("C", flags.c), ("Z", flags.z)],
+        Condition::GE | Condition::LT => &[("N", flags.n), ("V", flags.v)],
+        Condition::GT | Condition::LE => &[("Z", flags.z), ("N", flags.n), ("V", flags.v)],
+        Condition::AL => &[],
+    };
+
+    names
+        .iter()
+        .map(|(name, set)| format!("{name}={}", *set as u8))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn lowercase_first(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl Condition {
+    pub(crate) fn describe(&self) -> &'static str {
+        match self {
+            Condition::EQ => "equal",
+            Condition::NE => "not equal",
+            Condition::CS => "the carry is set",
+            Condition::CC => "the carry is clear",
+            Condition::MI => "negative",
+            Condition::PL => "positive or zero",
+            Condition::VS => "overflow occurred",
+            Condition::VC => "no overflow occurred",
+            Condition::HI => "higher (unsigned)",
+            Condition::LS => "lower or the same (unsigned)",
+            Condition::GE => "greater than or equal",
+            Condition::LT => "less than",
+            Condition::GT => "greater than",
+            Condition::LE => "less than or equal",
+            Condition::AL => "always",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::{assemble, symbol_table}, machine::Machine};
+
+    #[test]
+    fn explains_compare_and_branch() {
+        let src = "cmp r1, #10\nbgt done\nmov r2, r1, lsl #1\ndone:";
+        let mut machine = Machine::default();
+        assemble(src).unwrap().serialise(&mut machine.ram);
+        machine.registers[1] = 7;
+        let symbols = symbol_table(src);
+
+        assert_eq!(machine.explain(&symbols).unwrap(), "Compare R1 with 10: R1 (7) - 10 = -3, so N=1, Z=0, C=0, V=0; the BGT will not be taken.");
+        machine.step().unwrap();
+        assert_eq!(machine.explain(&symbols).unwrap(), "If greater than, branch to done. Z=0, N=1, V=0, so the branch will not be taken.");
+        machine.step().unwrap();
+        assert_eq!(machine.explain(&symbols).unwrap(), "Copy R1 (7) shifted left by 1 (14) into R2.");
+    }
+
+    #[test]
+    fn explains_results_without_running() {
+        let src = "mov r0, pc\nsubs r2, r0, #8\nsvc #9";
+        let mut machine = Machine::default();
+        assemble(src).unwrap().serialise(&mut machine.ram);
+        let symbols = symbol_table(src);
+
+        assert_eq!(machine.explain(&symbols).unwrap(), "Copy PC (8) into R0.");
+        assert_eq!(machine.registers[0], 0);
+        machine.step().unwrap();
+        assert_eq!(machine.explain(&symbols).unwrap(), "Subtract 8 from R0 (8) and store the result in R2: 8 - 8 = 0.");
+        machine.step().unwrap();
+        assert_eq!(machine.explain(&symbols).unwrap(), "Make supervisor call 9, which isn't a known service.");
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+
+use pest::{iterators::Pair, Parser};
+use serde::Deserialize;
+
+use crate::{
+    assembler::lines_with_offsets,
+    parser::{AssemblyParser, Rule},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct FormatOptions {
+    /// Spaces before each instruction
+    pub indent: usize,
+    /// Write mnemonics, registers and shifts in upper case rather than lower case
+    pub uppercase: bool,
+    /// Line trailing comments up in one column, rather than leaving a single space before them
+    pub align_comments: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { indent: 4, uppercase: true, align_comments: true }
+    }
+}
+
+/// A source line split into the formatted code and its comment.
+struct FormattedLine {
+    kind: LineKind,
+    code: String,
+    comment: Option<String>,
+}
+
+#[derive(PartialEq, Eq)]
+enum LineKind {
+    Label,
+    Instruction,
+    /// Lines which don't parse are kept as they are
+    Invalid,
+    /// Blank or only a comment
+    Empty,
+}
+
+/// Reformats `src` with consistent case, spacing and literals, lining up mnemonics, operands and comments in columns.
+pub fn format(src: &str, options: &FormatOptions) -> String {
+    let parsed = AssemblyParser::parse(Rule::program, src)
+        .expect("invalid lines are parsed as `invalid_line`")
+        .next()
+        .unwrap();
+
+    let mut entries = HashMap::new();
+    for entry in parsed.into_inner().filter(|entry| matches!(entry.as_rule(), Rule::line | Rule::invalid_line)) {
+        entries.insert(entry.line_col().0 - 1, entry);
+    }
+
+    let lines = lines_with_offsets(src)
+        .enumerate()
+        .map(|(i, (start, text))| match entries.remove(&i) {
+            Some(entry) if entry.as_rule() == Rule::line => {
+                // `line` and `instruction` spans can run on over a trailing comment, so find where the last token inside ends
+                let end = entry
+                    .clone()
+                    .into_inner()
+                    .flatten()
+                    .filter(|pair| pair.as_rule() != Rule::instruction)
+                    .map(|pair| pair.as_span().end())
+                    .max()
+                    .unwrap()
+                    - start;
+                let line = entry.into_inner().next().unwrap();
+                let (kind, code) = match line.as_rule() {
+                    Rule::label => (LineKind::Label, format!("{}:", line.into_inner().next().unwrap().as_str())),
+                    _ => (LineKind::Instruction, format_instruction(line, options)),
+                };
+
+                FormattedLine { kind, code, comment: comment(&text[end..]) }
+            }
+            Some(_) => FormattedLine { kind: LineKind::Invalid, code: text.trim_end().to_string(), comment: None },
+            None => FormattedLine { kind: LineKind::Empty, code: String::new(), comment: comment(text) },
+        })
+        .collect::<Vec<_>>();
+
+    let mnemonic_width = lines
+        .iter()
+        .filter(|line| line.kind == LineKind::Instruction)
+        .map(|line| line.code.split(' ').next().unwrap().len())
+        .max()
+        .unwrap_or(0);
+
+    let lines = lines
+        .into_iter()
+        .map(|mut line| {
+            if line.kind == LineKind::Instruction {
+                line.code = match line.code.split_once(' ') {
+                    Some((mnemonic, operands)) => format!("{}{mnemonic:mnemonic_width$} {operands}", " ".repeat(options.indent)),
+                    None => format!("{}{}", " ".repeat(options.indent), line.code),
+                };
+            }
+            line
+        })
+        .collect::<Vec<_>>();
+
+    let comment_column = lines
+        .iter()
+        .filter(|line| line.comment.is_some() && matches!(line.kind, LineKind::Label | LineKind::Instruction))
+        .map(|line| line.code.len() + 1)
+        .max()
+        .unwrap_or(0);
+
+    let mut out = lines
+        .into_iter()
+        .map(|line| match (line.kind, line.comment) {
+            (LineKind::Empty, Some(comment)) => comment,
+            (_, Some(comment)) if options.align_comments => format!("{:comment_column$}{comment}", line.code),
+            (_, Some(comment)) => format!("{} {comment}", line.code),
+            (_, None) => line.code,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if src.ends_with('\n') {
+        out.push('\n');
+    }
+
+    out
+}
+
+/// The comment in what is left of a line after its code.
+fn comment(rest: &str) -> Option<String> {
+    let rest = rest.trim();
+    (!rest.is_empty()).then(|| rest.to_string())
+}
+
+fn format_instruction(instruction: Pair<'_, Rule>, options: &FormatOptions) -> String {
+    let mut inner = instruction.into_inner();
+    let mnemonic = set_case(inner.next().unwrap().as_str(), options);
+    let operands = inner.map(|arg| format_argument(arg, options)).collect::<Vec<_>>();
+
+    if operands.is_empty() {
+        mnemonic
+    } else {
+        format!("{mnemonic} {}", operands.join(", "))
+    }
+}
+
+fn format_argument(arg: Pair<'_, Rule>, options: &FormatOptions) -> String {
+    match arg.as_rule() {
+        Rule::register => format_register(arg, options),
+        Rule::literal => format_literal(arg),
+        Rule::shift => {
+            let mut inner = arg.into_inner();
+            let ty = set_case(inner.next().unwrap().as_str(), options);
+            format!("{ty} {}", format_argument(inner.next().unwrap(), options))
+        }
+        Rule::indirect_addr => {
+            let parts = arg.into_inner().map(|part| format_argument(part, options)).collect::<Vec<_>>();
+            format!("[{}]", parts.join(" "))
+        }
+        _ => arg.as_str().to_string(),
+    }
+}
+
+fn format_register(register: Pair<'_, Rule>, options: &FormatOptions) -> String {
+    let text = register.as_str();
+    let register = match register.into_inner().next() {
+        // Drop leading zeros from the index, as in `R01`
+        Some(index) if index.as_rule() == Rule::decimal => match index.as_str().parse::<u32>() {
+            Ok(index) => format!("r{index}"),
+            Err(_) => text.to_string(),
+        },
+        _ => text.to_string(),
+    };
+
+    set_case(&register, options)
+}
+
+/// Literals are written as `#-12` or `-0xff`, without leading zeros on decimals.
+fn format_literal(literal: Pair<'_, Rule>) -> String {
+    let mut negative = false;
+    let mut out = String::new();
+
+    for part in literal.into_inner().flatten() {
+        match part.as_rule() {
+            Rule::negation => negative = !negative,
+            Rule::decimal => {
+                let digits = part.as_str().trim_start_matches('0');
+                out = format!("#{}{}", if negative { "-" } else { "" }, if digits.is_empty() { "0" } else { digits });
+            }
+            Rule::hex => out = format!("{}0x{}", if negative { "-" } else { "" }, part.as_str().to_ascii_lowercase()),
+            _ => {}
+        }
+    }
+
+    out
+}
+
+fn set_case(text: &str, options: &FormatOptions) -> String {
+    if options.uppercase {
+        text.to_ascii_uppercase()
+    } else {
+        text.to_ascii_lowercase()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{prelude::any, prop_oneof, proptest, strategy::Strategy};
+
+    use super::{format, FormatOptions};
+    use crate::{assembler::assemble, Instruction};
+
+    #[test]
+    fn formats() {
+        let src = "start:  ; entry\n mov r01,#0010\n\tADDSEQ  r1,r1, -#3 // add\nloop :\n  cmp r1,r2,lsl  #2\n; done\n  b   loop\n";
+
+        assert_eq!(format(src, &FormatOptions::default()), concat!(
+            "start:                 ; entry\n",
+            "    MOV    R1, #10\n",
+            "    ADDSEQ R1, R1, #-3 // add\n",
+            "loop:\n",
+            "    CMP    R1, R2, LSL #2\n",
+            "; done\n",
+            "    B      loop\n",
+        ));
+    }
+
+    /// Lines of source with messy spacing and case, including some which don't parse.
+    fn messy_line() -> impl Strategy<Value = String> {
+        let instruction = (any::<Instruction>(), any::<bool>(), "[ \t]{0,3}").prop_map(|(instruction, upper, space)| {
+            let text = instruction.to_string().replace(", ", &format!(",{space}"));
+            format!("{space}{}", if upper { text } else { text.to_lowercase() })
+        });
+
+        prop_oneof![
+            instruction,
+            "[ \t]{0,2}[a-z]{1,5}:",
+            "[ \t]{0,4}(;|//)[a-z ]{0,8}",
+            "[a-z#, ]{0,10}",
+            "",
+        ]
+        .prop_flat_map(|line| ("( *; [a-z]{1,4})?").prop_map(move |comment| format!("{line}{comment}")))
+    }
+
+    proptest! {
+        #[test]
+        fn format_is_idempotent(lines in proptest::collection::vec(messy_line(), 0..8), uppercase: bool, align_comments: bool) {
+            let src = lines.join("\n");
+            let options = FormatOptions { indent: 2, uppercase, align_comments };
+            let formatted = format(&src, &options);
+
+            assert_eq!(format(&formatted, &options), formatted, "{src:?}");
+
+            if let Ok(program) = assemble(&src) {
+                assert_eq!(assemble(&formatted).unwrap().instructions, program.instructions, "{src:?}");
+            }
+        }
+    }
+}
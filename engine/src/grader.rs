@@ -0,0 +1,306 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    blocks::Backend,
+    machine::{register_index, register_name, Machine, RAM_SIZE},
+    metrics::{CycleModel, Metrics},
+    profile::Profile,
+    Assembly, Flags,
+};
+
+/// Cases to check a program against, as written in a TOML or JSON specification.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestSuite {
+    /// Steps each case may run for before it fails, unless the case sets its own limit
+    #[serde(default = "default_max_steps")]
+    pub max_steps: u64,
+    /// RAM size in bytes
+    #[serde(default = "default_ram_size")]
+    pub ram_size: usize,
+    /// Cycle costs used to rank submissions
+    #[serde(default)]
+    pub cycles: CycleModel,
+    #[serde(rename = "case", alias = "cases")]
+    pub cases: Vec<TestCase>,
+}
+
+/// The machine a case starts with, and what it should end with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TestCase {
+    pub name: String,
+    pub max_steps: Option<u64>,
+    /// Register names such as `r0` or `sp` to their starting values
+    pub registers: BTreeMap<String, Word>,
+    /// Addresses to words stored from there on, after the program is loaded
+    pub memory: BTreeMap<String, Vec<Word>>,
+    /// Starting flags, written like `nZCv`
+    pub flags: Option<String>,
+    /// Console input, all available from the start
+    pub input: String,
+    pub expect: Expectation,
+}
+
+/// The final machine state a case checks. Anything left out isn't checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Expectation {
+    pub registers: BTreeMap<String, Word>,
+    pub memory: BTreeMap<String, Vec<Word>>,
+    pub flags: Option<String>,
+    /// Everything written to the console
+    pub output: Option<String>,
+    /// The code passed to `SVC #0`, which also requires the program to exit rather than halt at a branch to itself
+    pub exit_code: Option<u32>,
+}
+
+/// A 32 bit value, which may be written as a negative number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Word(pub u32);
+
+impl<'de> Deserialize<'de> for Word {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = i64::deserialize(deserializer)?;
+        match value {
+            -0x8000_0000..=0xFFFF_FFFF => Ok(Word(value as u32)),
+            _ => Err(serde::de::Error::custom(format!("{value} doesn't fit in 32 bits"))),
+        }
+    }
+}
+
+/// How a program did on one case.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CaseReport {
+    pub name: String,
+    pub metrics: Metrics,
+    pub output: String,
+    pub mismatches: Vec<Mismatch>,
+    /// Which instructions ran, for coverage of the whole suite
+    #[serde(skip)]
+    pub profile: Profile,
+}
+
+impl CaseReport {
+    pub fn passed(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Something which didn't end up as the case expected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Mismatch {
+    /// What was checked, e.g. `R0` or `output line 2`
+    pub what: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: expected {}, got {}", self.what, self.expected, self.actual)
+    }
+}
+
+fn default_max_steps() -> u64 {
+    100_000
+}
+
+fn default_ram_size() -> usize {
+    RAM_SIZE
+}
+
+impl TestSuite {
+    /// Runs `assembly` against every case, failing only if the specification itself is invalid.
+    pub fn run(&self, assembly: &Assembly) -> Result<Vec<CaseReport>> {
+        self.cases.iter().map(|case| self.run_case(assembly, case).with_context(|| format!("In case `{}`", case.name))).collect()
+    }
+
+    pub fn run_case(&self, assembly: &Assembly, case: &TestCase) -> Result<CaseReport> {
+        let size = assembly.instructions.len() * 4;
+        if size > self.ram_size {
+            bail!("The program takes {size} bytes, which doesn't fit in {} bytes of RAM", self.ram_size);
+        }
+
+        let mut machine = Machine::with_backend(self.ram_size, Backend::Blocks);
+        machine.cycle_model = self.cycles;
+        machine.profiler = Some(Profile::new());
+        assembly.serialise(&mut machine.ram);
+
+        for (name, value) in &case.registers {
+            machine.registers[parse_register(name)?] = value.0;
+        }
+        for (addr, words) in &case.memory {
+            let addr = parse_address(addr)?;
+            let bytes = words.iter().flat_map(|word| word.0.to_be_bytes()).collect::<Vec<_>>();
+            let ram = (addr as usize).checked_add(bytes.len()).and_then(|end| machine.ram.get_mut(addr as usize..end));
+            ram.with_context(|| format!("Memory at {addr:#x} is outside of RAM"))?.copy_from_slice(&bytes);
+        }
+        if let Some(flags) = &case.flags {
+            machine.flags = flags.parse()?;
+        }
+        machine.console.push_input(case.input.as_bytes());
+        machine.console.closed = true;
+
+        let max_steps = case.max_steps.unwrap_or(self.max_steps);
+        let mut mismatches = Vec::new();
+
+        // Input is closed, so the run only stops early by halting or faulting
+        match machine.run(max_steps) {
+            Err(e) => mismatches.push(Mismatch::new("execution", "to halt", format!("{e} at {:#010x}", machine.pc()))),
+            Ok(_) if !machine.halted() => {
+                mismatches.push(Mismatch::new("execution", format!("to halt within {max_steps} steps"), format!("still running at {:#010x}", machine.pc())));
+            }
+            Ok(_) => {}
+        }
+
+        let output = String::from_utf8_lossy(&machine.console.take_output()).into_owned();
+        mismatches.extend(check(&case.expect, &machine, &output)?);
+
+        Ok(CaseReport { name: case.name.clone(), metrics: machine.metrics, output, mismatches, profile: machine.profiler.unwrap_or_default() })
+    }
+}
+
+impl Mismatch {
+    fn new(what: impl Into<String>, expected: impl Into<String>, actual: impl Into<String>) -> Self {
+        Self { what: what.into(), expected: expected.into(), actual: actual.into() }
+    }
+}
+
+fn check(expect: &Expectation, machine: &Machine, output: &str) -> Result<Vec<Mismatch>> {
+    let mut mismatches = Vec::new();
+
+    if let Some(code) = expect.exit_code {
+        if machine.exit_code != Some(code) {
+            let actual = machine.exit_code.map_or("no exit".to_string(), |code| code.to_string());
+            mismatches.push(Mismatch::new("exit code", code.to_string(), actual));
+        }
+    }
+
+    let mut registers = expect.registers.iter().map(|(name, expected)| Ok((parse_register(name)?, expected))).collect::<Result<Vec<_>>>()?;
+    registers.sort_by_key(|(index, _)| *index);
+
+    for (index, expected) in registers {
+        let actual = machine.registers[index];
+        if actual != expected.0 {
+            mismatches.push(Mismatch::new(register_name(index), format_word(expected.0), format_word(actual)));
+        }
+    }
+
+    for (addr, words) in &expect.memory {
+        let start = parse_address(addr)?;
+        for (i, expected) in words.iter().enumerate() {
+            let addr = start.checked_add(i as u32 * 4).with_context(|| format!("Memory after {start:#x} is outside of RAM"))?;
+            let actual = machine.fetch(addr).map(u32::from_be_bytes).with_context(|| format!("Memory at {addr:#x} is outside of RAM"))?;
+            if actual != expected.0 {
+                mismatches.push(Mismatch::new(format!("memory at {addr:#010x}"), format_word(expected.0), format_word(actual)));
+            }
+        }
+    }
+
+    if let Some(flags) = &expect.flags {
+        let expected: Flags = flags.parse()?;
+        if machine.flags != expected {
+            mismatches.push(Mismatch::new("flags", expected.to_string(), machine.flags.to_string()));
+        }
+    }
+
+    if let Some(expected) = &expect.output {
+        mismatches.extend(diff_output(expected, output));
+    }
+
+    Ok(mismatches)
+}
+
+/// The first line where the output differs, so long outputs don't bury the difference.
+fn diff_output(expected: &str, actual: &str) -> Option<Mismatch> {
+    if expected == actual {
+        return None;
+    }
+
+    let mut expected_lines = expected.split_inclusive('\n');
+    let mut actual_lines = actual.split_inclusive('\n');
+
+    for line in 1.. {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(expected), Some(actual)) if expected == actual => {}
+            (expected, actual) => {
+                let describe = |text: Option<&str>| text.map_or("end of output".to_string(), |text| format!("{text:?}"));
+                return Some(Mismatch::new(format!("output line {line}"), describe(expected), describe(actual)));
+            }
+        }
+    }
+
+    unreachable!()
+}
+
+fn parse_register(name: &str) -> Result<usize> {
+    register_index(name).with_context(|| format!("Unknown register `{name}`"))
+}
+
+/// A decimal or `0x` hex address.
+fn parse_address(addr: &str) -> Result<u32> {
+    let parsed = match addr.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => addr.parse(),
+    };
+
+    parsed.with_context(|| format!("`{addr}` isn't an address"))
+}
+
+fn format_word(value: u32) -> String {
+    format!("{} ({value:#x})", value as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble_all;
+
+    use super::TestSuite;
+
+    #[test]
+    fn grades_cases() {
+        let suite: TestSuite = serde_json::from_str(r#"{
+            "max_steps": 50,
+            "cases": [
+                { "name": "adds", "registers": { "r1": 2, "r2": -5 }, "expect": { "registers": { "r0": -3 }, "flags": "Nzcv" } },
+                { "name": "echoes", "input": "ab", "expect": { "output": "a\nb", "exit_code": 0 } },
+                { "name": "wrong", "registers": { "r1": 1, "r2": 1 }, "expect": { "registers": { "r0": 3, "pc": 8 } } }
+            ]
+        }"#).unwrap();
+
+        let assembly = assemble_all("adds r0, r1, r2\nbmi done\nsvc #2\nsvc #1\nmov r0, #10\nsvc #1\nsvc #2\nsvc #1\nmov r0, #0\nsvc #0\ndone:\nb done");
+        let reports = suite.run(&assembly).unwrap();
+
+        assert!(reports[0].passed(), "{:?}", reports[0]);
+        assert_eq!((reports[0].metrics.instructions, reports[0].metrics.cycles), (2, 4));
+        assert!(reports[1].passed(), "{:?}", reports[1]);
+        assert_eq!(reports[2].mismatches.iter().map(ToString::to_string).collect::<Vec<_>>(), [
+            "R0: expected 3 (0x3), got 0 (0x0)",
+            "PC: expected 8 (0x8), got 40 (0x28)",
+        ]);
+    }
+
+    #[test]
+    fn reports_where_faults_happen() {
+        let suite: TestSuite = serde_json::from_str(r#"{ "cases": [{ "name": "faults" }] }"#).unwrap();
+        let reports = suite.run(&assemble_all("mov r0, #1\nsvc #99")).unwrap();
+
+        assert_eq!(reports[0].mismatches[0].to_string(), "execution: expected to halt, got Unknown supervisor call 99 at 0x00000004");
+    }
+
+    #[test]
+    fn rejects_addresses_outside_memory() {
+        let error = |memory: &str| {
+            let suite: TestSuite = serde_json::from_str(&format!(r#"{{ "cases": [{{ "name": "memory", "expect": {{ "memory": {memory} }} }}] }}"#)).unwrap();
+            format!("{:#}", suite.run(&assemble_all("svc #0")).unwrap_err())
+        };
+
+        // Used to wrap around to address 0
+        assert_eq!(error(r#"{ "0x100000000": [0] }"#), "In case `memory`: `0x100000000` isn't an address: number too large to fit in target type");
+        assert_eq!(error(r#"{ "0xFFFFFFFC": [0, 0] }"#), "In case `memory`: Memory at 0xfffffffc is outside of RAM");
+    }
+}
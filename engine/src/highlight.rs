@@ -0,0 +1,224 @@
+use pest::{iterators::Pair, Parser};
+use serde::Serialize;
+
+use crate::{
+    assembler::{lines_with_offsets, parse_opcode},
+    parser::{AssemblyParser, Rule},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SemanticToken {
+    pub kind: TokenKind,
+    pub line: u32,
+    /// Byte offsets into `src`
+    pub from: u32,
+    pub to: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    Mnemonic,
+    /// The condition code at the end of a mnemonic, such as the `EQ` in `ADDSEQ`
+    Condition,
+    Register,
+    /// `SP`, `LR` or `PC`
+    Alias,
+    LabelDefinition,
+    LabelReference,
+    Immediate,
+    Shift,
+    Comment,
+}
+
+/// Tokens for every line of `src`, in source order.
+///
+/// Lines with syntax errors can't be read the way the assembler would, so they fall back to [`lexical_tokens`].
+pub fn semantic_tokens(src: &str) -> Vec<SemanticToken> {
+    let mut tokens = Vec::new();
+
+    for (i, (line_start, line)) in lines_with_offsets(src).enumerate() {
+        let mut push = |kind, from: usize, to: usize| tokens.push(SemanticToken {
+            kind,
+            line: i as u32,
+            from: (line_start + from) as u32,
+            to: (line_start + to) as u32,
+        });
+
+        let parsed = match AssemblyParser::parse(Rule::lint_line, line) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                lexical_tokens(line, push);
+                continue;
+            }
+        };
+
+        let mut end = 0;
+        let mut definition = None;
+        for pair in parsed.flatten() {
+            let span = pair.as_span();
+            if !matches!(pair.as_rule(), Rule::EOI | Rule::line | Rule::instruction) {
+                end = end.max(span.end());
+            }
+
+            match pair.as_rule() {
+                Rule::label => {
+                    let name = pair.into_inner().next().unwrap().as_span();
+                    definition = Some(name);
+                    push(TokenKind::LabelDefinition, name.start(), name.end());
+                }
+                Rule::opcode => {
+                    let condition_len = opcode_condition_len(pair.as_str());
+                    push(TokenKind::Mnemonic, span.start(), span.end() - condition_len);
+                    if condition_len > 0 {
+                        push(TokenKind::Condition, span.end() - condition_len, span.end());
+                    }
+                }
+                Rule::register => push(register_kind(pair), span.start(), span.end()),
+                Rule::literal => push(TokenKind::Immediate, span.start(), span.end()),
+                Rule::shift_type => push(TokenKind::Shift, span.start(), span.end()),
+                Rule::text if definition != Some(span) => push(TokenKind::LabelReference, span.start(), span.end()),
+                _ => {}
+            }
+        }
+
+        // Comments are skipped implicitly after the last token, so anything left is the comment.
+        // `line` and `instruction` can end after the comment, so only the tokens inside them are counted.
+        if let Some(comment) = line[end..].find(['/', ';']) {
+            push(TokenKind::Comment, end + comment, line.len());
+        }
+    }
+
+    tokens
+}
+
+/// Tokens for a line which doesn't parse, guessed word by word so the line keeps its colours while it is being typed.
+///
+/// The first word is taken to be the mnemonic, and anything which isn't a register or immediate is left alone.
+fn lexical_tokens(line: &str, mut push: impl FnMut(TokenKind, usize, usize)) {
+    let code_len = [line.find(';'), line.find("//")].into_iter().flatten().min().unwrap_or(line.len());
+    let code = &line[..code_len];
+
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '#' || c == '-';
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in code.char_indices().chain([(code.len(), ' ')]) {
+        match start {
+            None if is_word(c) => start = Some(i),
+            Some(from) if !is_word(c) => {
+                words.push((&code[from..i], from));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    for (n, (word, from)) in words.into_iter().enumerate() {
+        let register = AssemblyParser::parse(Rule::register, word).ok().and_then(|mut pairs| pairs.next()).filter(|pair| pair.as_str() == word);
+
+        let kind = match register {
+            Some(register) => register_kind(register),
+            None if word.starts_with(['#', '-']) || word.starts_with(|c: char| c.is_ascii_digit()) => TokenKind::Immediate,
+            None if n == 0 => TokenKind::Mnemonic,
+            None => continue,
+        };
+        push(kind, from, from + word.len());
+    }
+
+    if code_len < line.len() {
+        push(TokenKind::Comment, code_len, line.len());
+    }
+}
+
+fn register_kind(register: Pair<'_, Rule>) -> TokenKind {
+    match register.into_inner().next().map(|inner| inner.as_rule()) {
+        Some(Rule::stack_pointer | Rule::program_counter | Rule::link_register) => TokenKind::Alias,
+        _ => TokenKind::Register,
+    }
+}
+
+/// Length of the condition suffix at the end of `mnemonic`, which is zero for an implicit `AL`.
+fn opcode_condition_len(mnemonic: &str) -> usize {
+    match parse_opcode(mnemonic) {
+        Some((_, condition, _)) if mnemonic.to_ascii_lowercase().ends_with(condition.as_str()) => condition.as_str().len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{semantic_tokens, TokenKind};
+    use crate::utf16::{ToUtf16, Utf16Map};
+
+    #[test]
+    fn tokenises_source() {
+        let src = "loop: // top\n  addseq sp, r1, 0x10\n  mov r2, r3, lsl #2 ; shift\nbne loop ; back";
+
+        let tokens = semantic_tokens(src)
+            .into_iter()
+            .map(|token| (token.kind, &src[token.from as usize..token.to as usize]))
+            .collect::<Vec<_>>();
+
+        assert_eq!(tokens, [
+            (TokenKind::LabelDefinition, "loop"),
+            (TokenKind::Comment, "// top"),
+            (TokenKind::Mnemonic, "adds"),
+            (TokenKind::Condition, "eq"),
+            (TokenKind::Alias, "sp"),
+            (TokenKind::Register, "r1"),
+            (TokenKind::Immediate, "0x10"),
+            (TokenKind::Mnemonic, "mov"),
+            (TokenKind::Register, "r2"),
+            (TokenKind::Register, "r3"),
+            (TokenKind::Shift, "lsl"),
+            (TokenKind::Immediate, "#2"),
+            (TokenKind::Comment, "; shift"),
+            (TokenKind::Mnemonic, "b"),
+            (TokenKind::Condition, "ne"),
+            (TokenKind::LabelReference, "loop"),
+            (TokenKind::Comment, "; back"),
+        ]);
+    }
+
+    #[test]
+    fn tokenises_after_non_ascii() {
+        let src = "; π ≈ 𝑥\nmov r1, #3 ; ≈ 3\nb end";
+
+        let tokens = semantic_tokens(src)
+            .to_utf16(&Utf16Map::new(src))
+            .into_iter()
+            .map(|token| (token.kind, token.line, token.from, token.to))
+            .collect::<Vec<_>>();
+
+        assert_eq!(tokens, [
+            (TokenKind::Comment, 0, 0, 8),
+            (TokenKind::Mnemonic, 1, 9, 12),
+            (TokenKind::Register, 1, 13, 15),
+            (TokenKind::Immediate, 1, 17, 19),
+            (TokenKind::Comment, 1, 20, 25),
+            (TokenKind::Mnemonic, 2, 26, 27),
+            (TokenKind::LabelReference, 2, 28, 31),
+        ]);
+    }
+
+    #[test]
+    fn tokenises_lines_with_errors() {
+        let src = "mov r1 #3, sp ; missing comma\nadd r1, r2, ";
+
+        let tokens = semantic_tokens(src)
+            .into_iter()
+            .map(|token| (token.kind, &src[token.from as usize..token.to as usize]))
+            .collect::<Vec<_>>();
+
+        assert_eq!(tokens, [
+            (TokenKind::Mnemonic, "mov"),
+            (TokenKind::Register, "r1"),
+            (TokenKind::Immediate, "#3"),
+            (TokenKind::Alias, "sp"),
+            (TokenKind::Comment, "; missing comma"),
+            (TokenKind::Mnemonic, "add"),
+            (TokenKind::Register, "r1"),
+            (TokenKind::Register, "r2"),
+        ]);
+    }
+}
@@ -0,0 +1,173 @@
+use pest::{iterators::Pair, Parser};
+
+use crate::{
+    assembler::{encode_immediate, lines_with_offsets, parse_literal, parse_opcode, parse_reg, Opcode, SymbolIndex},
+    parser::{AssemblyParser, Rule},
+    Condition,
+};
+
+/// Markdown describing the token at byte `offset` in `src`, if there is anything useful to say.
+pub fn hover(src: &str, offset: usize) -> Option<String> {
+    let (line_start, line) = line_at(src, offset)?;
+    let parsed = AssemblyParser::parse(Rule::lint_line, line).ok()?;
+    let cursor = offset - line_start;
+
+    let token = parsed
+        .flatten()
+        .find(|pair| is_hoverable(pair.as_rule()) && pair.as_span().start() <= cursor && cursor <= pair.as_span().end())?;
+
+    match token.as_rule() {
+        Rule::opcode => Some(hover_opcode(token.as_str())),
+        Rule::register => Some(hover_register(token)),
+        Rule::literal => Some(hover_literal(token)),
+        Rule::shift_type => Some(hover_shift(token.as_str())),
+        Rule::label => {
+            let name = token.into_inner().next()?.as_str();
+            Some(hover_label(name, &SymbolIndex::build(src)))
+        }
+        Rule::text => Some(hover_label(token.as_str(), &SymbolIndex::build(src))),
+        _ => None,
+    }
+}
+
+fn is_hoverable(rule: Rule) -> bool {
+    matches!(rule, Rule::opcode | Rule::register | Rule::literal | Rule::shift_type | Rule::label | Rule::text)
+}
+
+/// The line containing `offset` along with the offset it starts at.
+pub(crate) fn line_at(src: &str, offset: usize) -> Option<(usize, &str)> {
+    lines_with_offsets(src)
+        .find(|(start, line)| offset <= start + line.len())
+        .or_else(|| (offset == src.len()).then_some((offset, "")))
+}
+
+fn hover_opcode(mnemonic: &str) -> String {
+    let (opcode, condition, set_flags) = match parse_opcode(mnemonic) {
+        Some(parsed) => parsed,
+        None => return format!("Unknown instruction `{}`", mnemonic.to_ascii_uppercase()),
+    };
+
+    let (title, syntax, description) = opcode.documentation();
+    let mut out = format!("**{}** — {title}\n\n`{syntax}`\n\n{description}\n", mnemonic.to_ascii_uppercase());
+
+    if condition != Condition::AL {
+        out += &format!("\n- `{condition}`: only runs if {}", condition.describe());
+    }
+    if set_flags {
+        out += "\n- `S`: updates the condition flags";
+    }
+
+    out
+}
+
+fn hover_register(register: Pair<'_, Rule>) -> String {
+    let text = register.as_str().to_ascii_uppercase();
+    let register = match parse_reg(register) {
+        Ok(register) => register,
+        Err(e) => return e.variant.message().into_owned(),
+    };
+
+    let alias = match register.0 {
+        13 => Some(("SP", "the stack pointer")),
+        14 => Some(("LR", "the link register, holding the return address after `BL`")),
+        15 => Some(("PC", "the program counter")),
+        _ => None,
+    };
+
+    match alias {
+        Some((alias, description)) if text == alias => format!("**{alias}** — R{}, {description}", register.0),
+        Some((alias, description)) => format!("**R{}** — also written `{alias}`, {description}", register.0),
+        None => format!("**R{}** — general purpose register", register.0),
+    }
+}
+
+fn hover_literal(literal: Pair<'_, Rule>) -> String {
+    let text = literal.as_str().to_string();
+    let value = match parse_literal(literal) {
+        Ok(value) => value,
+        Err(e) => return e.variant.message().into_owned(),
+    };
+
+    let encoding = match encode_immediate(value) {
+        Some((0, _)) => "Can be used as an immediate operand".to_string(),
+        Some((rotate, byte)) => format!("Can be used as an immediate operand, encoded as `{byte:#x}` rotated right by {}", rotate * 2),
+        None => "Cannot be used as an immediate operand, as it is not an 8 bit value rotated by an even amount".to_string(),
+    };
+
+    format!(
+        "**Literal** `{text}`\n\n| Base | Value |\n|---|---|\n| Decimal | {} |\n| Unsigned | {value} |\n| Hex | {value:#010x} |\n| Binary | {value:#034b} |\n\n{encoding}",
+        value as i32
+    )
+}
+
+fn hover_shift(shift: &str) -> String {
+    let description = match shift.to_ascii_uppercase().as_str() {
+        "LSL" => "Logical shift left, filling with zeros",
+        "LSR" => "Logical shift right, filling with zeros",
+        "ASR" => "Arithmetic shift right, copying the sign bit",
+        "ROR" => "Rotate right",
+        _ => "Unknown shift",
+    };
+
+    format!("**{}** — {description}", shift.to_ascii_uppercase())
+}
+
+fn hover_label(name: &str, index: &SymbolIndex) -> String {
+    match index.symbols.get(name).and_then(|symbol| Some((symbol.definition?, symbol.addr?))) {
+        Some((definition, addr)) => format!("**{name}** — label\n\nDefined on line {} at address `{addr:#04x}`", definition.line + 1),
+        None => format!("Label `{name}` is not defined"),
+    }
+}
+
+impl Opcode {
+    /// Title, syntax and a one line description of what the instruction does.
+    pub(crate) fn documentation(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Opcode::And => ("Bitwise AND", "AND{S}{cond} Rd, Rn, <operand2>", "Rd := Rn AND operand2"),
+            Opcode::Eor => ("Bitwise exclusive OR", "EOR{S}{cond} Rd, Rn, <operand2>", "Rd := Rn EOR operand2"),
+            Opcode::Sub => ("Subtract", "SUB{S}{cond} Rd, Rn, <operand2>", "Rd := Rn - operand2"),
+            Opcode::Rsb => ("Reverse subtract", "RSB{S}{cond} Rd, Rn, <operand2>", "Rd := operand2 - Rn"),
+            Opcode::Add => ("Add", "ADD{S}{cond} Rd, Rn, <operand2>", "Rd := Rn + operand2"),
+            Opcode::Adc => ("Add with carry", "ADC{S}{cond} Rd, Rn, <operand2>", "Rd := Rn + operand2 + C"),
+            Opcode::Sbc => ("Subtract with carry", "SBC{S}{cond} Rd, Rn, <operand2>", "Rd := Rn - operand2 - NOT C"),
+            Opcode::Rsc => ("Reverse subtract with carry", "RSC{S}{cond} Rd, Rn, <operand2>", "Rd := operand2 - Rn - NOT C"),
+            Opcode::Tst => ("Test", "TST{cond} Rn, <operand2>", "Sets the flags on Rn AND operand2"),
+            Opcode::Teq => ("Test equivalence", "TEQ{cond} Rn, <operand2>", "Sets the flags on Rn EOR operand2"),
+            Opcode::Cmp => ("Compare", "CMP{cond} Rn, <operand2>", "Sets the flags on Rn - operand2"),
+            Opcode::Cmn => ("Compare negative", "CMN{cond} Rn, <operand2>", "Sets the flags on Rn + operand2"),
+            Opcode::Or => ("Bitwise OR", "ORR{S}{cond} Rd, Rn, <operand2>", "Rd := Rn OR operand2"),
+            Opcode::Mov => ("Move", "MOV{S}{cond} Rd, <operand2>", "Rd := operand2"),
+            Opcode::Bic => ("Bit clear", "BIC{S}{cond} Rd, Rn, <operand2>", "Rd := Rn AND NOT operand2"),
+            Opcode::Mvn => ("Move NOT", "MVN{S}{cond} Rd, <operand2>", "Rd := NOT operand2"),
+            Opcode::B => ("Branch", "B{cond} <label>", "Continues execution at the label"),
+            Opcode::Bl => ("Branch with link", "BL{cond} <label>", "Stores the return address in LR and continues execution at the label"),
+            Opcode::Svc => ("Supervisor call", "SVC{cond} #<number>", "Asks the host to perform a service such as console I/O, passing values in R0"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hover;
+    use crate::utf16::Utf16Map;
+
+    #[test]
+    fn hovers() {
+        let src = "mov r1, #256\nloop:\naddseq sp, r1, #1\nb loop";
+
+        assert!(hover(src, 8).unwrap().contains("encoded as `0x1` rotated right by 24"));
+        assert!(hover(src, 21).unwrap().starts_with("**ADDSEQ** — Add"));
+        assert!(hover(src, 27).unwrap().starts_with("**SP** — R13"));
+        assert!(hover(src, 42).unwrap().contains("Defined on line 2 at address `0x04`"));
+        assert_eq!(hover(src, 7), None);
+    }
+
+    #[test]
+    fn hovers_after_non_ascii() {
+        let src = "; café ≈ 𝑥\nmov r1, #3";
+        let map = Utf16Map::new(src);
+
+        assert!(hover(src, map.byte(16)).unwrap().starts_with("**R1**"));
+        assert_eq!(hover(src, 6), None);
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    console::Service, disassembler::SymbolTable, emulator::{alu, operand_registers}, machine::Machine, Condition, DataProcessingOpcode, DataProcessingOperand, Instruction, InstructionBody, Register, ShiftAmount
+};
+
+const LINK_REGISTER: Register = Register(14);
+const PROGRAM_COUNTER: Register = Register(15);
+
+/// Everything the UI shows about the instruction at the PC.
+#[derive(Debug, Serialize)]
+pub struct InstructionReadout {
+    pub addr: u32,
+    pub word: u32,
+    pub instruction: Instruction,
+    pub disassembly: String,
+    /// Source line the instruction was assembled from, if known
+    pub line: Option<u32>,
+    pub condition_passes: bool,
+    pub reads: Vec<Register>,
+    pub writes: Vec<Register>,
+    pub reads_flags: bool,
+    pub writes_flags: bool,
+    /// `None` if executing the instruction would fault
+    pub next_pc: Option<u32>,
+}
+
+impl Machine {
+    /// Decodes the instruction at the PC and predicts its effects without changing the machine.
+    pub fn current_instruction(&self, source_map: &HashMap<u32, u32>, symbols: &SymbolTable) -> Result<InstructionReadout> {
+        let addr = self.pc();
+        let word = self.fetch(addr).context("PC is outside of memory")?;
+        let instruction = Instruction::deserialise(&word)?;
+        let condition_passes = instruction.condition.matches(self.flags);
+
+        Ok(InstructionReadout {
+            addr,
+            word: u32::from_be_bytes(word),
+            instruction,
+            disassembly: instruction.to_asm(addr, symbols),
+            line: source_map.get(&addr).copied(),
+            condition_passes,
+            reads: instruction.registers_read(),
+            writes: if condition_passes { instruction.registers_written() } else { vec![PROGRAM_COUNTER] },
+            reads_flags: instruction.reads_flags(),
+            writes_flags: condition_passes && instruction.writes_flags(),
+            next_pc: self.next_pc(addr, &instruction),
+        })
+    }
+
+    /// Where the PC will be once `instruction` runs from `addr`, or `None` if running it would fault.
+    fn next_pc(&self, addr: u32, instruction: &Instruction) -> Option<u32> {
+        if self.exit_code.is_some() {
+            return None;
+        }
+
+        let next = addr.wrapping_add(4);
+        if !instruction.condition.matches(self.flags) {
+            return Some(next);
+        }
+
+        match instruction.body {
+            InstructionBody::DataProcessing(dp) if dp.dest == PROGRAM_COUNTER && !dp.opcode.is_comparison() => {
+                let registers = operand_registers(&self.registers, addr);
+                let rhs = dp.operand.eval(&registers);
+
+                Some(alu(dp.opcode, registers[dp.register.0 as usize], rhs, self.flags).0)
+            }
+            InstructionBody::DataProcessing(_) => Some(next),
+            InstructionBody::Branch(branch) => Some(branch.target(addr)),
+            // A read which has to wait for input runs again
+            InstructionBody::SupervisorCall(call) => match call.service()? {
+                service if self.console.would_wait(service) => Some(addr),
+                _ => Some(next),
+            },
+        }
+    }
+}
+
+impl Instruction {
+    pub fn registers_read(&self) -> Vec<Register> {
+        let mut reads = Vec::new();
+
+        match &self.body {
+            InstructionBody::DataProcessing(dp) => {
+                if !matches!(dp.opcode, DataProcessingOpcode::MOV | DataProcessingOpcode::MVN) {
+                    reads.push(dp.register);
+                }
+
+                if let DataProcessingOperand::Register { shift, register } = dp.operand {
+                    reads.push(register);
+                    if let ShiftAmount::Register(amount) = shift.amount {
+                        reads.push(amount);
+                    }
+                }
+            }
+            InstructionBody::Branch(_) => reads.push(PROGRAM_COUNTER),
+            InstructionBody::SupervisorCall(call) => {
+                if matches!(call.service(), Some(Service::Exit | Service::WriteChar | Service::WriteInt)) {
+                    reads.push(Register(0));
+                }
+            }
+        }
+
+        reads.sort_by_key(|register| register.0);
+        reads.dedup();
+        reads
+    }
+
+    /// Registers written if the condition passes. The PC is always advanced as well.
+    pub fn registers_written(&self) -> Vec<Register> {
+        match &self.body {
+            InstructionBody::DataProcessing(dp) if dp.opcode.is_comparison() => vec![PROGRAM_COUNTER],
+            InstructionBody::DataProcessing(dp) if dp.dest == PROGRAM_COUNTER => vec![PROGRAM_COUNTER],
+            InstructionBody::DataProcessing(dp) => vec![dp.dest, PROGRAM_COUNTER],
+            InstructionBody::Branch(branch) if branch.link => vec![LINK_REGISTER, PROGRAM_COUNTER],
+            InstructionBody::Branch(_) => vec![PROGRAM_COUNTER],
+            InstructionBody::SupervisorCall(call) if matches!(call.service(), Some(Service::ReadChar | Service::ReadInt)) => vec![Register(0), PROGRAM_COUNTER],
+            InstructionBody::SupervisorCall(_) => vec![PROGRAM_COUNTER],
+        }
+    }
+
+    pub fn reads_flags(&self) -> bool {
+        let carry_in = matches!(
+            self.body,
+            InstructionBody::DataProcessing(dp) if matches!(dp.opcode, DataProcessingOpcode::ADC | DataProcessingOpcode::SBC | DataProcessingOpcode::RSC)
+        );
+
+        self.condition != Condition::AL || carry_in
+    }
+
+    /// Only data processing instructions with the S suffix update the flags.
+    pub fn writes_flags(&self) -> bool {
+        matches!(self.body, InstructionBody::DataProcessing(dp) if dp.set_condition_codes)
+    }
+
+    /// `BL`, which returns to the next instruction once the subroutine finishes.
+    pub fn is_call(&self) -> bool {
+        matches!(self.body, InstructionBody::Branch(branch) if branch.link)
+    }
+
+    /// A branch, or a write to the PC such as `MOV pc, lr`.
+    pub fn is_branch(&self) -> bool {
+        match self.body {
+            InstructionBody::Branch(_) => true,
+            InstructionBody::DataProcessing(dp) => dp.dest == PROGRAM_COUNTER && !dp.opcode.is_comparison(),
+            InstructionBody::SupervisorCall(_) => false,
+        }
+    }
+
+    /// A branch or write to the PC which has a condition, such as `BNE loop` or `MOVNE pc, lr`, so can go either way.
+    pub fn is_conditional_branch(&self) -> bool {
+        self.is_branch() && self.condition != Condition::AL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::{assemble, assemble_all, symbol_table}, machine::Machine, Register};
+
+    #[test]
+    fn predicts_branch() {
+        let src = "cmp r1, #1\nloop:\nbleq loop";
+        let mut machine = Machine::default();
+        assemble(src).unwrap().serialise(&mut machine.ram);
+        let source_map = assemble_all(src).source_map();
+        let symbols = symbol_table(src);
+
+        machine.registers[1] = 1;
+        machine.step().unwrap();
+
+        let readout = machine.current_instruction(&source_map, &symbols).unwrap();
+        assert_eq!(readout.disassembly, "BLEQ loop");
+        assert_eq!(readout.line, Some(2));
+        assert!(readout.condition_passes);
+        assert_eq!(readout.writes, [Register(14), Register(15)]);
+        assert_eq!(readout.next_pc, Some(4));
+    }
+
+    #[test]
+    fn predicts_without_running() {
+        let src = "add r1, r2, r3, lsl r2\nsvc #2\nadd pc, lr, r1, lsl #2";
+        let mut machine = Machine::default();
+        assemble(src).unwrap().serialise(&mut machine.ram);
+        let source_map = assemble_all(src).source_map();
+        let symbols = symbol_table(src);
+
+        let readout = machine.current_instruction(&source_map, &symbols).unwrap();
+        assert_eq!(readout.reads, [Register(2), Register(3)]);
+        assert_eq!(readout.next_pc, Some(4));
+        machine.step().unwrap();
+
+        // Waits for input, so stays put, until there is some
+        assert_eq!(machine.current_instruction(&source_map, &symbols).unwrap().next_pc, Some(4));
+        machine.console.push_input(b"x");
+        assert_eq!(machine.current_instruction(&source_map, &symbols).unwrap().next_pc, Some(8));
+        machine.step().unwrap();
+
+        machine.registers[14] = 0x40;
+        machine.registers[1] = 3;
+        let readout = machine.current_instruction(&source_map, &symbols).unwrap();
+        machine.step().unwrap();
+        assert_eq!(readout.next_pc, Some(0x4c));
+        assert_eq!(readout.next_pc, Some(machine.pc()));
+    }
+
+    #[test]
+    fn predicts_reads_of_the_pc() {
+        let src = "add pc, pc, #4";
+        let mut machine = Machine::default();
+        assemble(src).unwrap().serialise(&mut machine.ram);
+
+        let readout = machine.current_instruction(&assemble_all(src).source_map(), &symbol_table(src)).unwrap();
+        machine.step().unwrap();
+        assert_eq!(readout.next_pc, Some(12));
+        assert_eq!(readout.next_pc, Some(machine.pc()));
+    }
+}
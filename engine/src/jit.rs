@@ -0,0 +1,581 @@
+use anyhow::Result;
+use wasm_encoder::{
+    BlockType, CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection, ImportSection, InstructionSink, MemArg, MemoryType, Module,
+    TypeSection, ValType,
+};
+
+use crate::{
+    blocks::{translate, Block, OpKind, Operand, MAX_BLOCK_LEN},
+    machine::Machine,
+    Condition, DataProcessingOpcode, Flags, Shift, ShiftAmount, ShiftType,
+};
+
+/// Times a block has to be reached before it's compiled, so code which only runs once doesn't pay for compiling it.
+pub const HOT_THRESHOLD: u32 = 16;
+
+// The layout of the memory a compiled block runs on, which the host copies the machine state in and out of
+/// R0 to R15 as little endian words
+const REGISTERS: u64 = 0;
+/// N, Z, C and V as one word each, 0 or 1
+const FLAGS: u64 = 64;
+/// One byte per instruction of the block, set if its condition passed, for metrics and profiling
+const PASSED: u64 = 80;
+const STATE_SIZE: usize = PASSED as usize + MAX_BLOCK_LEN;
+
+const N: u64 = 0;
+const Z: u64 = 1;
+const C: u64 = 2;
+const V: u64 = 3;
+
+/// Somewhere to instantiate and call the modules [`Jit`] generates.
+/// Each module imports its memory as `env.memory`, of one page, and exports a `run` function taking and returning nothing.
+pub trait WasmRuntime {
+    type Module;
+
+    fn instantiate(&mut self, bytes: &[u8]) -> Result<Self::Module>;
+
+    /// Copies `state` into the module's memory, calls `run`, then copies it back out.
+    fn call(&mut self, module: &mut Self::Module, state: &mut [u8]) -> Result<()>;
+}
+
+/// Runs a [`Machine`], compiling blocks to WebAssembly once they're hot and interpreting everything else.
+pub struct Jit<R: WasmRuntime> {
+    pub runtime: R,
+    pub threshold: u32,
+    /// Indexed by word address
+    entries: Vec<Entry<R::Module>>,
+}
+
+struct Entry<M> {
+    visits: u32,
+    compiled: Option<(Block, M)>,
+}
+
+impl<M> Default for Entry<M> {
+    fn default() -> Self {
+        Self { visits: 0, compiled: None }
+    }
+}
+
+impl<R: WasmRuntime> Jit<R> {
+    pub fn new(runtime: R) -> Self {
+        Self { runtime, threshold: HOT_THRESHOLD, entries: Vec::new() }
+    }
+
+    /// Like [`Machine::run`], but runs compiled blocks where it can.
+    pub fn run(&mut self, machine: &mut Machine, max_steps: u64) -> Result<u64> {
+        machine.run_blocks(max_steps, |machine, budget| self.run_compiled(machine, budget))
+    }
+
+    /// Runs the compiled block at the PC if there is one, or it's just become hot, returning how many instructions it ran.
+    fn run_compiled(&mut self, machine: &mut Machine, budget: u64) -> Option<u64> {
+        let pc = machine.pc();
+        if !pc.is_multiple_of(4) || pc as usize >= machine.ram.len() {
+            return None;
+        }
+
+        let index = pc as usize / 4;
+        if index >= self.entries.len() {
+            self.entries.resize_with(index + 1, Entry::default);
+        }
+        let entry = &mut self.entries[index];
+
+        // Modified code has to warm up again before it's recompiled
+        let stale = entry.compiled.as_ref().is_some_and(|(block, _)| machine.ram.get(pc as usize..pc as usize + block.words.len()) != Some(&block.words));
+        if stale {
+            *entry = Entry::default();
+        }
+
+        if entry.compiled.is_none() {
+            entry.visits += 1;
+            if entry.visits < self.threshold {
+                return None;
+            }
+
+            let block = translate(&machine.ram, pc);
+            let module = block.as_ref().and_then(|block| self.runtime.instantiate(&compile(block)).ok());
+            match block.zip(module) {
+                Some(compiled) => entry.compiled = Some(compiled),
+                // Try again once it's been reached as often again, as the code may have changed by then
+                None => {
+                    entry.visits = 0;
+                    return None;
+                }
+            }
+        }
+
+        let (block, module) = entry.compiled.as_mut()?;
+        if block.ops.len() as u64 > budget {
+            return None;
+        }
+
+        let mut state = [0; STATE_SIZE];
+        for (dest, register) in state.chunks_mut(4).zip(machine.registers) {
+            dest.copy_from_slice(&register.to_le_bytes());
+        }
+        let flags = u8::from(machine.flags);
+        for (flag, bit) in [N, Z, C, V].into_iter().zip([3, 2, 1, 0]) {
+            state[(FLAGS + flag * 4) as usize] = (flags >> bit) & 1;
+        }
+
+        // The state is only copied back on success, so a block which fails leaves the machine for the interpreter
+        if self.runtime.call(module, &mut state).is_err() {
+            *entry = Entry::default();
+            return None;
+        }
+
+        for (register, word) in machine.registers.iter_mut().zip(state.chunks(4)) {
+            *register = u32::from_le_bytes(word.try_into().unwrap());
+        }
+        let flag = |flag: u64| state[(FLAGS + flag * 4) as usize] & 1;
+        machine.flags = Flags::from((flag(N) << 3) | (flag(Z) << 2) | (flag(C) << 1) | flag(V));
+
+        for (i, op) in block.ops.iter().enumerate() {
+            let condition_passed = state[PASSED as usize + i] != 0;
+            machine.metrics.record(&machine.cycle_model, &op.instruction, condition_passed);
+            if let Some(profiler) = &mut machine.profiler {
+                profiler.record(op.addr, &op.instruction, condition_passed);
+            }
+        }
+
+        Some(block.ops.len() as u64)
+    }
+}
+
+/// Compiles the block starting at `addr` to a WebAssembly module, or `None` if it has to be interpreted.
+pub fn compile_block(ram: &[u8], addr: u32) -> Option<Vec<u8>> {
+    translate(ram, addr).map(|block| compile(&block))
+}
+
+// Locals of the generated function
+const CONDITION_PASSED: u32 = 0;
+const LHS: u32 = 1;
+const RHS: u32 = 2;
+const RESULT: u32 = 3;
+const CARRY: u32 = 4;
+const OVERFLOW: u32 = 5;
+const ADDEND_A: u32 = 6;
+const ADDEND_B: u32 = 7;
+const CARRY_IN: u32 = 8;
+const SHIFT_AMOUNT: u32 = 9;
+const SHIFT_INPUT: u32 = 10;
+const UNSIGNED_SUM: u32 = 11;
+const SIGNED_SUM: u32 = 12;
+
+fn compile(block: &Block) -> Vec<u8> {
+    let mut types = TypeSection::new();
+    types.ty().function([], []);
+
+    let mut imports = ImportSection::new();
+    imports.import("env", "memory", EntityType::Memory(MemoryType { minimum: 1, maximum: None, memory64: false, shared: false, page_size_log2: None }));
+
+    let mut functions = FunctionSection::new();
+    functions.function(0);
+
+    let mut exports = ExportSection::new();
+    exports.export("run", ExportKind::Func, 0);
+
+    let mut function = Function::new([(11, ValType::I32), (2, ValType::I64)]);
+    let f = &mut function.instructions();
+
+    for (i, op) in block.ops.iter().enumerate() {
+        // The PC points at the next instruction unless this one writes it
+        set_register(f, 15, |f| {
+            f.i32_const((op.addr + 4) as i32);
+        });
+
+        condition(f, op.condition);
+        f.local_set(CONDITION_PASSED);
+        f.i32_const(0).local_get(CONDITION_PASSED).i32_store8(mem_arg(PASSED + i as u64, 0));
+
+        f.local_get(CONDITION_PASSED).if_(BlockType::Empty);
+        match op.kind {
+            OpKind::DataProcessing { opcode, dest, set_flags, lhs, rhs } => {
+                // While the operands are evaluated the PC reads as this instruction's address plus 8
+                set_register(f, 15, |f| {
+                    f.i32_const(op.addr.wrapping_add(8) as i32);
+                });
+                operand(f, rhs);
+                f.local_set(RHS);
+                register(f, lhs);
+                f.local_set(LHS);
+                set_register(f, 15, |f| {
+                    f.i32_const((op.addr + 4) as i32);
+                });
+
+                alu(f, opcode);
+
+                if let Some(dest) = dest {
+                    set_register(f, dest, |f| {
+                        f.local_get(RESULT);
+                    });
+                }
+                if set_flags {
+                    set_flag(f, N, |f| {
+                        f.local_get(RESULT).i32_const(31).i32_shr_u();
+                    });
+                    set_flag(f, Z, |f| {
+                        f.local_get(RESULT).i32_eqz();
+                    });
+                    set_flag(f, C, |f| {
+                        f.local_get(CARRY);
+                    });
+                    set_flag(f, V, |f| {
+                        f.local_get(OVERFLOW);
+                    });
+                }
+            }
+            OpKind::Branch { link, target } => {
+                if link {
+                    set_register(f, 14, |f| {
+                        f.i32_const((op.addr + 4) as i32);
+                    });
+                }
+                set_register(f, 15, |f| {
+                    f.i32_const(target as i32);
+                });
+            }
+        }
+        f.end();
+    }
+    f.end();
+
+    let mut code = CodeSection::new();
+    code.function(&function);
+
+    let mut module = Module::new();
+    module.section(&types).section(&imports).section(&functions).section(&exports).section(&code);
+    module.finish()
+}
+
+fn mem_arg(offset: u64, align: u32) -> MemArg {
+    MemArg { offset, align, memory_index: 0 }
+}
+
+fn register(f: &mut InstructionSink, register: usize) {
+    f.i32_const(0).i32_load(mem_arg(REGISTERS + register as u64 * 4, 2));
+}
+
+fn set_register(f: &mut InstructionSink, register: usize, value: impl FnOnce(&mut InstructionSink)) {
+    f.i32_const(0);
+    value(f);
+    f.i32_store(mem_arg(REGISTERS + register as u64 * 4, 2));
+}
+
+fn flag(f: &mut InstructionSink, flag: u64) {
+    f.i32_const(0).i32_load(mem_arg(FLAGS + flag * 4, 2));
+}
+
+fn set_flag(f: &mut InstructionSink, flag: u64, value: impl FnOnce(&mut InstructionSink)) {
+    f.i32_const(0);
+    value(f);
+    f.i32_store(mem_arg(FLAGS + flag * 4, 2));
+}
+
+/// Leaves whether `condition` passes on the stack, as [`Condition::matches`] would decide.
+fn condition(f: &mut InstructionSink, condition: Condition) {
+    let n_equals_v = |f: &mut InstructionSink| {
+        flag(f, N);
+        flag(f, V);
+        f.i32_eq();
+    };
+
+    match condition {
+        Condition::EQ => flag(f, Z),
+        Condition::NE => {
+            flag(f, Z);
+            f.i32_eqz();
+        }
+        Condition::CS => flag(f, C),
+        Condition::CC => {
+            flag(f, C);
+            f.i32_eqz();
+        }
+        Condition::MI => flag(f, N),
+        Condition::PL => {
+            flag(f, N);
+            f.i32_eqz();
+        }
+        Condition::VS => flag(f, V),
+        Condition::VC => {
+            flag(f, V);
+            f.i32_eqz();
+        }
+        Condition::HI => {
+            flag(f, C);
+            flag(f, Z);
+            f.i32_eqz().i32_and();
+        }
+        Condition::LS => {
+            flag(f, C);
+            f.i32_eqz();
+            flag(f, Z);
+            f.i32_or();
+        }
+        Condition::GE => n_equals_v(f),
+        Condition::LT => {
+            n_equals_v(f);
+            f.i32_eqz();
+        }
+        Condition::GT => {
+            flag(f, Z);
+            f.i32_eqz();
+            n_equals_v(f);
+            f.i32_and();
+        }
+        Condition::LE => {
+            flag(f, Z);
+            n_equals_v(f);
+            f.i32_eqz().i32_or();
+        }
+        Condition::AL => {
+            f.i32_const(1);
+        }
+    }
+}
+
+/// Leaves the value of the second operand on the stack, shifting as [`Shift::eval`] would.
+fn operand(f: &mut InstructionSink, operand: Operand) {
+    let (input, shift) = match operand {
+        Operand::Immediate(value) => {
+            f.i32_const(value as i32);
+            return;
+        }
+        Operand::Register(input) => return register(f, input),
+        Operand::Shifted { register, shift } => (register, shift),
+    };
+    let Shift { ty, amount } = shift;
+
+    register(f, input);
+    f.local_set(SHIFT_INPUT);
+    match amount {
+        ShiftAmount::Immediate(amount) => {
+            f.i32_const(amount as i32);
+        }
+        // Only the bottom byte of the register is used
+        ShiftAmount::Register(amount) => {
+            register(f, amount.0 as usize);
+            f.i32_const(0xFF).i32_and();
+        }
+    }
+    f.local_set(SHIFT_AMOUNT);
+
+    // WebAssembly takes shift amounts modulo 32, so larger ones are handled here
+    f.local_get(SHIFT_INPUT);
+    match ty {
+        ShiftType::LogicalLeft | ShiftType::LogicalRight => {
+            f.local_get(SHIFT_AMOUNT);
+            if ty == ShiftType::LogicalLeft {
+                f.i32_shl();
+            } else {
+                f.i32_shr_u();
+            }
+            f.i32_const(0).local_get(SHIFT_AMOUNT).i32_const(32).i32_lt_u().select();
+        }
+        ShiftType::ArithmeticRight => {
+            f.local_get(SHIFT_AMOUNT).i32_const(31).local_get(SHIFT_AMOUNT).i32_const(31).i32_lt_u().select().i32_shr_s();
+        }
+        ShiftType::RotateRight => {
+            f.local_get(SHIFT_AMOUNT).i32_rotr();
+        }
+    }
+}
+
+/// Sets the result, carry and overflow locals from the operand locals, as [`alu`](crate::emulator::alu) would.
+fn alu(f: &mut InstructionSink, opcode: DataProcessingOpcode) {
+    let logical = |f: &mut InstructionSink, op: fn(&mut InstructionSink)| {
+        op(f);
+        f.local_set(RESULT);
+        // Logical operations leave the carry and overflow flags alone
+        flag(f, C);
+        f.local_set(CARRY);
+        flag(f, V);
+        f.local_set(OVERFLOW);
+    };
+
+    // Each arithmetic operation is `a + b + carry in`, which subtraction gets to by inverting `b`.
+    // A carry in of `None` comes from the carry flag.
+    let (a, b, invert, carry_in) = match opcode {
+        DataProcessingOpcode::AND | DataProcessingOpcode::TST => return logical(f, |f| {
+            f.local_get(LHS).local_get(RHS).i32_and();
+        }),
+        DataProcessingOpcode::EOR | DataProcessingOpcode::TEQ => return logical(f, |f| {
+            f.local_get(LHS).local_get(RHS).i32_xor();
+        }),
+        DataProcessingOpcode::ORR => return logical(f, |f| {
+            f.local_get(LHS).local_get(RHS).i32_or();
+        }),
+        DataProcessingOpcode::MOV => return logical(f, |f| {
+            f.local_get(RHS);
+        }),
+        DataProcessingOpcode::BIC => return logical(f, |f| {
+            f.local_get(LHS).local_get(RHS).i32_const(-1).i32_xor().i32_and();
+        }),
+        DataProcessingOpcode::MVN => return logical(f, |f| {
+            f.local_get(RHS).i32_const(-1).i32_xor();
+        }),
+        DataProcessingOpcode::ADD | DataProcessingOpcode::CMN => (LHS, RHS, false, Some(0)),
+        DataProcessingOpcode::ADC => (LHS, RHS, false, None),
+        DataProcessingOpcode::SUB | DataProcessingOpcode::CMP => (LHS, RHS, true, Some(1)),
+        DataProcessingOpcode::SBC => (LHS, RHS, true, None),
+        DataProcessingOpcode::RSB => (RHS, LHS, true, Some(1)),
+        DataProcessingOpcode::RSC => (RHS, LHS, true, None),
+    };
+
+    f.local_get(a).local_set(ADDEND_A);
+    f.local_get(b);
+    if invert {
+        f.i32_const(-1).i32_xor();
+    }
+    f.local_set(ADDEND_B);
+    match carry_in {
+        Some(carry_in) => {
+            f.i32_const(carry_in);
+        }
+        None => flag(f, C),
+    }
+    f.local_set(CARRY_IN);
+
+    f.local_get(ADDEND_A).i64_extend_i32_u().local_get(ADDEND_B).i64_extend_i32_u().i64_add();
+    f.local_get(CARRY_IN).i64_extend_i32_u().i64_add().local_set(UNSIGNED_SUM);
+    f.local_get(ADDEND_A).i64_extend_i32_s().local_get(ADDEND_B).i64_extend_i32_s().i64_add();
+    f.local_get(CARRY_IN).i64_extend_i32_u().i64_add().local_set(SIGNED_SUM);
+
+    f.local_get(UNSIGNED_SUM).i32_wrap_i64().local_set(RESULT);
+    f.local_get(UNSIGNED_SUM).i64_const(32).i64_shr_u().i64_const(0).i64_ne().local_set(CARRY);
+    f.local_get(SIGNED_SUM).local_get(RESULT).i64_extend_i32_s().i64_ne().local_set(OVERFLOW);
+}
+
+/// Runs generated modules with the browser's own WebAssembly engine.
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug, Default)]
+pub struct BrowserRuntime;
+
+#[cfg(target_arch = "wasm32")]
+pub struct BrowserModule {
+    memory: js_sys::WebAssembly::Memory,
+    run: js_sys::Function,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl WasmRuntime for BrowserRuntime {
+    type Module = BrowserModule;
+
+    fn instantiate(&mut self, bytes: &[u8]) -> Result<BrowserModule> {
+        use js_sys::{Function, Object, Reflect, Uint8Array, WebAssembly};
+        use wasm_bindgen::{JsCast, JsValue};
+
+        let js_error = |e: JsValue| anyhow::anyhow!("{e:?}");
+
+        let descriptor = Object::new();
+        Reflect::set(&descriptor, &"initial".into(), &1.into()).map_err(js_error)?;
+        let memory = WebAssembly::Memory::new(&descriptor).map_err(js_error)?;
+
+        let env = Object::new();
+        Reflect::set(&env, &"memory".into(), &memory).map_err(js_error)?;
+        let imports = Object::new();
+        Reflect::set(&imports, &"env".into(), &env).map_err(js_error)?;
+
+        let module = WebAssembly::Module::new(&Uint8Array::from(bytes)).map_err(js_error)?;
+        let instance = WebAssembly::Instance::new(&module, &imports).map_err(js_error)?;
+        let run = Reflect::get(&instance.exports(), &"run".into()).map_err(js_error)?.dyn_into::<Function>().map_err(js_error)?;
+
+        Ok(BrowserModule { memory, run })
+    }
+
+    fn call(&mut self, module: &mut BrowserModule, state: &mut [u8]) -> Result<()> {
+        let view = js_sys::Uint8Array::new(&module.memory.buffer()).subarray(0, state.len() as u32);
+        view.copy_from(state);
+        module.run.call0(&wasm_bindgen::JsValue::UNDEFINED).map_err(|e| anyhow::anyhow!("{e:?}"))?;
+        view.copy_to(state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Result};
+    use proptest::{collection::vec, prelude::any, proptest};
+    use wasmi::{Engine, Linker, Memory, MemoryType, Module, Store, TypedFunc};
+
+    use crate::{
+        differential::{differential, program_ram, random_program, PROGRAM_INPUT},
+        machine::Machine,
+        Instruction,
+    };
+
+    use super::{Jit, WasmRuntime};
+
+    /// Runs the generated modules natively, standing in for the browser.
+    struct Wasmi {
+        engine: Engine,
+        store: Store<()>,
+    }
+
+    impl Default for Wasmi {
+        fn default() -> Self {
+            let engine = Engine::default();
+            Self { store: Store::new(&engine, ()), engine }
+        }
+    }
+
+    impl WasmRuntime for Wasmi {
+        type Module = (Memory, TypedFunc<(), ()>);
+
+        fn instantiate(&mut self, bytes: &[u8]) -> Result<Self::Module> {
+            let module = Module::new(&self.engine, bytes)?;
+            let memory = MemoryType::new(1, None).and_then(|ty| Memory::new(&mut self.store, ty)).map_err(|e| anyhow!("{e}"))?;
+
+            let mut linker = Linker::new(&self.engine);
+            linker.define("env", "memory", memory)?;
+            let instance = linker.instantiate(&mut self.store, &module)?.start(&mut self.store)?;
+
+            Ok((memory, instance.get_typed_func(&self.store, "run")?))
+        }
+
+        fn call(&mut self, (memory, run): &mut Self::Module, state: &mut [u8]) -> Result<()> {
+            memory.write(&mut self.store, 0, state).map_err(|e| anyhow!("{e}"))?;
+            run.call(&mut self.store, ())?;
+            memory.read(&self.store, 0, state).map_err(|e| anyhow!("{e}"))?;
+            Ok(())
+        }
+    }
+
+    /// Runs a machine with a new JIT, which compiles blocks once they've been visited `threshold` times.
+    fn run_jit(threshold: u32) -> impl FnOnce(&mut Machine, u64) -> Result<u64> {
+        move |machine, max_steps| {
+            let mut jit = Jit::new(Wasmi::default());
+            jit.threshold = threshold;
+            jit.run(machine, max_steps)
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let ram = program_ram();
+
+        for max_steps in [0, 5, 40, 1000] {
+            for threshold in [1, 2, super::HOT_THRESHOLD] {
+                differential(&ram, [0; 16], 0, PROGRAM_INPUT, max_steps, run_jit(threshold));
+            }
+        }
+
+        // The loop is hot enough to have been compiled
+        let mut machine = Machine::new(ram.len());
+        machine.ram.copy_from_slice(&ram);
+        machine.console.push_input(PROGRAM_INPUT);
+        let mut jit = Jit::new(Wasmi::default());
+        jit.run(&mut machine, 1000).unwrap();
+        assert!(jit.entries.iter().any(|entry| entry.compiled.is_some()));
+    }
+
+    proptest! {
+        #[test]
+        fn random_programs_match_interpreter(program in vec(any::<Instruction>(), 1..24), registers in any::<[u32; 16]>(), flags in 0..16u8, max_steps in 0..200u64) {
+            let (ram, registers) = random_program(&program, registers);
+
+            // Compile everything, so every instruction goes through the generated code
+            differential(&ram, registers, flags, b"7\n", max_steps, run_jit(1));
+        }
+    }
+}
@@ -1,58 +1,138 @@
-use std::collections::HashMap;
+#![allow(clippy::result_large_err)]
+
+use std::{collections::HashMap, fmt::Display, str::FromStr};
 
 use assembler::assemble;
 use log::info;
+use machine::Machine;
 use num_derive::FromPrimitive;
 use serde::Serialize;
 use strum_macros::EnumIter;
+use utf16::{ToUtf16, Utf16Map};
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
 
 #[cfg(test)] use proptest_derive::Arbitrary;
 #[cfg(test)] use proptest::prelude::{any, Strategy, BoxedStrategy};
 
+mod analysis;
 mod assembler;
+pub mod blocks;
+pub mod completion;
+pub mod console;
 pub mod macros;
 pub mod parser;
+pub mod profile;
+pub mod quickfix;
 mod serialise;
 mod emulator;
 mod deserialise;
-
-#[wasm_bindgen]
-pub fn lint(src: &str) -> JsValue {
-    let parsed = assembler::parse_per_line(src);
-    let labels = assembler::get_lint_labels(&parsed);
-    let source_map = assembler::gen_source_map(&parsed);
-
-    let lints = parsed
-        .into_iter()
-        .enumerate()
-        .filter_map(|(i, line)| {
-            let line = line.and_then(|mut line| assembler::lint_line(line.next().unwrap(), &labels));
-
-            match line {
-                Ok(_) => None,
-                Err(e) => {
-                    let span = match e.location {
-                        pest::error::InputLocation::Pos(p) => (p, p),
-                        pest::error::InputLocation::Span(p) => p,
-                    };
-
-                    Some(Lint {
-                        err: e.clone().with_path("program.as").to_string(),
-                        from: span.0 as u32,
-                        to: span.1 as u32,
-                        line: i as u32
-                    })
-                },
+pub mod decode_cache;
+#[cfg(test)]
+mod differential;
+pub mod disassembler;
+pub mod explain;
+pub mod format;
+pub mod grader;
+pub mod jit;
+pub mod highlight;
+pub mod hover;
+pub mod inspect;
+pub mod machine;
+pub mod metrics;
+pub mod navigation;
+mod snapshot;
+pub mod trace;
+pub mod utf16;
+
+pub use assembler::{assemble_all, AssembledInstruction, Assembly, Diagnostic, SourceSpan, Symbol, SymbolIndex};
+
+/// Assembly errors for every line of `src` followed by warnings from static analysis, along with the map from address to source line.
+pub fn lint_source(src: &str) -> Lints {
+    let assembly = assembler::assemble_all(src);
+
+    let lints = assembly
+        .diagnostics
+        .iter()
+        .map(|diagnostic| {
+            let code = match diagnostic.error.variant {
+                pest::error::ErrorVariant::ParsingError { .. } => LintCode::SyntaxError,
+                pest::error::ErrorVariant::CustomError { .. } => LintCode::InvalidInstruction,
+            };
+
+            Lint {
+                fixes: diagnostic.fixes.clone(),
+                ..Lint::new(code, diagnostic.span, &diagnostic.error)
             }
         })
-        // .map(|lint| serde_wasm_bindgen::to_value(&lint).unwrap())
+        .chain(analysis::analyse(src, &assembly))
         .collect::<Vec<_>>();
 
-    serde_wasm_bindgen::to_value(&Lints {
+    Lints {
         lints,
-        source_map
-    }).unwrap()
+        source_map: assembly.source_map()
+    }
+}
+
+/// Lints for `src`, with every offset in UTF-16 code units.
+#[wasm_bindgen]
+pub fn lint(src: &str) -> JsValue {
+    let Lints { lints, source_map } = lint_source(src);
+    let lints = Lints { lints: lints.to_utf16(&Utf16Map::new(src)), source_map };
+
+    serde_wasm_bindgen::to_value(&lints).unwrap()
+}
+
+/// Semantic highlighting for `src` in UTF-16 offsets, following exactly how the assembler parses it.
+#[wasm_bindgen]
+pub fn semantic_tokens(src: &str) -> JsValue {
+    serde_wasm_bindgen::to_value(&highlight::semantic_tokens(src).to_utf16(&Utf16Map::new(src))).unwrap()
+}
+
+/// Reformats `src`. Any options not given in `options` take their default values.
+#[wasm_bindgen]
+pub fn format(src: &str, options: JsValue) -> Result<String, JsValue> {
+    let options = if options.is_undefined() || options.is_null() {
+        format::FormatOptions::default()
+    } else {
+        serde_wasm_bindgen::from_value(options)?
+    };
+
+    Ok(format::format(src, &options))
+}
+
+/// Markdown for the editor hover at UTF-16 `offset` in `src`.
+#[wasm_bindgen]
+pub fn hover(src: &str, offset: u32) -> Option<String> {
+    hover::hover(src, Utf16Map::new(src).byte(offset))
+}
+
+/// Editor completions for the word ending at UTF-16 `offset` in `src`.
+#[wasm_bindgen]
+pub fn complete(src: &str, offset: u32) -> JsValue {
+    let map = Utf16Map::new(src);
+    serde_wasm_bindgen::to_value(&completion::complete(src, map.byte(offset)).to_utf16(&map)).unwrap()
+}
+
+/// Where the label under the cursor at UTF-16 `offset` is defined, or `undefined`.
+#[wasm_bindgen]
+pub fn definition(src: &str, offset: u32) -> JsValue {
+    let map = Utf16Map::new(src);
+    serde_wasm_bindgen::to_value(&navigation::definition(src, map.byte(offset)).to_utf16(&map)).unwrap()
+}
+
+#[wasm_bindgen]
+pub fn references(src: &str, offset: u32) -> JsValue {
+    let map = Utf16Map::new(src);
+    serde_wasm_bindgen::to_value(&navigation::references(src, map.byte(offset)).to_utf16(&map)).unwrap()
+}
+
+/// Text edits renaming the label under the cursor at UTF-16 `offset` to `new_name`.
+#[wasm_bindgen]
+pub fn rename(src: &str, offset: u32, new_name: &str) -> Result<JsValue, JsValue> {
+    let map = Utf16Map::new(src);
+    navigation::rename(src, map.byte(offset), new_name)
+        .map(|edits| serde_wasm_bindgen::to_value(&edits.to_utf16(&map)).unwrap())
+        .map_err(|e| JsValue::from_str(&e.to_string()))
 }
 
 #[wasm_bindgen]
@@ -64,39 +144,229 @@ pub fn assemble_into_ram(src: &str, ram: &mut [u8]) {
     }
 }
 
+/// Runs the instruction at the PC. `input` is console input the program hasn't read yet, as for [`run`].
 #[wasm_bindgen]
-pub fn step(ram: &mut [u8], registers: &mut [u32], flags: u8) -> JsValue {
+pub fn step(ram: &mut [u8], registers: &mut [u32], flags: u8, input: &[u8]) -> JsValue {
     setup_logging();
-    let mut state = ProcessorState {
-        flags: Flags::from(flags),
-        ram,
-        registers: registers.try_into().unwrap()
-    };
+    let mut machine = Machine::from_parts(ram, registers, flags);
+    machine.console.push_input(input);
+
+    let res = machine.step();
+    serde_wasm_bindgen::to_value(&ExecutionResult::new(&mut machine, res, ram, registers)).unwrap()
+}
+
+/// Disassembles the aligned words of `ram` in `from..to`, naming branch targets after labels in `src`.
+#[wasm_bindgen]
+pub fn disassemble(ram: &[u8], from: u32, to: u32, src: &str) -> JsValue {
+    let symbols = assembler::symbol_table(src);
+    serde_wasm_bindgen::to_value(&disassembler::disassemble(ram, from..to, &symbols)).unwrap()
+}
+
+/// Describes the instruction at the PC without executing it.
+#[wasm_bindgen]
+pub fn current_instruction(ram: &[u8], registers: &[u32], flags: u8, src: &str) -> Result<JsValue, JsValue> {
+    let machine = Machine::from_parts(ram, registers, flags);
+    let source_map = assembler::assemble_all(src).source_map();
+    let symbols = assembler::symbol_table(src);
 
-    serde_wasm_bindgen::to_value(&match state.step() {
-        Ok(_) => ExecutionResult { message: "".into(), flags: state.flags.into() },
-        Err(e) => ExecutionResult { message: e.to_string(), flags: state.flags.into() },
-    }).unwrap()
+    let readout = machine.current_instruction(&source_map, &symbols)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    Ok(serde_wasm_bindgen::to_value(&readout).unwrap())
+}
+
+/// Explains the instruction at the PC in plain English.
+#[wasm_bindgen]
+pub fn explain(ram: &[u8], registers: &[u32], flags: u8, src: &str) -> Result<String, JsValue> {
+    Machine::from_parts(ram, registers, flags)
+        .explain(&assembler::symbol_table(src))
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Runs a copy of the machine for up to `max_steps` instructions, returning how often each source line ran, for a heatmap in the gutter.
+/// Console input reads as already finished, since a copy can't wait for the user.
+#[wasm_bindgen]
+pub fn profile(ram: &[u8], registers: &[u32], flags: u8, src: &str, max_steps: u32) -> JsValue {
+    let mut machine = Machine::from_parts(ram, registers, flags);
+    machine.console.closed = true;
+    machine.profiler = Some(profile::Profile::new());
+
+    for _ in 0..max_steps {
+        if machine.halted() || machine.step().is_err() {
+            break;
+        }
+    }
+
+    let source_map = assembler::assemble_all(src).source_map();
+    let lines = machine.profiler.unwrap_or_default().lines(&source_map);
+    serde_wasm_bindgen::to_value(&lines).unwrap()
+}
+
+/// Runs up to `max_steps` instructions, compiling hot blocks to WebAssembly as it goes.
+/// Compiled blocks are kept between calls, and compiled again if the code they came from changes.
+///
+/// `input` is console input the program hasn't read yet. The run stops early if the program has to wait for more,
+/// and whatever it didn't read is returned to be passed back in, along with anything typed in the meantime.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn run(ram: &mut [u8], registers: &mut [u32], flags: u8, input: &[u8], max_steps: u32) -> JsValue {
+    thread_local! {
+        static JIT: std::cell::RefCell<jit::Jit<jit::BrowserRuntime>> = std::cell::RefCell::new(jit::Jit::new(jit::BrowserRuntime));
+    }
+
+    setup_logging();
+    let mut machine = Machine::from_parts(ram, registers, flags);
+    machine.console.push_input(input);
+
+    let res = JIT.with_borrow_mut(|jit| jit.run(&mut machine, max_steps as u64));
+    serde_wasm_bindgen::to_value(&ExecutionResult::new(&mut machine, res.map(drop), ram, registers)).unwrap()
+}
+
+/// Encodes the machine, including its breakpoints, as a base64 snapshot suitable for sharing in a URL.
+#[wasm_bindgen]
+pub fn save_state(ram: &[u8], registers: &[u32], flags: u8, breakpoints: &[u32], src: &str) -> String {
+    let mut machine = Machine::from_parts(ram, registers, flags);
+    machine.breakpoints = breakpoints.iter().copied().collect();
+    machine.save_state_base64(Some(src))
+}
+
+/// Restores a snapshot from [`save_state`] into `ram` and `registers`, returning the flags and breakpoints.
+#[wasm_bindgen]
+pub fn load_state(snapshot: &str, ram: &mut [u8], registers: &mut [u32], src: &str) -> Result<JsValue, JsValue> {
+    let mut machine = Machine::new(ram.len());
+    machine.load_state_base64(snapshot, Some(src))
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    ram.copy_from_slice(&machine.ram);
+    registers.copy_from_slice(&machine.registers);
+
+    Ok(serde_wasm_bindgen::to_value(&RestoredState {
+        flags: machine.flags.into(),
+        breakpoints: machine.breakpoints.into_iter().collect(),
+    }).unwrap())
+}
+
+#[derive(Serialize)]
+struct RestoredState {
+    flags: u8,
+    breakpoints: Vec<u32>,
 }
 
 #[derive(Serialize)]
 struct ExecutionResult {
     message: String,
-    flags: u8
+    flags: u8,
+    /// Console output written by the program
+    output: String,
+    /// Console input the program hasn't read yet
+    input: Vec<u8>,
+    /// The program is waiting for more console input before it can carry on
+    waiting: bool,
+    /// What ran during this call
+    metrics: metrics::Metrics,
+}
+
+impl ExecutionResult {
+    /// Copies `machine` back into the front end's `ram` and `registers`, and describes how running it went.
+    fn new(machine: &mut Machine, res: anyhow::Result<()>, ram: &mut [u8], registers: &mut [u32]) -> Self {
+        ram.copy_from_slice(&machine.ram);
+        registers.copy_from_slice(&machine.registers);
+
+        let message = match (res, machine.exit_code) {
+            (Err(e), _) => e.to_string(),
+            (Ok(_), Some(code)) => format!("The program has exited with code {code}"),
+            (Ok(_), None) => "".into(),
+        };
+
+        ExecutionResult {
+            message,
+            flags: machine.flags.into(),
+            output: String::from_utf8_lossy(&machine.console.take_output()).into_owned(),
+            input: machine.console.input.iter().copied().collect(),
+            waiting: machine.console.waiting,
+            metrics: machine.metrics,
+        }
+    }
 }
 
 #[derive(Serialize)]
-struct Lints {
-    lints: Vec<Lint>,
-    source_map: HashMap<u32, u32>
+pub struct Lints {
+    pub lints: Vec<Lint>,
+    pub source_map: HashMap<u32, u32>
 }
 
+/// A problem found in the source.
+///
+/// `from` and `to` are offsets into the whole source, the same as the offsets in `fixes`. They count bytes,
+/// except in the results of the wasm [`lint`], which converts every offset to UTF-16 code units for the editor.
 #[derive(Serialize)]
-struct Lint {
+pub struct Lint {
+    /// The full error report, including the offending line
     pub err: String,
+    pub message: String,
+    pub severity: Severity,
+    pub code: LintCode,
     pub line: u32,
     pub from: u32,
-    pub to: u32
+    pub to: u32,
+    pub fixes: Vec<quickfix::Fix>,
+}
+
+impl Lint {
+    /// A lint reporting `error` at `span` in `src`.
+    pub(crate) fn new(code: LintCode, span: SourceSpan, error: &pest::error::Error<parser::Rule>) -> Self {
+        Lint {
+            err: error.clone().with_path("program.as").to_string(),
+            message: error.variant.message().into_owned(),
+            severity: code.severity(),
+            code,
+            line: span.line,
+            from: span.from,
+            to: span.to,
+            fixes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Hint,
+}
+
+/// What a lint is about. These are serialised in kebab case and should not be renamed, so editors can refer to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LintCode {
+    /// The line doesn't match the grammar
+    SyntaxError,
+    /// The line parses but can't be assembled, e.g. an unknown opcode or out of range literal
+    InvalidInstruction,
+    UnusedLabel,
+    /// Code after an unconditional branch which nothing jumps to
+    UnreachableCode,
+    /// A data processing instruction other than `MOV PC, LR` with PC as its destination
+    WritesPc,
+    /// A register which is read somewhere but never written anywhere
+    UninitialisedRegister,
+    /// The last instruction doesn't branch away, so execution carries on into empty memory
+    FallsOffEnd,
+    /// A comparison whose flags are overwritten or never read by a conditional instruction
+    UnusedComparison,
+    /// A branch to an address outside the assembled instructions
+    BranchIntoData,
+}
+
+impl LintCode {
+    pub fn severity(&self) -> Severity {
+        match self {
+            LintCode::SyntaxError | LintCode::InvalidInstruction => Severity::Error,
+            LintCode::UnusedLabel => Severity::Hint,
+            _ => Severity::Warning,
+        }
+    }
 }
 
 pub fn setup_logging() {
@@ -117,7 +387,7 @@ pub struct ProcessorState<'a> {
     pub flags: Flags
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Flags {
     /// Negative
     n: bool,
@@ -135,17 +405,42 @@ impl From<u8> for Flags {
             n: (value >> 3) & 1 == 1,
             z: (value >> 2) & 1 == 1,
             c: (value >> 1) & 1 == 1,
-            v: (value >> 0) & 1 == 1,
+            v: value & 1 == 1,
         }
     }
 }
 
-impl Into<u8> for Flags {
-    fn into(self) -> u8 {
-          ((self.n as u8) << 3)
-        | ((self.z as u8) << 2)
-        | ((self.c as u8) << 1)
-        | ((self.v as u8) << 0)
+impl Display for Flags {
+    /// Set flags are shown in upper case, e.g. `nZCv`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (set, name) in [(self.n, 'n'), (self.z, 'z'), (self.c, 'c'), (self.v, 'v')] {
+            write!(f, "{}", if set { name.to_ascii_uppercase() } else { name })?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Flags {
+    type Err = anyhow::Error;
+
+    /// Flags written as they're displayed, e.g. `nZCv`
+    fn from_str(flags: &str) -> anyhow::Result<Self> {
+        if flags.len() != 4 || !flags.chars().zip("nzcv".chars()).all(|(flag, name)| flag.to_ascii_lowercase() == name) {
+            anyhow::bail!("Expected flags such as nZCv, with set flags in upper case");
+        }
+
+        let bits = flags.chars().fold(0u8, |bits, flag| (bits << 1) | flag.is_ascii_uppercase() as u8);
+        Ok(Flags::from(bits))
+    }
+}
+
+impl From<Flags> for u8 {
+    fn from(value: Flags) -> Self {
+          ((value.n as u8) << 3)
+        | ((value.z as u8) << 2)
+        | ((value.c as u8) << 1)
+        | (value.v as u8)
     }
 }
 
@@ -165,21 +460,22 @@ impl Program {
 // https://developer.arm.com/documentation/ddi0597/2024-12?lang=en
 // https://iitd-plos.github.io/col718/ref/arm-instructionset.pdf
 // https://peterhigginson.co.uk/ARMlite/doc.php
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub struct Instruction {
     condition: Condition,
     body: InstructionBody,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub enum InstructionBody {
     DataProcessing(DataProcessing),
-    Branch(Branch)
+    Branch(Branch),
+    SupervisorCall(SupervisorCall)
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub struct Branch {
     link: bool,
@@ -187,7 +483,15 @@ pub struct Branch {
     offset: u32
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// `SVC`, asking the host to perform the service numbered by `comment`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(test, derive(Arbitrary))]
+pub struct SupervisorCall {
+    #[cfg_attr(test, proptest(strategy = "any::<u32>().prop_map(|x| x & ((1 << 24) - 1))"))]
+    comment: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub struct DataProcessing {
     opcode: DataProcessingOpcode,
@@ -197,7 +501,7 @@ pub struct DataProcessing {
     operand: DataProcessingOperand,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub enum DataProcessingOperand {
     #[cfg_attr(test, proptest(strategy = "any::<(u8, u8)>().prop_map(|(a, b)| Self::Immediate{ rotate: a % 16, value: b })"))]
@@ -205,14 +509,14 @@ pub enum DataProcessingOperand {
     Register { shift: Shift, register: Register },
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub struct Shift {
     ty: ShiftType,
     amount: ShiftAmount
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub enum ShiftAmount {
     #[cfg_attr(test, proptest(strategy = "any::<u8>().prop_map(|x| Self::Immediate(x % 16))"))]
@@ -226,7 +530,7 @@ impl Default for ShiftAmount {
     }
 }
 
-#[derive(Debug, Clone, Copy, FromPrimitive, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, FromPrimitive, Default, PartialEq, Eq, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub enum ShiftType {
     #[default]
@@ -236,7 +540,7 @@ pub enum ShiftType {
     RotateRight
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
 pub struct Register(u8);
 
 #[cfg(test)]
@@ -252,7 +556,7 @@ impl proptest::prelude::Arbitrary for Register {
     type Strategy = BoxedStrategy<Self>;
 }
 
-#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Eq, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub enum DataProcessingOpcode {
     AND,
@@ -273,7 +577,7 @@ pub enum DataProcessingOpcode {
     MVN,
 }
 
-#[derive(Default, Debug, Clone, Copy, FromPrimitive, PartialEq, Eq, EnumIter)]
+#[derive(Default, Debug, Clone, Copy, FromPrimitive, PartialEq, Eq, EnumIter, Serialize)]
 #[cfg_attr(test, derive(Arbitrary))]
 pub enum Condition {
     EQ,
@@ -298,7 +602,48 @@ pub enum Condition {
 mod test {
     use proptest::proptest;
 
-    use crate::Instruction;
+    use crate::{assembler::{assemble, encode_immediate}, emulator::expand_immediate, DataProcessingOpcode, DataProcessingOperand, Instruction, InstructionBody, Register};
+
+    /// Encodings taken from the ARM reference manual
+    const REFERENCE_ENCODINGS: &[(&str, &[u32])] = &[
+        ("mov r1, #12", &[0xE3A0100C]),
+        ("add r2, r1, r3", &[0xE0812003]),
+        ("adds r2, r1, #1", &[0xE2912001]),
+        ("subne r0, r0, #1", &[0x12400001]),
+        ("cmp r1, #10", &[0xE351000A]),
+        ("teq r3, r4, ror #4", &[0xE1330264]),
+        ("mov r0, r1, lsl #2", &[0xE1A00101]),
+        ("mov r0, r1, lsr r2", &[0xE1A00231]),
+        ("sbc r5, r6, r7, asr #1", &[0xE0C650C7]),
+        ("mvn r0, #0", &[0xE3E00000]),
+        ("orr r0, r0, 0xFF000000", &[0xE38004FF]),
+        ("movgt pc, lr", &[0xC1A0F00E]),
+        ("loop:\nb loop", &[0xEAFFFFFE]),
+        ("bl next\nnext:\nmov r0, #0", &[0xEBFFFFFF, 0xE3A00000]),
+        ("svc #1", &[0xEF000001]),
+        ("svceq 0x123456", &[0x0F123456]),
+    ];
+
+    /// The form the assembler produces for an instruction, as several encodings can share the same text.
+    fn canonicalise(mut instruction: Instruction) -> Instruction {
+        if let InstructionBody::DataProcessing(dp) = &mut instruction.body {
+            if let DataProcessingOperand::Immediate { rotate, value } = dp.operand {
+                let (rotate, value) = encode_immediate(expand_immediate(rotate, value)).unwrap();
+                dp.operand = DataProcessingOperand::Immediate { rotate, value };
+            }
+
+            match dp.opcode {
+                DataProcessingOpcode::MOV | DataProcessingOpcode::MVN => dp.register = Register(0),
+                opcode if opcode.is_comparison() => {
+                    dp.dest = Register(0);
+                    dp.set_condition_codes = true;
+                },
+                _ => {}
+            }
+        }
+
+        instruction
+    }
 
     proptest! {
         #[test]
@@ -307,5 +652,37 @@ mod test {
             instruction.serialise(&mut dest);
             assert_eq!(Instruction::deserialise(&dest).unwrap(), instruction);
         }
+
+        #[test]
+        fn disassemble_round_trip(instruction: Instruction) {
+            let text = instruction.to_string();
+            let reassembled = assemble(&text)
+                .unwrap_or_else(|e| panic!("{text} failed to assemble: {e}"))
+                .instructions
+                .remove(0);
+
+            let (mut expected, mut actual) = ([0u8; 4], [0u8; 4]);
+            canonicalise(instruction).serialise(&mut expected);
+            reassembled.serialise(&mut actual);
+            assert_eq!(actual, expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn reference_encodings() {
+        for (src, expected) in REFERENCE_ENCODINGS {
+            let words = assemble(src)
+                .unwrap()
+                .instructions
+                .iter()
+                .map(|instruction| {
+                    let mut dest = [0u8; 4];
+                    instruction.serialise(&mut dest);
+                    u32::from_be_bytes(dest)
+                })
+                .collect::<Vec<_>>();
+
+            assert_eq!(&words, expected, "{src}");
+        }
     }
 }
@@ -0,0 +1,287 @@
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Context, Result};
+
+use crate::{blocks::{Backend, BlockCache}, console::Console, decode_cache::DecodeCache, explain::explain, metrics::{CycleModel, Metrics}, profile::Profile, trace::{AccessKind, MemoryAccess, RegisterChange, Trace, TraceEntry}, Flags, Instruction, InstructionBody, ProcessorState, Register};
+
+/// Default RAM size, matching `RAM_SIZE` in the front end.
+pub const RAM_SIZE: usize = 256 * 4;
+
+/// The index of `R0` to `R15`, `SP`, `LR` or `PC`, in any case.
+pub fn register_index(name: &str) -> Option<usize> {
+    let name = name.to_ascii_lowercase();
+    match name.as_str() {
+        "sp" => Some(13),
+        "lr" => Some(14),
+        "pc" => Some(15),
+        _ => name.strip_prefix('r').and_then(|index| index.parse().ok()).filter(|index| *index < 16),
+    }
+}
+
+/// The name of register `index`, using `SP`, `LR` and `PC` for the registers with special uses.
+pub fn register_name(index: usize) -> String {
+    Register(index as u8).to_string()
+}
+
+/// The registers and flags without memory, cheap enough to capture either side of every instruction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuState {
+    pub registers: [u32; 16],
+    pub flags: Flags,
+}
+
+/// A machine which owns its memory, unlike [`ProcessorState`] which borrows it from the caller.
+#[derive(Debug, Clone)]
+pub struct Machine {
+    pub ram: Vec<u8>,
+    pub registers: [u32; 16],
+    pub flags: Flags,
+    pub breakpoints: BTreeSet<u32>,
+    pub tracer: Option<Trace>,
+    pub console: Console,
+    /// Set once the program exits with `SVC #0`, to the code it passed in R0
+    pub exit_code: Option<u32>,
+    pub metrics: Metrics,
+    pub cycle_model: CycleModel,
+    pub profiler: Option<Profile>,
+    /// Instructions already decoded, or `None` to decode every instruction as it runs
+    pub decode_cache: Option<DecodeCache>,
+    /// Translated blocks, if [`Machine::run`] uses the [`Backend::Blocks`] backend
+    pub blocks: Option<BlockCache>,
+}
+
+/// Caches never change what a machine does, so two machines are equal whatever their caches hold.
+impl PartialEq for Machine {
+    fn eq(&self, other: &Self) -> bool {
+        // Destructured so that new fields have to be compared or ignored here explicitly
+        let Machine { ram, registers, flags, breakpoints, tracer, console, exit_code, metrics, cycle_model, profiler, decode_cache: _, blocks: _ } = self;
+
+        *ram == other.ram
+            && *registers == other.registers
+            && *flags == other.flags
+            && *breakpoints == other.breakpoints
+            && *tracer == other.tracer
+            && *console == other.console
+            && *exit_code == other.exit_code
+            && *metrics == other.metrics
+            && *cycle_model == other.cycle_model
+            && *profiler == other.profiler
+    }
+}
+
+impl Eq for Machine {}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new(RAM_SIZE)
+    }
+}
+
+impl Machine {
+    pub fn new(ram_size: usize) -> Self {
+        Self {
+            ram: vec![0; ram_size],
+            registers: [0; 16],
+            flags: Flags::default(),
+            breakpoints: BTreeSet::new(),
+            tracer: None,
+            console: Console::default(),
+            exit_code: None,
+            metrics: Metrics::default(),
+            cycle_model: CycleModel::default(),
+            profiler: None,
+            decode_cache: Some(DecodeCache::new()),
+            blocks: None,
+        }
+    }
+
+    pub fn with_backend(ram_size: usize, backend: Backend) -> Self {
+        Self {
+            blocks: (backend == Backend::Blocks).then(BlockCache::new),
+            ..Self::new(ram_size)
+        }
+    }
+
+    /// Copies the machine state held by the front end.
+    pub fn from_parts(ram: &[u8], registers: &[u32], flags: u8) -> Self {
+        let mut machine = Self::new(ram.len());
+        machine.ram.copy_from_slice(ram);
+        machine.registers.copy_from_slice(registers);
+        machine.flags = Flags::from(flags);
+        machine
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers,
+            flags: self.flags,
+        }
+    }
+
+    pub fn state(&mut self) -> ProcessorState<'_> {
+        ProcessorState {
+            ram: &mut self.ram,
+            registers: &mut self.registers,
+            flags: self.flags,
+        }
+    }
+
+    pub fn step(&mut self) -> Result<()> {
+        if let Some(code) = self.exit_code {
+            bail!("The program has exited with code {code}");
+        }
+
+        let pc = self.pc();
+        match self.tracer.take() {
+            Some(mut tracer) if tracer.wants(pc) => {
+                let res = self.step_traced(&mut tracer);
+                self.tracer = Some(tracer);
+                res
+            }
+            tracer => {
+                self.tracer = tracer;
+                self.step_untraced()
+            }
+        }
+    }
+
+    /// Runs until the program halts, has to wait for input, or has run `max_steps` instructions, returning how many it ran.
+    /// Unlike stepping, this doesn't stop at breakpoints.
+    pub fn run(&mut self, max_steps: u64) -> Result<u64> {
+        self.run_blocks(max_steps, |machine, budget| {
+            let mut blocks = machine.blocks.take()?;
+            let ran = blocks.run(machine, budget);
+            machine.blocks = Some(blocks);
+            ran
+        })
+    }
+
+    /// Runs like [`Machine::run`], giving `run_block` the chance to run a whole block of instructions from the PC within a budget.
+    /// It returns how many instructions it ran, or `None` to have the interpreter step the next one instead.
+    pub fn run_blocks(&mut self, max_steps: u64, mut run_block: impl FnMut(&mut Machine, u64) -> Option<u64>) -> Result<u64> {
+        let mut steps = 0;
+
+        while steps < max_steps && self.exit_code.is_none() {
+            // Blocks never include the branch a program halts at, so only need checking for halts when they can't run.
+            // They don't go through the tracer either, so tracing needs the interpreter.
+            if self.tracer.is_none() {
+                if let Some(ran) = run_block(self, max_steps - steps) {
+                    steps += ran;
+                    continue;
+                }
+            }
+
+            if self.halted() {
+                break;
+            }
+
+            self.step()?;
+            if self.console.waiting {
+                break;
+            }
+            steps += 1;
+        }
+
+        Ok(steps)
+    }
+
+    fn step_untraced(&mut self) -> Result<()> {
+        let pc = self.pc();
+        let instruction = self.decode(pc)?;
+        let condition_passed = instruction.condition.matches(self.flags);
+
+        let mut state = self.state();
+        let res = state.execute(instruction);
+        self.flags = state.flags;
+
+        if let Some(call) = res? {
+            // Leave the PC at a call which faults, as any other fault does, rather than past it
+            self.supervisor_call(call).inspect_err(|_| self.registers[15] = pc)?;
+        }
+
+        // A read which has to wait for input is retried, so is only counted once it completes
+        if !self.console.waiting {
+            self.metrics.record(&self.cycle_model, &instruction, condition_passed);
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record(pc, &instruction, condition_passed);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The instruction at `addr`, from the decode cache if there is one.
+    fn decode(&mut self, addr: u32) -> Result<Instruction> {
+        let word = self.fetch(addr).context("PC is outside of memory")?;
+
+        match &mut self.decode_cache {
+            Some(cache) => cache.decode(addr, word),
+            None => Instruction::deserialise(&word),
+        }
+    }
+
+    fn step_traced(&mut self, tracer: &mut Trace) -> Result<()> {
+        let pc = self.pc();
+        let before = self.cpu_state();
+        let word = self.fetch(pc);
+        let instruction = word.and_then(|word| Instruction::deserialise(&word).ok());
+
+        // Fetching the instruction is the only memory access, as nothing in the instruction set loads or stores
+        let memory = word.iter()
+            .flatten()
+            .zip(pc..)
+            .map(|(&value, address)| MemoryAccess { address, kind: AccessKind::Read, value })
+            .collect();
+
+        let res = self.step_untraced();
+        let after = self.cpu_state();
+
+        let registers = before.registers.iter()
+            .zip(after.registers)
+            .enumerate()
+            .filter(|(_, (old, new))| **old != *new)
+            .map(|(i, (&old, new))| RegisterChange { register: i as u8, old, new })
+            .collect();
+
+        let (disassembly, explanation) = match &instruction {
+            Some(instruction) => (instruction.to_string(), explain(instruction, &self.ram, &before, &after, &tracer.symbols)),
+            None => (String::new(), String::new()),
+        };
+
+        tracer.record(TraceEntry {
+            pc,
+            word: word.map(u32::from_be_bytes),
+            disassembly,
+            explanation,
+            condition_passed: instruction.is_some_and(|instruction| instruction.condition.matches(before.flags)),
+            registers,
+            memory,
+            flags: after.flags.into(),
+            fault: res.as_ref().err().map(|e| e.to_string()),
+        });
+
+        res
+    }
+
+    pub fn fetch(&self, addr: u32) -> Option<[u8; 4]> {
+        let addr = addr as usize;
+        self.ram.get(addr..addr + 4)?.try_into().ok()
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.registers[15]
+    }
+
+    /// Whether the program has stopped, by exiting or by reaching a branch to itself which will loop forever.
+    pub fn halted(&self) -> bool {
+        let pc = self.pc();
+        let instruction = self.fetch(pc).and_then(|word| {
+            let cached = self.decode_cache.as_ref().and_then(|cache| cache.get(pc, word));
+            cached.or_else(|| Instruction::deserialise(&word).ok())
+        });
+
+        self.exit_code.is_some() || instruction.is_some_and(|instruction| {
+            matches!(instruction.body, InstructionBody::Branch(branch) if branch.target(pc) == pc) && instruction.condition.matches(self.flags)
+        })
+    }
+}
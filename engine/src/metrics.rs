@@ -0,0 +1,104 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DataProcessingOperand, Instruction, InstructionBody, ShiftAmount};
+
+/// Cycles charged for each class of instruction, loosely following an ARM7 pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CycleModel {
+    pub data_processing: u64,
+    /// Extra cycles when the operand is shifted by a register
+    pub register_shift: u64,
+    /// Extra cycles when a data processing instruction writes the PC, refilling the pipeline
+    pub pc_write: u64,
+    /// A branch which is taken
+    pub branch: u64,
+    pub supervisor_call: u64,
+    /// An instruction whose condition fails, including branches which aren't taken
+    pub skipped: u64,
+}
+
+impl Default for CycleModel {
+    fn default() -> Self {
+        Self { data_processing: 1, register_shift: 1, pc_write: 2, branch: 3, supervisor_call: 3, skipped: 1 }
+    }
+}
+
+/// Counts of what a [`Machine`](crate::machine::Machine) has executed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Metrics {
+    /// Every instruction stepped over, whether or not its condition passed
+    pub instructions: u64,
+    /// Instructions whose condition failed
+    pub skipped: u64,
+    /// Branches and writes to the PC, whose condition passed
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    pub cycles: u64,
+}
+
+impl Metrics {
+    pub(crate) fn record(&mut self, model: &CycleModel, instruction: &Instruction, condition_passed: bool) {
+        self.instructions += 1;
+
+        if instruction.is_branch() {
+            match condition_passed {
+                true => self.branches_taken += 1,
+                false => self.branches_not_taken += 1,
+            }
+        }
+
+        if !condition_passed {
+            self.skipped += 1;
+            self.cycles += model.skipped;
+            return;
+        }
+
+        self.cycles += match instruction.body {
+            InstructionBody::DataProcessing(data_processing) => {
+                let shifted = matches!(data_processing.operand, DataProcessingOperand::Register { shift, .. } if matches!(shift.amount, ShiftAmount::Register(_)));
+                let writes_pc = !data_processing.opcode.is_comparison() && data_processing.dest.0 == 15;
+
+                model.data_processing + if shifted { model.register_shift } else { 0 } + if writes_pc { model.pc_write } else { 0 }
+            }
+            InstructionBody::Branch(_) => model.branch,
+            InstructionBody::SupervisorCall(_) => model.supervisor_call,
+        };
+    }
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Instructions {} ({} skipped)", self.instructions, self.skipped)?;
+        writeln!(f, "Branches     {} taken, {} not taken", self.branches_taken, self.branches_not_taken)?;
+        writeln!(f, "Cycles       {}", self.cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble_all, machine::Machine};
+
+    use super::Metrics;
+
+    #[test]
+    fn counts_instructions() {
+        let mut machine = Machine::default();
+        assemble_all("mov r0, #3\nloop:\nsubs r0, r0, #1\nmovne r1, r0, lsl r0\nbne loop\nbl sub\nsvc #0\nsub:\nmov pc, lr").serialise(&mut machine.ram);
+
+        while !machine.halted() {
+            machine.step().unwrap();
+        }
+
+        assert_eq!(machine.metrics, Metrics {
+            instructions: 13,
+            skipped: 2,
+            // Returning with `MOV PC, LR` counts as a branch
+            branches_taken: 4,
+            branches_not_taken: 1,
+            cycles: 1 + 3 + 2 * 2 + 2 * 3 + 1 + 1 + 3 + 3 + 3,
+        });
+    }
+}
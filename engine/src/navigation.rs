@@ -0,0 +1,97 @@
+use anyhow::{bail, Result};
+use pest::Parser;
+use serde::Serialize;
+
+use crate::{
+    assembler::{SourceSpan, SymbolIndex},
+    parser::{AssemblyParser, Rule},
+};
+
+/// Replace the text between the byte offsets `from` and `to` with `text`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TextEdit {
+    pub from: u32,
+    pub to: u32,
+    pub text: String,
+}
+
+/// Where the label under the cursor is defined.
+pub fn definition(src: &str, offset: usize) -> Option<SourceSpan> {
+    SymbolIndex::build(src).at(offset)?.1.definition
+}
+
+/// The definition and every use of the label under the cursor, in source order.
+pub fn references(src: &str, offset: usize) -> Vec<SourceSpan> {
+    let index = SymbolIndex::build(src);
+    let mut spans = match index.at(offset) {
+        Some((_, symbol)) => symbol.definition.iter().chain(&symbol.references).copied().collect::<Vec<_>>(),
+        None => return Vec::new(),
+    };
+
+    spans.sort_by_key(|span| span.from);
+    spans
+}
+
+/// Edits renaming the label under the cursor everywhere it appears.
+pub fn rename(src: &str, offset: usize, new_name: &str) -> Result<Vec<TextEdit>> {
+    let index = SymbolIndex::build(src);
+    let (name, _) = match index.at(offset) {
+        Some(symbol) => symbol,
+        None => bail!("There is no label here to rename"),
+    };
+
+    if !is_valid_label(new_name) {
+        bail!("`{new_name}` is not a valid label name");
+    }
+
+    if new_name != name && index.symbols.get(new_name).is_some_and(|symbol| symbol.definition.is_some()) {
+        bail!("A label called `{new_name}` already exists");
+    }
+
+    Ok(references(src, offset)
+        .into_iter()
+        .map(|span| TextEdit { from: span.from, to: span.to, text: new_name.to_string() })
+        .collect())
+}
+
+/// Labels must be plain text which would not be read as a register when used as an operand.
+fn is_valid_label(name: &str) -> bool {
+    let is_text = AssemblyParser::parse(Rule::text, name).is_ok_and(|text| text.as_str() == name);
+    let is_register = AssemblyParser::parse(Rule::register, name).is_ok_and(|reg| reg.as_str() == name);
+
+    is_text && !is_register
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{definition, references, rename};
+    use crate::utf16::{ToUtf16, Utf16Map};
+
+    #[test]
+    fn renames_label() {
+        let src = "loop:\nb loop\nbeq loop\nend:";
+
+        assert_eq!(definition(src, 10).map(|span| (span.from, span.to)), Some((0, 4)));
+        assert_eq!(references(src, 2).iter().map(|span| span.line).collect::<Vec<_>>(), [0, 1, 2]);
+
+        let edits = rename(src, 10, "top").unwrap();
+        assert_eq!(edits.iter().map(|edit| (edit.from, edit.to)).collect::<Vec<_>>(), [(0, 4), (8, 12), (17, 21)]);
+
+        assert!(rename(src, 10, "end").is_err());
+        assert!(rename(src, 10, "r3").is_err());
+        assert!(rename(src, 10, "two words").is_err());
+    }
+
+    #[test]
+    fn renames_after_non_ascii() {
+        let src = "; ≈ 𝑥\nloop:\nb loop";
+        let map = Utf16Map::new(src);
+        let offset = map.byte(16);
+
+        assert_eq!(definition(src, offset).to_utf16(&map).map(|span| (span.from, span.to)), Some((7, 11)));
+        assert_eq!(references(src, offset).to_utf16(&map).iter().map(|span| span.to).collect::<Vec<_>>(), [11, 19]);
+
+        let edits = rename(src, offset, "top").unwrap().to_utf16(&map);
+        assert_eq!(edits.iter().map(|edit| (edit.from, edit.to)).collect::<Vec<_>>(), [(7, 11), (15, 19)]);
+    }
+}
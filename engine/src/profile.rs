@@ -0,0 +1,160 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use serde::Serialize;
+
+use crate::Instruction;
+
+/// Counts how often each instruction runs while attached to a [`Machine`](crate::machine::Machine) as its profiler.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Address to the number of times the instruction there was stepped over, whether or not its condition passed
+    pub counts: BTreeMap<u32, u64>,
+    /// Address of each conditional branch to how often it went each way
+    pub branches: BTreeMap<u32, BranchCoverage>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// How often one source line ran, for a heatmap in the editor's gutter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LineProfile {
+    pub line: u32,
+    pub count: u64,
+    /// Only set for conditional branches
+    pub branch: Option<BranchCoverage>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&mut self, pc: u32, instruction: &Instruction, condition_passed: bool) {
+        *self.counts.entry(pc).or_default() += 1;
+
+        if instruction.is_conditional_branch() {
+            let coverage = self.branches.entry(pc).or_default();
+            match condition_passed {
+                true => coverage.taken += 1,
+                false => coverage.not_taken += 1,
+            }
+        }
+    }
+
+    /// Adds the counts from another run, such as another test case.
+    pub fn merge(&mut self, other: &Profile) {
+        for (addr, count) in &other.counts {
+            *self.counts.entry(*addr).or_default() += count;
+        }
+
+        for (addr, coverage) in &other.branches {
+            let merged = self.branches.entry(*addr).or_default();
+            merged.taken += coverage.taken;
+            merged.not_taken += coverage.not_taken;
+        }
+    }
+
+    /// Every instruction line in `source_map`, including ones which never ran, in line order.
+    pub fn lines(&self, source_map: &HashMap<u32, u32>) -> Vec<LineProfile> {
+        let mut lines = source_map
+            .iter()
+            .map(|(addr, line)| LineProfile {
+                line: *line,
+                count: self.counts.get(addr).copied().unwrap_or(0),
+                branch: self.branches.get(addr).copied(),
+            })
+            .collect::<Vec<_>>();
+
+        lines.sort_by_key(|line| line.line);
+        lines
+    }
+
+    /// Coverage of the source file at `path` in the lcov tracefile format.
+    /// `branches` lists the addresses of every conditional branch, so ones which never ran still count as found.
+    pub fn to_lcov(&self, path: &str, source_map: &HashMap<u32, u32>, branches: &[u32]) -> String {
+        let mut out = format!("TN:\nSF:{path}\n");
+
+        let mut branch_lines = branches.iter().filter_map(|addr| Some((source_map.get(addr)?, addr))).collect::<Vec<_>>();
+        branch_lines.sort();
+
+        let mut hit = 0;
+        for (line, addr) in &branch_lines {
+            let line = **line + 1;
+            match (self.counts.contains_key(addr), self.branches.get(addr).copied().unwrap_or_default()) {
+                (true, coverage) => {
+                    writeln!(out, "BRDA:{line},0,0,{}", coverage.taken).unwrap();
+                    writeln!(out, "BRDA:{line},0,1,{}", coverage.not_taken).unwrap();
+                    hit += (coverage.taken > 0) as usize + (coverage.not_taken > 0) as usize;
+                }
+                // Branches on lines which never ran are written as `-` rather than 0
+                (false, _) => writeln!(out, "BRDA:{line},0,0,-\nBRDA:{line},0,1,-").unwrap(),
+            }
+        }
+        writeln!(out, "BRF:{}\nBRH:{hit}", branch_lines.len() * 2).unwrap();
+
+        let lines = self.lines(source_map);
+        for line in &lines {
+            writeln!(out, "DA:{},{}", line.line + 1, line.count).unwrap();
+        }
+        writeln!(out, "LF:{}\nLH:{}", lines.len(), lines.iter().filter(|line| line.count > 0).count()).unwrap();
+
+        out + "end_of_record\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assemble_all, machine::Machine};
+
+    use super::Profile;
+
+    #[test]
+    fn profiles_lines() {
+        let src = "mov r0, #2\nloop:\nsubs r0, r0, #1\nbne loop\nbeq end\nmov r1, #1\nend:\nb end";
+        let assembly = assemble_all(src);
+        let mut machine = Machine::default();
+        assembly.serialise(&mut machine.ram);
+        machine.profiler = Some(Profile::new());
+
+        while !machine.halted() {
+            machine.step().unwrap();
+        }
+
+        let profile = machine.profiler.unwrap();
+        assert_eq!(profile.lines(&assembly.source_map()).iter().map(|line| (line.line, line.count)).collect::<Vec<_>>(), [(0, 1), (2, 2), (3, 2), (4, 1), (5, 0), (7, 0)]);
+        assert_eq!(profile.to_lcov("loop.s", &assembly.source_map(), &assembly.conditional_branches()), concat!(
+            "TN:\nSF:loop.s\n",
+            "BRDA:4,0,0,1\nBRDA:4,0,1,1\n",
+            "BRDA:5,0,0,1\nBRDA:5,0,1,0\n",
+            "BRF:4\nBRH:3\n",
+            "DA:1,1\nDA:3,2\nDA:4,2\nDA:5,1\nDA:6,0\nDA:8,0\n",
+            "LF:6\nLH:4\n",
+            "end_of_record\n",
+        ));
+    }
+
+    #[test]
+    fn counts_conditional_writes_to_pc_as_branches() {
+        let src = "bl sub\nend:\nb end\nsub:\ncmp r0, #0\nmoveq pc, lr\nmov pc, lr";
+        let assembly = assemble_all(src);
+        let mut machine = Machine::default();
+        assembly.serialise(&mut machine.ram);
+        machine.profiler = Some(Profile::new());
+
+        while !machine.halted() {
+            machine.step().unwrap();
+        }
+
+        let profile = machine.profiler.unwrap();
+        assert_eq!(assembly.conditional_branches(), [12]);
+        assert_eq!(profile.branches.keys().copied().collect::<Vec<_>>(), [12]);
+        assert_eq!((profile.branches[&12].taken, profile.branches[&12].not_taken), (1, 0));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+use pest::iterators::Pair;
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use crate::{
+    assembler::{encode_immediate, parse_literal, parse_opcode, Opcode, MAX_REG_NUM},
+    parser::Rule,
+    Condition,
+};
+
+/// The furthest a name can be from what was typed and still be suggested.
+const MAX_EDIT_DISTANCE: usize = 2;
+
+/// A suggested replacement for part of the source which fixes a lint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Fix {
+    pub title: String,
+    /// Byte offsets into `src`
+    pub from: u32,
+    pub to: u32,
+    pub replacement: String,
+}
+
+impl Fix {
+    fn replace(pair: &Pair<'_, Rule>, replacement: String) -> Self {
+        Fix {
+            title: format!("Change to `{replacement}`"),
+            from: pair.as_span().start() as u32,
+            to: pair.as_span().end() as u32,
+            replacement,
+        }
+    }
+}
+
+/// Fixes for an instruction which failed to assemble.
+pub(crate) fn suggest(instruction: Pair<'_, Rule>, labels: &HashMap<String, u32>) -> Vec<Fix> {
+    let mut inner = instruction.into_inner();
+    let opcode = match inner.next() {
+        Some(opcode) => opcode,
+        None => return Vec::new(),
+    };
+
+    let parsed_opcode = match parse_opcode(opcode.as_str()) {
+        Some((parsed, _, _)) => parsed,
+        None => return suggest_opcode(&opcode),
+    };
+
+    let mut fixes = Vec::new();
+    for arg in inner {
+        match arg.as_rule() {
+            Rule::register => fixes.extend(suggest_register(&arg)),
+            Rule::text => fixes.extend(suggest_label(&arg, labels)),
+            Rule::literal => fixes.extend(suggest_immediate(&opcode, parsed_opcode, &arg)),
+            Rule::shift => fixes.extend(arg.into_inner().filter(|amount| amount.as_rule() == Rule::register).flat_map(|amount| suggest_register(&amount))),
+            _ => {}
+        }
+    }
+
+    fixes
+}
+
+/// Mnemonics close to a misspelt one, such as `MOV` for `MOVE`.
+fn suggest_opcode(opcode: &Pair<'_, Rule>) -> Vec<Fix> {
+    let typed = opcode.as_str().to_ascii_lowercase();
+    let mut mnemonics = Vec::new();
+
+    for op in Opcode::iter() {
+        let suffixes: &[&str] = if op.accepts_s() { &["", "s"] } else { &[""] };
+        for name in op.as_str() {
+            for suffix in suffixes {
+                for condition in Condition::iter() {
+                    let condition = if condition == Condition::AL { "" } else { condition.as_str() };
+                    mnemonics.push(format!("{name}{suffix}{condition}"));
+                }
+            }
+        }
+    }
+
+    nearest(&typed, mnemonics)
+        .into_iter()
+        .map(|mnemonic| Fix::replace(opcode, match_case(opcode.as_str(), &mnemonic)))
+        .collect()
+}
+
+/// Registers past R15 are usually a slip of the finger, so suggest dropping a digit.
+fn suggest_register(register: &Pair<'_, Rule>) -> Vec<Fix> {
+    let text = register.as_str();
+    let index = match register.clone().into_inner().next() {
+        Some(index) if index.as_rule() == Rule::decimal => index.as_str(),
+        _ => return Vec::new(),
+    };
+
+    if index.parse::<u32>().map_or(true, |index| index <= MAX_REG_NUM as u32) {
+        return Vec::new();
+    }
+
+    let mut candidates = (0..index.len())
+        .filter_map(|i| format!("{}{}", &index[..i], &index[i + 1..]).parse::<u8>().ok())
+        .filter(|index| *index <= MAX_REG_NUM)
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates.dedup();
+
+    candidates
+        .into_iter()
+        .map(|index| Fix::replace(register, format!("{}{index}", &text[..1])))
+        .collect()
+}
+
+fn suggest_label(label: &Pair<'_, Rule>, labels: &HashMap<String, u32>) -> Vec<Fix> {
+    if labels.contains_key(label.as_str()) {
+        return Vec::new();
+    }
+
+    nearest(label.as_str(), labels.keys().cloned())
+        .into_iter()
+        .map(|name| Fix::replace(label, name))
+        .collect()
+}
+
+/// An immediate which can't be encoded may still fit once inverted or negated, by switching to the opposite instruction.
+fn suggest_immediate(opcode: &Pair<'_, Rule>, parsed: Opcode, literal: &Pair<'_, Rule>) -> Vec<Fix> {
+    let value = match parse_literal(literal.clone()) {
+        Ok(value) if encode_immediate(value).is_none() => value,
+        _ => return Vec::new(),
+    };
+
+    let (opposite, value) = match parsed {
+        Opcode::Mov => (Opcode::Mvn, !value),
+        Opcode::Mvn => (Opcode::Mov, !value),
+        Opcode::And => (Opcode::Bic, !value),
+        Opcode::Bic => (Opcode::And, !value),
+        Opcode::Adc => (Opcode::Sbc, !value),
+        Opcode::Sbc => (Opcode::Adc, !value),
+        Opcode::Add => (Opcode::Sub, value.wrapping_neg()),
+        Opcode::Sub => (Opcode::Add, value.wrapping_neg()),
+        Opcode::Cmp => (Opcode::Cmn, value.wrapping_neg()),
+        Opcode::Cmn => (Opcode::Cmp, value.wrapping_neg()),
+        _ => return Vec::new(),
+    };
+
+    if encode_immediate(value).is_none() {
+        return Vec::new();
+    }
+
+    // Keep the condition and S suffixes, swapping just the name of the instruction
+    let typed = opcode.as_str();
+    let name_len = parsed.as_str().iter().find(|name| typed.to_ascii_lowercase().starts_with(*name)).unwrap().len();
+    let mnemonic = format!("{}{}", match_case(&typed[..name_len], opposite.as_str()[0]), &typed[name_len..]);
+
+    let literal_text = if literal.as_str().contains("0x") { format!("{value:#x}") } else { format!("#{}", value as i32) };
+
+    let src = opcode.get_input();
+    let (from, to) = (opcode.as_span().start(), literal.as_span().end());
+    let between = &src[opcode.as_span().end()..literal.as_span().start()];
+    let replacement = format!("{mnemonic}{between}{literal_text}");
+
+    vec![Fix {
+        title: format!("Change to `{replacement}`"),
+        from: from as u32,
+        to: to as u32,
+        replacement,
+    }]
+}
+
+/// The candidates closest to `typed`, as long as they are close enough to be a plausible typo.
+/// Ties go to the shortest candidates, so `MOVE` suggests `MOV` rather than `MOVEQ`.
+fn nearest(typed: &str, candidates: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut distances = candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(typed, &candidate), candidate.len(), candidate))
+        .filter(|(distance, _, _)| *distance <= MAX_EDIT_DISTANCE.min(typed.len() / 2))
+        .collect::<Vec<_>>();
+    distances.sort();
+
+    let best = distances.first().map(|(distance, len, _)| (*distance, *len));
+    distances
+        .into_iter()
+        .take_while(|(distance, len, _)| Some((*distance, *len)) == best)
+        .map(|(_, _, candidate)| candidate)
+        .collect()
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + (a != *b) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// `suggestion` in upper case if `typed` was written in upper case.
+fn match_case(typed: &str, suggestion: &str) -> String {
+    if typed.chars().any(|c| c.is_ascii_uppercase()) {
+        suggestion.to_ascii_uppercase()
+    } else {
+        suggestion.to_ascii_lowercase()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble_all, lint_source, utf16::{ToUtf16, Utf16Map}};
+
+    fn fixes(src: &str) -> Vec<String> {
+        assemble_all(src)
+            .diagnostics
+            .into_iter()
+            .flat_map(|diagnostic| diagnostic.fixes)
+            .map(|fix| format!("{}{}{}", &src[..fix.from as usize], fix.replacement, &src[fix.to as usize..]))
+            .collect()
+    }
+
+    #[test]
+    fn suggests_fixes() {
+        assert_eq!(fixes("MOVE R1, R2"), ["MOV R1, R2"]);
+        assert_eq!(fixes("addseqq r1, r1, r2"), ["addseq r1, r1, r2"]);
+        assert_eq!(fixes("loop:\nb lopo"), ["loop:\nb loop"]);
+        assert_eq!(fixes("mov R16, #1"), ["mov R1, #1", "mov R6, #1"]);
+        assert_eq!(fixes("moveq r1, #-2"), ["mvneq r1, #1"]);
+        assert_eq!(fixes("SUB r1, r1, 0xffffff00"), ["ADD r1, r1, 0x100"]);
+        assert_eq!(fixes("mov r1, #257"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn lints_and_fixes_share_offsets() {
+        let src = "; ≈ 𝑥\nMOVE R1, R2";
+        let lints = lint_source(src).lints.to_utf16(&Utf16Map::new(src));
+
+        assert_eq!((lints[0].line, lints[0].from, lints[0].to), (1, 7, 18));
+        assert_eq!(lints[0].fixes.iter().map(|fix| (fix.from, fix.to)).collect::<Vec<_>>(), [(7, 11)]);
+    }
+}
@@ -1,7 +1,7 @@
 use bitvec::{field::BitField, order::Msb0, slice::BitSlice, view::AsMutBits};
 use funty::Integral;
 
-use crate::{Branch, DataProcessing, Instruction, Register, Shift, ShiftAmount};
+use crate::{Branch, DataProcessing, Instruction, Register, Shift, ShiftAmount, SupervisorCall};
 
 impl Instruction {
     pub fn serialise(&self, mut dest: &mut [u8]) {
@@ -10,8 +10,9 @@ impl Instruction {
         writer.write(self.condition as u8, 4);
 
         match &self.body {
-            crate::InstructionBody::DataProcessing(data_processing) => serialise_data_processing(&mut writer, &data_processing),
-            crate::InstructionBody::Branch(branch) => serialise_branch(&mut writer, &branch),
+            crate::InstructionBody::DataProcessing(data_processing) => serialise_data_processing(&mut writer, data_processing),
+            crate::InstructionBody::Branch(branch) => serialise_branch(&mut writer, branch),
+            crate::InstructionBody::SupervisorCall(call) => serialise_supervisor_call(&mut writer, call),
         }
     }
 }
@@ -67,6 +68,11 @@ fn serialise_branch(writer: &mut InstructionWriter, instruction: &Branch) {
     writer.write(instruction.offset, 24);
 }
 
+fn serialise_supervisor_call(writer: &mut InstructionWriter, instruction: &SupervisorCall) {
+    writer.write(0b1111, 4);
+    writer.write(instruction.comment, 24);
+}
+
 fn serialise_data_processing(writer: &mut InstructionWriter, instruction: &DataProcessing) {
     // Instruction code
     writer.write(0, 2);
@@ -0,0 +1,137 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{machine::Machine, Flags};
+
+/// Every snapshot format ever written. New versions are appended so older links keep loading.
+#[derive(Debug, Serialize, Deserialize)]
+enum Snapshot {
+    V1(SnapshotV1),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotV1 {
+    ram_size: u32,
+    /// RAM with trailing zero bytes stripped
+    ram: Vec<u8>,
+    registers: [u32; 16],
+    flags: u8,
+    breakpoints: Vec<u32>,
+    source_hash: Option<u64>,
+}
+
+impl Machine {
+    /// Serialises the whole machine into a compact binary snapshot.
+    /// Passing the program source records its hash so [`Machine::load_state`] can detect a mismatch.
+    pub fn save_state(&self, src: Option<&str>) -> Vec<u8> {
+        let used = self.ram.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+
+        let snapshot = Snapshot::V1(SnapshotV1 {
+            ram_size: self.ram.len() as u32,
+            ram: self.ram[..used].to_vec(),
+            registers: self.registers,
+            flags: self.flags.into(),
+            breakpoints: self.breakpoints.iter().copied().collect(),
+            source_hash: src.map(source_hash),
+        });
+
+        postcard::to_allocvec(&snapshot).expect("Snapshot serialisation cannot fail")
+    }
+
+    /// Restores a snapshot produced by [`Machine::save_state`] into a machine with the same amount of RAM.
+    /// If both the snapshot and `src` carry a source, they must match.
+    pub fn load_state(&mut self, bytes: &[u8], src: Option<&str>) -> Result<()> {
+        let snapshot = postcard::from_bytes::<Snapshot>(bytes).context("Malformed snapshot")?;
+
+        match snapshot {
+            Snapshot::V1(snapshot) => {
+                if let (Some(expected), Some(src)) = (snapshot.source_hash, src) {
+                    if expected != source_hash(src) {
+                        bail!("Snapshot was taken from a different program");
+                    }
+                }
+
+                // Snapshots come from shared links, so the declared size can't be trusted to allocate
+                if snapshot.ram_size as usize != self.ram.len() {
+                    bail!("Snapshot RAM size does not match");
+                }
+
+                if snapshot.ram.len() > snapshot.ram_size as usize {
+                    bail!("Snapshot RAM is larger than its declared size");
+                }
+
+                let mut ram = snapshot.ram;
+                ram.resize(snapshot.ram_size as usize, 0);
+
+                self.ram = ram;
+                self.registers = snapshot.registers;
+                self.flags = Flags::from(snapshot.flags);
+                self.breakpoints = snapshot.breakpoints.into_iter().collect();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// [`Machine::save_state`] encoded as URL-safe base64.
+    pub fn save_state_base64(&self, src: Option<&str>) -> String {
+        URL_SAFE_NO_PAD.encode(self.save_state(src))
+    }
+
+    pub fn load_state_base64(&mut self, encoded: &str, src: Option<&str>) -> Result<()> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded.trim())
+            .context("Snapshot is not valid base64")?;
+        self.load_state(&bytes, src)
+    }
+}
+
+/// 64-bit FNV-1a. `std`'s hasher is not guaranteed stable between releases, which would break old snapshots.
+fn source_hash(src: &str) -> u64 {
+    src.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::Machine;
+
+    use super::{Snapshot, SnapshotV1};
+
+    #[test]
+    fn round_trip() {
+        let mut machine = Machine::default();
+        machine.ram[0..4].copy_from_slice(&[0xe3, 0xa0, 0x10, 0x0c]);
+        machine.registers[3] = 0xdeadbeef;
+        machine.flags = 0b1010.into();
+        machine.breakpoints.insert(8);
+
+        let encoded = machine.save_state_base64(Some("mov R1, #12"));
+
+        let mut restored = Machine::default();
+        restored.load_state_base64(&encoded, Some("mov R1, #12")).unwrap();
+        assert_eq!(restored, machine);
+
+        assert!(restored.load_state_base64(&encoded, Some("mov R1, #13")).is_err());
+        assert!(Machine::new(4).load_state_base64(&encoded, None).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_ram() {
+        let snapshot = Snapshot::V1(SnapshotV1 {
+            ram_size: u32::MAX,
+            ram: Vec::new(),
+            registers: [0; 16],
+            flags: 0,
+            breakpoints: Vec::new(),
+            source_hash: None,
+        });
+
+        let mut machine = Machine::default();
+        let err = machine.load_state(&postcard::to_allocvec(&snapshot).unwrap(), None).unwrap_err();
+        assert_eq!(err.to_string(), "Snapshot RAM size does not match");
+        assert_eq!(machine, Machine::default());
+    }
+}
@@ -0,0 +1,165 @@
+use std::{fmt::Write, ops::Range};
+
+use serde::Serialize;
+
+use crate::{disassembler::SymbolTable, Flags};
+
+/// Records every instruction a [`Machine`](crate::machine::Machine) executes while attached as its tracer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub entries: Vec<TraceEntry>,
+    /// Only instructions whose address falls in this range are recorded
+    pub range: Option<Range<u32>>,
+    /// Labels to name branch targets after in explanations
+    pub symbols: SymbolTable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TraceEntry {
+    pub pc: u32,
+    /// `None` if the PC is outside of memory
+    pub word: Option<u32>,
+    pub disassembly: String,
+    pub explanation: String,
+    pub condition_passed: bool,
+    pub registers: Vec<RegisterChange>,
+    pub memory: Vec<MemoryAccess>,
+    pub flags: u8,
+    /// Why the instruction couldn't be fetched, decoded or executed
+    pub fault: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RegisterChange {
+    pub register: u8,
+    pub old: u32,
+    pub new: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MemoryAccess {
+    pub address: u32,
+    pub kind: AccessKind,
+    pub value: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn filtered(range: Range<u32>) -> Self {
+        Self {
+            range: Some(range),
+            ..Self::default()
+        }
+    }
+
+    /// Names branch targets in explanations after the labels in `symbols`.
+    pub fn with_symbols(self, symbols: SymbolTable) -> Self {
+        Self { symbols, ..self }
+    }
+
+    pub fn wants(&self, pc: u32) -> bool {
+        self.range.as_ref().is_none_or(|range| range.contains(&pc))
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) {
+        if self.wants(entry.pc) {
+            self.entries.push(entry);
+        }
+    }
+
+    /// One JSON object per executed instruction.
+    pub fn to_json_lines(&self) -> String {
+        self.entries
+            .iter()
+            .map(|entry| serde_json::to_string(entry).expect("Trace entries are always serialisable") + "\n")
+            .collect()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        for entry in &self.entries {
+            match entry.word {
+                Some(word) => write!(out, "{:08x}: {word:08x}  {:<24}", entry.pc, entry.disassembly).unwrap(),
+                None => write!(out, "{:08x}: {:8}  {:<24}", entry.pc, "", entry.disassembly).unwrap(),
+            }
+
+            if !entry.condition_passed {
+                out.push_str(" (skipped)");
+            }
+
+            for change in &entry.registers {
+                write!(out, " R{}: {:#x} -> {:#x}", change.register, change.old, change.new).unwrap();
+            }
+
+            for access in entry.memory.iter().filter(|access| access.kind == AccessKind::Write) {
+                write!(out, " [{:#x}] <- {:#04x}", access.address, access.value).unwrap();
+            }
+
+            writeln!(out, " {}", Flags::from(entry.flags)).unwrap();
+            if !entry.explanation.is_empty() {
+                writeln!(out, "          {}", entry.explanation).unwrap();
+            }
+            if let Some(fault) = &entry.fault {
+                writeln!(out, "          Fault: {fault}").unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::{assemble, symbol_table}, machine::Machine};
+
+    use super::{AccessKind, Trace};
+
+    #[test]
+    fn records_changes() {
+        let mut machine = Machine::default();
+        assemble("start:\nmov R1, #12\nmoveq R2, #1\nb start").unwrap().serialise(&mut machine.ram);
+        machine.tracer = Some(Trace::filtered(0..8));
+
+        for _ in 0..4 {
+            machine.step().unwrap();
+        }
+
+        let trace = machine.tracer.take().unwrap();
+        assert_eq!(trace.entries.iter().map(|e| e.pc).collect::<Vec<_>>(), [0, 4, 0]);
+        assert!(trace.entries[0].condition_passed);
+        assert!(!trace.entries[1].condition_passed);
+        assert_eq!(trace.entries[0].registers.iter().find(|r| r.register == 1).unwrap().new, 12);
+        assert_eq!(trace.to_json_lines().lines().count(), 3);
+    }
+
+    #[test]
+    fn records_faults() {
+        let src = "b next\nnext:\nsvc #99";
+        let mut machine = Machine::new(8);
+        assemble(src).unwrap().serialise(&mut machine.ram);
+        machine.tracer = Some(Trace::new().with_symbols(symbol_table(src)));
+
+        machine.step().unwrap();
+        assert!(machine.step().is_err());
+        machine.registers[15] = 8;
+        assert!(machine.step().is_err());
+
+        let trace = machine.tracer.take().unwrap();
+        assert_eq!(trace.entries[0].explanation, "Branch to next.");
+        assert_eq!(trace.entries[0].memory.iter().map(|access| (access.address, access.kind)).collect::<Vec<_>>(), [0, 1, 2, 3].map(|address| (address, AccessKind::Read)));
+        assert_eq!(trace.entries[1].fault.as_deref(), Some("Unknown supervisor call 99"));
+        assert_eq!(trace.entries[2].word, None);
+        assert_eq!(trace.entries[2].fault.as_deref(), Some("PC is outside of memory"));
+    }
+}
@@ -0,0 +1,156 @@
+//! Editors count positions in UTF-16 code units, while the engine works in byte offsets into the source.
+
+use crate::{completion::Completion, highlight::SemanticToken, navigation::TextEdit, quickfix::Fix, Lint, SourceSpan};
+
+/// Converts between byte offsets into a source and the UTF-16 offsets an editor uses for the same source.
+pub struct Utf16Map {
+    /// The UTF-16 offset of every byte, and of the end of the source. Bytes inside a character share its offset.
+    units: Vec<u32>,
+}
+
+impl Utf16Map {
+    pub fn new(src: &str) -> Self {
+        let mut units = Vec::with_capacity(src.len() + 1);
+        let mut offset = 0;
+
+        for c in src.chars() {
+            units.extend(std::iter::repeat_n(offset, c.len_utf8()));
+            offset += c.len_utf16() as u32;
+        }
+        units.push(offset);
+
+        Utf16Map { units }
+    }
+
+    /// The UTF-16 offset of byte `offset`, rounding down to the start of its character.
+    pub fn utf16(&self, offset: usize) -> u32 {
+        self.units[offset.min(self.units.len() - 1)]
+    }
+
+    /// The byte offset of UTF-16 `offset`, rounding up to the next character if it falls inside a surrogate pair.
+    pub fn byte(&self, offset: u32) -> usize {
+        self.units.partition_point(|&units| units < offset).min(self.units.len() - 1)
+    }
+}
+
+/// Results holding byte offsets into a source, which the editor needs as UTF-16 offsets.
+pub trait ToUtf16 {
+    fn to_utf16(self, map: &Utf16Map) -> Self;
+}
+
+impl<T: ToUtf16> ToUtf16 for Vec<T> {
+    fn to_utf16(self, map: &Utf16Map) -> Self {
+        self.into_iter().map(|item| item.to_utf16(map)).collect()
+    }
+}
+
+impl<T: ToUtf16> ToUtf16 for Option<T> {
+    fn to_utf16(self, map: &Utf16Map) -> Self {
+        self.map(|item| item.to_utf16(map))
+    }
+}
+
+impl ToUtf16 for SourceSpan {
+    fn to_utf16(self, map: &Utf16Map) -> Self {
+        SourceSpan { from: map.utf16(self.from as usize), to: map.utf16(self.to as usize), ..self }
+    }
+}
+
+impl ToUtf16 for TextEdit {
+    fn to_utf16(self, map: &Utf16Map) -> Self {
+        TextEdit { from: map.utf16(self.from as usize), to: map.utf16(self.to as usize), ..self }
+    }
+}
+
+impl ToUtf16 for SemanticToken {
+    fn to_utf16(self, map: &Utf16Map) -> Self {
+        SemanticToken { from: map.utf16(self.from as usize), to: map.utf16(self.to as usize), ..self }
+    }
+}
+
+impl ToUtf16 for Fix {
+    fn to_utf16(self, map: &Utf16Map) -> Self {
+        Fix { from: map.utf16(self.from as usize), to: map.utf16(self.to as usize), ..self }
+    }
+}
+
+impl ToUtf16 for Lint {
+    fn to_utf16(self, map: &Utf16Map) -> Self {
+        Lint { from: map.utf16(self.from as usize), to: map.utf16(self.to as usize), fixes: self.fixes.to_utf16(map), ..self }
+    }
+}
+
+impl ToUtf16 for Completion {
+    fn to_utf16(self, map: &Utf16Map) -> Self {
+        Completion { from: map.utf16(self.from as usize), to: map.utf16(self.to as usize), ..self }
+    }
+}
+
+/// The byte offset of the UTF-16 `column` on the zero based `line`, clamped to the end of the line.
+pub fn line_column_offset(src: &str, line: u32, column: u32) -> usize {
+    let start = src.split_inclusive('\n').take(line as usize).map(str::len).sum::<usize>();
+    let mut units = 0;
+
+    for (i, c) in src[start..].char_indices() {
+        if units >= column || c == '\n' {
+            return start + i;
+        }
+        units += c.len_utf16() as u32;
+    }
+
+    src.len()
+}
+
+/// The zero based line and UTF-16 column of byte `offset`.
+pub fn line_column(src: &str, offset: usize) -> (u32, u32) {
+    let before = &src[..floor_char_boundary(src, offset)];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().map(char::len_utf16).sum::<usize>();
+
+    (line as u32, column as u32)
+}
+
+/// The closest character boundary at or before byte `offset`, so `src` can be sliced there.
+pub fn floor_char_boundary(src: &str, offset: usize) -> usize {
+    (0..=offset.min(src.len())).rev().find(|&i| src.is_char_boundary(i)).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{floor_char_boundary, line_column, line_column_offset, Utf16Map};
+
+    #[test]
+    fn converts_offsets() {
+        // `π` and `≈` are two and three bytes but one unit each, and `𝑥` is four bytes and two units
+        let src = "; π ≈ 𝑥\nmov r1, #3\n";
+        let map = Utf16Map::new(src);
+
+        assert_eq!(map.utf16(3), 2);
+        assert_eq!(map.utf16(4), 3);
+        assert_eq!(map.utf16(9), 6);
+        assert_eq!(map.utf16(13), 8);
+        assert_eq!(map.byte(8), 13);
+        assert_eq!(map.byte(7), 13);
+        assert_eq!(map.byte(3), 4);
+        assert_eq!(map.byte(99), src.len());
+        assert_eq!(map.utf16(99), 20);
+
+        for offset in 0..=src.len() {
+            assert!(src.is_char_boundary(map.byte(offset as u32)));
+        }
+    }
+
+    #[test]
+    fn converts_lines_and_columns() {
+        let src = "; π ≈ 3\nmov r1, #3\n";
+
+        assert_eq!(line_column_offset(src, 1, 4), 15);
+        assert_eq!(line_column(src, 15), (1, 4));
+        assert_eq!(line_column(src, 5), (0, 4));
+        assert_eq!(line_column(src, 4), (0, 3));
+        assert_eq!(line_column_offset(src, 0, 4), 5);
+        assert_eq!(line_column_offset(src, 0, 99), 10);
+        assert_eq!(floor_char_boundary(src, 3), 2);
+    }
+}
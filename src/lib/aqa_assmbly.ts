@@ -1,176 +1,82 @@
 import type { Monaco } from "@monaco-editor/loader";
-import { editor, MarkerSeverity, type IRange, type languages, type Position } from "monaco-editor";
+import { editor, MarkerSeverity, type languages, type Position } from "monaco-editor";
 import * as engine from "./engine/engine";
 import { PROGRAM_COUNTER, RAM } from "./globals";
 import { get } from "svelte/store";
 
-enum Operand {
-    Register,
-    Label,
-    DataSource
-}
-
-const INSTRUCTIONS: {
-    name: string;
-    args: Operand[];
-}[] = [
-    {
-        name: "LDR",
-        args: [
-            Operand.Register,
-            Operand.DataSource
-        ],
-    },
-    {
-        name: "STR",
-        args: [
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "ADD",
-        args: [
-            Operand.Register,
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "SUB",
-        args: [
-            Operand.Register,
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "MOV",
-        args: [
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "CMP",
-        args: [
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "B",
-        args: [
-            Operand.Label
-        ]
-    },
-    {
-        name: "BEQ",
-        args: [
-            Operand.Label
-        ]
-    },
-    {
-        name: "BNE",
-        args: [
-            Operand.Label
-        ]
-    },
-    {
-        name: "BGT",
-        args: [
-            Operand.Label
-        ]
-    },
-    {
-        name: "BLT",
-        args: [
-            Operand.Label
-        ]
-    },
-    {
-        name: "AND",
-        args: [
-            Operand.Register,
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "ORR",
-        args: [
-            Operand.Register,
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "EOR",
-        args: [
-            Operand.Register,
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "MVN",
-        args: [
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "LSL",
-        args: [
-            Operand.Register,
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "LSR",
-        args: [
-            Operand.Register,
-            Operand.Register,
-            Operand.DataSource
-        ]
-    },
-    {
-        name: "HALT",
-        args: []
-    },
-]
-
-function completeOperand(operand: Operand, range: IRange, labels: string[], ctx: Monaco): languages.ProviderResult<languages.CompletionList> {
-    switch (operand) {
-        case Operand.Register:
-            return {
-                suggestions: [...Array(13).keys().map(reg => {
-                    return {
-                        label: `R${reg}`,
-                        kind: ctx.languages.CompletionItemKind.Variable,
-                        insertText: `R${reg}`,
-                        range: range
-                    }
-                })]
-            }
-        case Operand.Label:
-
-    
-        default:
-            break;
-    }
-}
-
 export function init(ctx: Monaco) {
     ctx.languages.register({
         id: "aqa-assembly"
     })
 
-    ctx.languages.setMonarchTokensProvider("aqa-assembly", LANGUAGE)
+    ctx.languages.registerDocumentSemanticTokensProvider("aqa-assembly", {
+        getLegend: () => ({ tokenTypes: Object.values(TOKEN_TYPES), tokenModifiers: [] }),
+        provideDocumentSemanticTokens: model => ({ data: getSemanticTokens(model) }),
+        releaseDocumentSemanticTokens: () => {}
+    })
     ctx.languages.registerCompletionItemProvider("aqa-assembly", {
         provideCompletionItems: (model, position) => getCompletions(model, position, ctx)
     })
+    ctx.languages.registerDefinitionProvider("aqa-assembly", {
+        provideDefinition: (model, position) => {
+            const span: SourceSpan | undefined = engine.definition(model.getValue(), model.getOffsetAt(position))
+            return span ? { uri: model.uri, range: spanToRange(model, span) } : null
+        }
+    })
+    ctx.languages.registerReferenceProvider("aqa-assembly", {
+        provideReferences: (model, position) => {
+            const spans: SourceSpan[] = engine.references(model.getValue(), model.getOffsetAt(position))
+            return spans.map(span => ({ uri: model.uri, range: spanToRange(model, span) }))
+        }
+    })
+    ctx.languages.registerRenameProvider("aqa-assembly", {
+        provideRenameEdits: (model, position, newName) => {
+            try {
+                const edits: TextEdit[] = engine.rename(model.getValue(), model.getOffsetAt(position), newName)
+                return {
+                    edits: edits.map(edit => ({
+                        resource: model.uri,
+                        versionId: model.getVersionId(),
+                        textEdit: { range: spanToRange(model, edit), text: edit.text }
+                    }))
+                }
+            } catch (e) {
+                return { edits: [], rejectReason: String(e) }
+            }
+        }
+    })
+    ctx.languages.registerCodeActionProvider("aqa-assembly", {
+        provideCodeActions: (model, range) => {
+            const lints: Lints = engine.lint(model.getValue())
+            const actions = lints.lints
+                .filter(lint => lint.line + 1 >= range.startLineNumber && lint.line + 1 <= range.endLineNumber)
+                .flatMap(lint => lint.fixes.map(fix => ({
+                    title: fix.title,
+                    kind: "quickfix",
+                    edit: {
+                        edits: [{
+                            resource: model.uri,
+                            versionId: model.getVersionId(),
+                            textEdit: { range: spanToRange(model, fix), text: fix.replacement }
+                        }]
+                    }
+                })))
+
+            return { actions, dispose: () => {} }
+        }
+    })
+    ctx.languages.registerDocumentFormattingEditProvider("aqa-assembly", {
+        provideDocumentFormattingEdits: (model, options) => [{
+            range: model.getFullModelRange(),
+            text: engine.format(model.getValue(), { indent: options.tabSize })
+        }]
+    })
+    ctx.languages.registerHoverProvider("aqa-assembly", {
+        provideHover: (model, position) => {
+            const contents = engine.hover(model.getValue(), model.getOffsetAt(position))
+            return contents ? { contents: [{ value: contents }] } : null
+        }
+    })
 }
 
 export function initModel(ctx: Monaco, model: editor.ITextModel, editor: editor.IStandaloneCodeEditor) {
@@ -206,14 +112,11 @@ export function initModel(ctx: Monaco, model: editor.ITextModel, editor: editor.
         updateInstructionHighlight()
 
         ctx.editor.setModelMarkers(model, "linter", lints.lints.map(lint => {
-            const firstChar = model.getLineFirstNonWhitespaceColumn(lint.line)
             return {
-                startLineNumber: lint.line + 1,
-                endLineNumber: lint.line + 1,
-                startColumn: firstChar + lint.from,
-                endColumn: firstChar + lint.to,
-                message: lint.err,
-                severity: MarkerSeverity.Error
+                ...spanToRange(model, lint),
+                message: lint.message,
+                code: lint.code,
+                severity: SEVERITIES[lint.severity]
             }
         }))
     })
@@ -227,85 +130,118 @@ type Lints = {
 
 type Lint = {
     err: string,
+    message: string,
+    severity: keyof typeof SEVERITIES,
+    code: string,
     from: number,
     to: number,
-    line: number
+    line: number,
+    fixes: Fix[]
 }
 
-function getCompletions(model: editor.ITextModel, position: Position, ctx: Monaco): languages.ProviderResult<languages.CompletionList> {
-    const line = model.getLineContent(position.lineNumber)
-    const word = model.getWordUntilPosition(position)
-    const range = {
-        startLineNumber: position.lineNumber,
-        endLineNumber: position.lineNumber,
-        startColumn: word.startColumn,
-        endColumn: word.endColumn,
-    }
+type Fix = {
+    title: string,
+    from: number,
+    to: number,
+    replacement: string
+}
 
-    const before = model.getValueInRange({
-        startLineNumber: position.lineNumber,
-        endLineNumber: position.lineNumber,
-        startColumn: 0,
-        endColumn: position.column,
-    }).trim().split(" ").filter(s => s.length > 0)
+const SEVERITIES = {
+    error: MarkerSeverity.Error,
+    warning: MarkerSeverity.Warning,
+    hint: MarkerSeverity.Hint,
+}
 
-    console.log(before)
-    if (before.length === 0) {
-        return {
-            suggestions: INSTRUCTIONS.map(instruction => {
-                return {
-                    label: instruction.name,
-                    kind: ctx.languages.CompletionItemKind.Function,
-                    insertText: instruction.name,
-                    range: range
-                }
-            })
-        }
-    }
+type SourceSpan = {
+    line: number,
+    from: number,
+    to: number
+}
 
-    const instructionName = before[0].toUpperCase()
+type TextEdit = {
+    from: number,
+    to: number,
+    text: string
+}
 
-    const instruction = INSTRUCTIONS.find(instr => instr.name === instructionName)
+function spanToRange(model: editor.ITextModel, span: { from: number, to: number }) {
+    const start = model.getPositionAt(span.from)
+    const end = model.getPositionAt(span.to)
 
-    if (instruction !== undefined) {
-        const labels = model.findMatches("e", true, false, false, null, false)
-        console.log(labels)
+    return {
+        startLineNumber: start.lineNumber,
+        startColumn: start.column,
+        endLineNumber: end.lineNumber,
+        endColumn: end.column,
+    }
+}
 
-        return completeOperand(instruction.args[before.length - 1], range, [], ctx)
+type Completion = {
+    label: string,
+    kind: "mnemonic" | "register" | "label" | "shift",
+    detail: string,
+    from: number,
+    to: number
+}
+
+function getCompletions(model: editor.ITextModel, position: Position, ctx: Monaco): languages.ProviderResult<languages.CompletionList> {
+    const completions: Completion[] = engine.complete(model.getValue(), model.getOffsetAt(position))
+    const kinds = {
+        mnemonic: ctx.languages.CompletionItemKind.Function,
+        register: ctx.languages.CompletionItemKind.Variable,
+        label: ctx.languages.CompletionItemKind.Reference,
+        shift: ctx.languages.CompletionItemKind.Operator,
     }
+
     return {
-        suggestions: [
-            // {
-            //     label: "mov",
-            //     kind: ctx.languages.CompletionItemKind.Variable,
-            //     insertText: "move",
-            //     range: range
-            // }
-        ]
+        suggestions: completions.map(completion => {
+            return {
+                label: completion.label,
+                kind: kinds[completion.kind],
+                detail: completion.detail,
+                insertText: completion.label,
+                range: spanToRange(model, completion)
+            }
+        })
     }
 }
 
-const LANGUAGE: languages.IMonarchLanguage = {
-    ignoreCase: true,
-    keywords: [
-        "ldr", "str", "add", "sub", "mov", "cmp", "b", "and", "orr", "eor", "mvn", "lsl", "lsr", "halt",
-        "beq", "bne", "bgt", "blt"
-    ],
-    tokenizer: {
-        root: [
-            {
-                regex: /(\/\/|;).*$/,
-                action: "comment"
-            },
-            {
-                regex: /^(\w+)(:)$/,
-                action: ["tag", "default"],
-            },
-            [/(R\d+)|PC|LR|SP/, "variable"],
-            [/#\d+/, "number"],
-            [/\w+/, {
-                cases: { "@keywords": "keyword" },
-            }],
-        ]
+type SemanticToken = {
+    kind: keyof typeof TOKEN_TYPES,
+    line: number,
+    from: number,
+    to: number
+}
+
+// Monaco theme token used to colour each kind of semantic token
+const TOKEN_TYPES = {
+    mnemonic: "keyword",
+    condition: "type",
+    register: "variable",
+    alias: "variable.predefined",
+    label_definition: "tag",
+    label_reference: "type.identifier",
+    immediate: "number",
+    shift: "keyword.operator",
+    comment: "comment",
+}
+
+function getSemanticTokens(model: editor.ITextModel): Uint32Array {
+    const tokens: SemanticToken[] = engine.semantic_tokens(model.getValue())
+    const kinds = Object.keys(TOKEN_TYPES)
+    const data: number[] = []
+    let previous = { lineNumber: 1, column: 1 }
+
+    // Each token is encoded relative to the previous one, as Monaco expects
+    for (const token of tokens) {
+        const start = model.getPositionAt(token.from)
+        const end = model.getPositionAt(token.to)
+        const lineDelta = start.lineNumber - previous.lineNumber
+        const columnDelta = lineDelta === 0 ? start.column - previous.column : start.column - 1
+
+        data.push(lineDelta, columnDelta, end.column - start.column, kinds.indexOf(token.kind), 0)
+        previous = start
     }
-}
\ No newline at end of file
+
+    return new Uint32Array(data)
+}
@@ -5,6 +5,8 @@ export const RAM_SIZE = 256 * 4;
 export const RAM = writable(new Uint8Array(RAM_SIZE))
 export const REGISTERS = writable(new Uint32Array(16))
 export const FLAGS = writable(0)
+/** Everything the program has written to the console, along with any errors from running it */
+export const CONSOLE = writable("")
 
 export const PROGRAM_COUNTER = derived(REGISTERS, registers => registers[15])
 